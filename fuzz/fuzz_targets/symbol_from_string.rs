@@ -0,0 +1,14 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+// The crate has no library to link against, but the symbol module only depends on other crates, so it is compiled in as it is.
+#[path = "../../src/symbol.rs"]
+#[allow(dead_code, unused_imports)]
+mod symbol;
+
+fuzz_target!(|data: &[u8]| {
+    // QR codes hold text, so only valid UTF-8 ever reaches the parser.
+    if let Ok(text) = std::str::from_utf8(data) {
+        let _ = symbol::symbol_from_string(text.to_string());
+    }
+});
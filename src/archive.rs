@@ -0,0 +1,262 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::output::{self, UnsafeNameError};
+use crate::symbol::HashAlgorithm;
+
+/// A file or directory in a directory tree, as listed in its manifest. The contents of the files follow each other in the order they are listed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// The path relative to the root of the tree, with `/` between directories.
+    pub path: String,
+    /// Whether this is a directory, which is listed so that it is made even if it is empty.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dir: bool,
+    /// The length of the file, in bytes; 0 for a directory.
+    #[serde(default)]
+    pub size: usize,
+    /// The hash of the file, as 64 hex digits, with the algorithm of the hash in the meta symbol. Directories have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// The Unix permission bits, if they were known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// The time it was last modified, in seconds since the Unix epoch, if it was known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<u64>,
+}
+
+/// Errors that may occur while reading a directory tree into an archive, or writing one back out.
+#[derive(Error, Debug)]
+pub enum ArchiveError {
+    #[error("could not read {0}: {1}")]
+    Read(PathBuf, io::Error),
+
+    #[error("could not write {0}: {1}")]
+    Write(PathBuf, io::Error),
+
+    /// Paths in the manifest are text, so a name that is not Unicode cannot be sent.
+    #[error("the name of {0} is not valid Unicode")]
+    NonUnicodeName(PathBuf),
+
+    /// A path in the manifest could reach outside the root, or cannot be written on some platforms.
+    #[error("{0:?} cannot be written safely: {1}")]
+    UnsafePath(String, UnsafeNameError),
+
+    #[error("the manifest is not valid: {0}")]
+    InvalidManifest(String),
+
+    #[error("the files in the manifest add up to {expected} bytes, but {actual} bytes were sent")]
+    SizeMismatch { expected: usize, actual: usize },
+
+    #[error("{0:?} does not match its hash in the manifest")]
+    HashMismatch(String),
+
+    /// Something in the way of a path is a symbolic link, which could lead outside the root.
+    #[error("{0} is a symbolic link, so nothing is written through it")]
+    Symlink(PathBuf),
+}
+
+/// Read the directory tree under this root, listing everything in it in the manifest in a fixed order, and concatenating the contents of its files.
+///
+/// Symbolic links and special files are left out with a warning.
+pub fn pack(root: &Path, hash_algorithm: HashAlgorithm) -> Result<(Vec<ManifestEntry>, Vec<u8>), ArchiveError> {
+    let mut entries = Vec::new();
+    let mut data = Vec::new();
+    pack_dir(root, "", hash_algorithm, &mut entries, &mut data)?;
+    Ok((entries, data))
+}
+
+fn pack_dir(dir: &Path, prefix: &str, hash_algorithm: HashAlgorithm, entries: &mut Vec<ManifestEntry>, data: &mut Vec<u8>) -> Result<(), ArchiveError> {
+    let read = |error| ArchiveError::Read(dir.to_path_buf(), error);
+    let mut children = fs::read_dir(dir).and_then(|children| children.collect::<io::Result<Vec<fs::DirEntry>>>()).map_err(read)?;
+    children.sort_by_key(fs::DirEntry::file_name);
+    for child in children {
+        let path = child.path();
+        let name = child.file_name().into_string().map_err(|_| ArchiveError::NonUnicodeName(path.clone()))?;
+        let relative = if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) };
+        // A name the decoder would refuse is better refused now, before the whole tree is sent.
+        output::safe_path(&relative).map_err(|error| ArchiveError::UnsafePath(relative.clone(), error))?;
+        let metadata = fs::symlink_metadata(&path).map_err(|error| ArchiveError::Read(path.clone(), error))?;
+        let (mode, mtime) = mode_and_mtime(&metadata);
+        if metadata.is_dir() {
+            entries.push(ManifestEntry { path: relative.clone(), dir: true, size: 0, hash: None, mode, mtime });
+            pack_dir(&path, &relative, hash_algorithm, entries, data)?;
+        } else if metadata.is_file() {
+            let contents = fs::read(&path).map_err(|error| ArchiveError::Read(path.clone(), error))?;
+            entries.push(ManifestEntry { path: relative, dir: false, size: contents.len(), hash: Some(hex::encode(hash_algorithm.digest(&contents))), mode, mtime });
+            data.extend(contents);
+        } else {
+            warn!("Leaving out {}, which is neither a file nor a directory", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// The permission bits and the modification time in seconds since the Unix epoch of a file, where the platform knows them.
+pub fn mode_and_mtime(metadata: &fs::Metadata) -> (Option<u32>, Option<u64>) {
+    (mode_of(metadata), metadata.modified().ok().and_then(|time| time.duration_since(UNIX_EPOCH).ok()).map(|time| time.as_secs()))
+}
+
+#[cfg(unix)]
+fn mode_of(metadata: &fs::Metadata) -> Option<u32> { Some(std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o777) }
+
+#[cfg(not(unix))]
+fn mode_of(_metadata: &fs::Metadata) -> Option<u32> { None }
+
+/// Recreate the directory tree described by this manifest under the root, with these concatenated file contents.
+///
+/// The manifest comes from the video, so every path is checked, and every file against its hash, before anything is written; nothing is written through a symbolic link, and existing files are only replaced if `overwrite` is set.
+/// The modes and modification times are restored where the platform allows, leaving out the bits beyond the permissions.
+pub fn unpack(root: &Path, entries: &[ManifestEntry], data: &[u8], hash_algorithm: HashAlgorithm, overwrite: bool) -> Result<(), ArchiveError> {
+    let expected = entries.iter().filter(|entry| !entry.dir).try_fold(0usize, |total, entry| total.checked_add(entry.size));
+    if expected != Some(data.len()) { return Err(ArchiveError::SizeMismatch { expected: expected.unwrap_or(usize::MAX), actual: data.len() }); }
+    let mut paths = Vec::with_capacity(entries.len());
+    let mut seen = HashSet::new();
+    let mut offset = 0;
+    for entry in entries {
+        let path = output::safe_path(&entry.path).map_err(|error| ArchiveError::UnsafePath(entry.path.clone(), error))?;
+        if !seen.insert(path.clone()) { return Err(ArchiveError::InvalidManifest(format!("{:?} is listed more than once", entry.path))); }
+        if !entry.dir {
+            let hash = entry.hash.as_ref().ok_or_else(|| ArchiveError::InvalidManifest(format!("the file {:?} has no hash", entry.path)))?;
+            if hex::encode(hash_algorithm.digest(&data[offset..offset + entry.size])) != hash.to_lowercase() { return Err(ArchiveError::HashMismatch(entry.path.clone())); }
+            offset += entry.size;
+        }
+        paths.push(path);
+    }
+    // A file cannot also be the directory of another entry.
+    let files: HashSet<&Path> = entries.iter().zip(&paths).filter(|(entry, _)| !entry.dir).map(|(_, path)| path.as_path()).collect();
+    for (entry, path) in entries.iter().zip(&paths) {
+        if let Some(file) = path.ancestors().skip(1).find(|ancestor| files.contains(ancestor)) {
+            return Err(ArchiveError::InvalidManifest(format!("{:?} is inside the file {}", entry.path, file.display())));
+        }
+    }
+
+    // The root is named in the video too, so it may be a link just as well as anything under it.
+    if root.symlink_metadata().map(|metadata| metadata.file_type().is_symlink()).unwrap_or(false) { return Err(ArchiveError::Symlink(root.to_path_buf())); }
+    for path in paths.iter() {
+        refuse_symlinks(root, path)?;
+    }
+    if !overwrite {
+        if let Some(existing) = files.iter().map(|path| root.join(path)).find(|path| path.symlink_metadata().is_ok()) {
+            return Err(ArchiveError::Write(existing, io::ErrorKind::AlreadyExists.into()));
+        }
+    }
+    fs::create_dir_all(root).map_err(|error| ArchiveError::Write(root.to_path_buf(), error))?;
+    let mut offset = 0;
+    for (entry, path) in entries.iter().zip(&paths) {
+        let path = root.join(path);
+        if entry.dir {
+            fs::create_dir_all(&path).map_err(|error| ArchiveError::Write(path.clone(), error))?;
+        } else {
+            output::write_file(&path, &data[offset..offset + entry.size], overwrite).map_err(|error| ArchiveError::Write(path.clone(), error))?;
+            offset += entry.size;
+            output::restore_metadata(&path, entry.dir, entry.mode, entry.mtime);
+        }
+    }
+    // Writing into a directory changes its modification time, and it may not be writable once its mode is restored, so directories come last, deepest first.
+    for (entry, path) in entries.iter().zip(&paths).rev().filter(|(entry, _)| entry.dir) {
+        output::restore_metadata(&root.join(path), entry.dir, entry.mode, entry.mtime);
+    }
+    Ok(())
+}
+
+/// Check that neither this path under the root nor any directory on the way to it is a symbolic link.
+fn refuse_symlinks(root: &Path, path: &Path) -> Result<(), ArchiveError> {
+    match output::find_symlink(root, path) {
+        Some(link) => Err(ArchiveError::Symlink(link)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::*;
+    use std::time::Duration;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("datablast-archive-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn file(path: &str, contents: &[u8]) -> ManifestEntry {
+        ManifestEntry { path: path.to_string(), dir: false, size: contents.len(), hash: Some(hex::encode(HashAlgorithm::Sha3_256.digest(contents))), mode: None, mtime: None }
+    }
+
+    #[test]
+    fn test_tree_round_trips() {
+        let source = temp_dir("source");
+        fs::create_dir_all(source.join("docs/empty")).unwrap();
+        fs::write(source.join("readme.txt"), b"read me").unwrap();
+        fs::write(source.join("docs/guide.md"), b"# Guide").unwrap();
+        let guide = fs::OpenOptions::new().write(true).open(source.join("docs/guide.md")).unwrap();
+        guide.set_modified(UNIX_EPOCH + Duration::from_secs(1_000_000_000)).unwrap();
+        #[cfg(unix)]
+        fs::set_permissions(source.join("readme.txt"), std::os::unix::fs::PermissionsExt::from_mode(0o640)).unwrap();
+
+        let (entries, data) = pack(&source, HashAlgorithm::Blake3).unwrap();
+        let paths: Vec<&str> = entries.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(paths, ["docs", "docs/empty", "docs/guide.md", "readme.txt"]);
+        assert_eq!(data, b"# Guideread me");
+
+        let target = temp_dir("target");
+        unpack(&target, &entries, &data, HashAlgorithm::Blake3, false).unwrap();
+        assert_eq!(fs::read(target.join("readme.txt")).unwrap(), b"read me");
+        assert!(target.join("docs/empty").is_dir());
+        assert_eq!(fs::metadata(target.join("docs/guide.md")).unwrap().modified().unwrap(), UNIX_EPOCH + Duration::from_secs(1_000_000_000));
+        #[cfg(unix)]
+        assert_eq!(std::os::unix::fs::PermissionsExt::mode(&fs::metadata(target.join("readme.txt")).unwrap().permissions()) & 0o777, 0o640);
+
+        // The files are there already, so they are only replaced when asked.
+        assert!(matches!(unpack(&target, &entries, &data, HashAlgorithm::Blake3, false), Err(ArchiveError::Write(_, error)) if error.kind() == io::ErrorKind::AlreadyExists));
+        unpack(&target, &entries, &data, HashAlgorithm::Blake3, true).unwrap();
+        fs::remove_dir_all(&source).unwrap();
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[test]
+    fn test_hostile_manifests_write_nothing() {
+        let target = temp_dir("hostile");
+        let unpack = |entries: &[ManifestEntry], data: &[u8]| unpack(&target, entries, data, HashAlgorithm::Sha3_256, false);
+        assert!(matches!(unpack(&[file("../escape.txt", b"x")], b"x"), Err(ArchiveError::UnsafePath(_, UnsafeNameError::RelativeComponent))));
+        assert!(matches!(unpack(&[file("/etc/cron.d/evil", b"x")], b"x"), Err(ArchiveError::UnsafePath(_, UnsafeNameError::Absolute))));
+        assert!(matches!(unpack(&[file("a.txt", b"x"), file("a.txt", b"y")], b"xy"), Err(ArchiveError::InvalidManifest(_))));
+        assert!(matches!(unpack(&[file("a.txt", b"x"), file("a.txt/b.txt", b"y")], b"xy"), Err(ArchiveError::InvalidManifest(_))));
+        assert!(matches!(unpack(&[file("a.txt", b"x")], b"xy"), Err(ArchiveError::SizeMismatch { expected: 1, actual: 2 })));
+        assert!(matches!(unpack(&[file("a.txt", b"xyz")], b"xy"), Err(ArchiveError::SizeMismatch { .. })));
+        assert!(matches!(unpack(&[file("a.txt", b"x")], b"y"), Err(ArchiveError::HashMismatch(_))));
+        assert!(matches!(unpack(&[ManifestEntry { hash: None, ..file("a.txt", b"x") }], b"x"), Err(ArchiveError::InvalidManifest(_))));
+        let huge = ManifestEntry { size: usize::MAX, ..file("a.txt", b"x") };
+        assert!(matches!(unpack(&[file("b.txt", b"x"), huge], b"x"), Err(ArchiveError::SizeMismatch { .. })));
+        assert!(!target.exists());
+        // A modification time beyond what the platform holds is skipped, rather than crashing the decoder.
+        unpack(&[ManifestEntry { mtime: Some(u64::MAX), ..file("a.txt", b"x") }], b"x").unwrap();
+        assert_eq!(fs::read(target.join("a.txt")).unwrap(), b"x");
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks_are_not_followed() {
+        let target = temp_dir("symlink");
+        let outside = temp_dir("outside");
+        fs::create_dir_all(&target).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, target.join("docs")).unwrap();
+        let entries = [file("docs/guide.md", b"x")];
+        assert!(matches!(unpack(&target, &entries, b"x", HashAlgorithm::Sha3_256, true), Err(ArchiveError::Symlink(_))));
+        assert!(!outside.join("guide.md").exists());
+        // The root itself is not written through either.
+        assert!(matches!(unpack(&target.join("docs"), &[file("guide.md", b"x")], b"x", HashAlgorithm::Sha3_256, true), Err(ArchiveError::Symlink(link)) if link == target.join("docs")));
+        assert!(!outside.join("guide.md").exists());
+        fs::remove_dir_all(&target).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+use image::{Rgb, RgbImage};
+use thiserror::Error;
+
+/// Errors that may occur while laying out symbols on a frame.
+#[derive(Error, Debug, Eq, PartialEq)]
+pub enum LayoutError {
+    /// A frame must hold at least one symbol.
+    #[error("there must be at least one symbol per frame")]
+    NoSymbols,
+
+    /// The grid has fewer cells than the number of symbols that should be placed on each frame.
+    #[error("a grid of {columns}x{rows} cells cannot hold {symbols} symbols")]
+    GridTooSmall { columns: u32, rows: u32, symbols: usize },
+
+    /// A symbol does not fit in a cell even without scaling it up.
+    #[error("a symbol {symbol_width} pixels wide does not fit in a cell with {cell_size} pixels of room; use a larger frame, fewer symbols per frame or a smaller QR code version")]
+    SymbolTooLarge { symbol_width: u32, cell_size: u32 },
+}
+
+/// How symbols are arranged on a frame: a grid of equal cells, each holding one symbol in its center.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameLayout {
+    /// Width of the whole frame in pixels.
+    pub width: u32,
+    /// Height of the whole frame in pixels.
+    pub height: u32,
+    /// Number of cells in each row of the grid.
+    pub columns: u32,
+    /// Number of rows in the grid.
+    pub rows: u32,
+    /// Each symbol is kept at least this many pixels away from the edges of its cell.
+    pub margin: u32,
+}
+
+impl FrameLayout {
+    /// Create a layout with exactly this grid.
+    pub fn with_grid(width: u32, height: u32, columns: u32, rows: u32, margin: u32) -> Self {
+        FrameLayout { width, height, columns, rows, margin }
+    }
+
+    /// Choose the grid for this many symbols per frame that gives each symbol as much room as possible.
+    pub fn fit(width: u32, height: u32, symbols: usize, margin: u32) -> Result<Self, LayoutError> {
+        if symbols == 0 { return Err(LayoutError::NoSymbols); }
+        let mut best = FrameLayout::with_grid(width, height, symbols as u32, 1, margin);
+        for columns in 1..=symbols as u32 {
+            let rows = (symbols as u32).div_ceil(columns);
+            let candidate = FrameLayout::with_grid(width, height, columns, rows, margin);
+            if candidate.cell_size() > best.cell_size() { best = candidate; }
+        }
+        Ok(best)
+    }
+
+    /// The number of symbols that fit on one frame.
+    pub fn capacity(&self) -> usize { (self.columns * self.rows) as usize }
+
+    /// Check that this layout can hold this many symbols per frame.
+    pub fn check_capacity(&self, symbols: usize) -> Result<(), LayoutError> {
+        if symbols == 0 { return Err(LayoutError::NoSymbols); }
+        if symbols > self.capacity() { return Err(LayoutError::GridTooSmall { columns: self.columns, rows: self.rows, symbols }); }
+        Ok(())
+    }
+
+    /// The side of the largest square that fits in a cell once the margins are taken away.
+    pub fn cell_size(&self) -> u32 {
+        let cell_width = self.width / self.columns.max(1);
+        let cell_height = self.height / self.rows.max(1);
+        cell_width.min(cell_height).saturating_sub(2 * self.margin)
+    }
+
+    /// Get the largest whole number of times a square symbol this many pixels wide can be enlarged and still fit in a cell.
+    pub fn scale_for(&self, symbol_width: u32) -> Result<u32, LayoutError> {
+        let scale = self.cell_size() / symbol_width.max(1);
+        if scale == 0 { return Err(LayoutError::SymbolTooLarge { symbol_width, cell_size: self.cell_size() }); }
+        Ok(scale)
+    }
+
+    /// Draw these symbol images onto a white frame, filling the cells left to right and then top to bottom.
+    ///
+    /// Each symbol is enlarged by a whole number of times without smoothing, so that module edges stay sharp.
+    pub fn compose(&self, symbols: &[RgbImage]) -> Result<RgbImage, LayoutError> {
+        self.check_capacity(symbols.len().max(1))?;
+        let mut frame = RgbImage::from_pixel(self.width, self.height, Rgb([255,255,255]));
+        let cell_width = self.width / self.columns;
+        let cell_height = self.height / self.rows;
+        for (i, symb) in symbols.iter().enumerate() {
+            let scale = self.scale_for(symb.width().max(symb.height()))?;
+            let (column, row) = (i as u32 % self.columns, i as u32 / self.columns);
+            let left = column * cell_width + (cell_width - symb.width() * scale) / 2;
+            let top = row * cell_height + (cell_height - symb.height() * scale) / 2;
+            for (x, y, pixel) in symb.enumerate_pixels() {
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        frame.put_pixel(left + x * scale + dx, top + y * scale + dy, *pixel);
+                    }
+                }
+            }
+        }
+        Ok(frame)
+    }
+
+    /// Draw up to three sets of symbol images onto the red, green and blue planes of a single frame, each set laid out as by `compose`.
+    ///
+    /// A plane with no symbols is left white, so it reads as an empty frame.
+    pub fn compose_planes(&self, planes: &[&[RgbImage]]) -> Result<RgbImage, LayoutError> {
+        let mut frame = RgbImage::from_pixel(self.width, self.height, Rgb([255,255,255]));
+        for (channel, symbols) in planes.iter().take(3).enumerate() {
+            if symbols.is_empty() { continue; }
+            let plane = self.compose(symbols)?;
+            for (x, y, pixel) in plane.enumerate_pixels() {
+                frame.get_pixel_mut(x, y)[channel] = pixel[channel];
+            }
+        }
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compositor::*;
+    use crate::qr_writer::{string_to_qrcode_with_version, Version, EcLevel};
+    use crate::qr_reader::{strings_from_image, strings_from_channel, Channel};
+
+    #[test]
+    fn test_fit_maximizes_cell_size() {
+        let layout = FrameLayout::fit(1920, 1080, 9, 0).unwrap();
+        assert_eq!((layout.columns, layout.rows), (5, 2));
+        assert_eq!(layout.cell_size(), 384);
+        let layout = FrameLayout::fit(1920, 1080, 1, 10).unwrap();
+        assert_eq!((layout.columns, layout.rows), (1, 1));
+        assert_eq!(layout.cell_size(), 1060);
+        assert_eq!(FrameLayout::fit(1920, 1080, 0, 0), Err(LayoutError::NoSymbols));
+    }
+
+    #[test]
+    fn test_symbol_too_large() {
+        let layout = FrameLayout::with_grid(640, 360, 4, 2, 10);
+        assert_eq!(layout.scale_for(185), Err(LayoutError::SymbolTooLarge { symbol_width: 185, cell_size: 140 }));
+        assert_eq!(layout.scale_for(37), Ok(3));
+    }
+
+    #[test]
+    fn test_composed_frame_is_readable() {
+        let layout = FrameLayout::fit(1280, 720, 6, 8).unwrap();
+        let codes: Vec<_> = (0..6).map(|i| string_to_qrcode_with_version(format!("symbol {}", i), Version::Normal(2), EcLevel::M, 1).unwrap()).collect();
+        let frame = layout.compose(&codes).unwrap();
+        assert_eq!(frame.dimensions(), (1280, 720));
+        let mut strings = strings_from_image(&frame);
+        strings.sort();
+        assert_eq!(strings, (0..6).map(|i| format!("symbol {}", i)).collect::<Vec<_>>());
+    }
+
+    /// Convert the frame to Y'CbCr, keep the chroma only at half resolution, and convert it back, like a 4:2:0 video would.
+    fn subsample_chroma(frame: &RgbImage) -> RgbImage {
+        let (w, h) = frame.dimensions();
+        let ycbcr: Vec<[f64; 3]> = frame.pixels().map(|p| {
+            let (r, g, b) = (p[0] as f64, p[1] as f64, p[2] as f64);
+            let y = 0.299*r + 0.587*g + 0.114*b;
+            [y, (b - y) / 1.772, (r - y) / 1.402]
+        }).collect();
+        let mut out = RgbImage::new(w, h);
+        for (x, y, pixel) in out.enumerate_pixels_mut() {
+            let (bx, by) = (x / 2 * 2, y / 2 * 2);
+            let block = [(bx, by), (bx+1, by), (bx, by+1), (bx+1, by+1)];
+            let (mut cb, mut cr) = (0.0, 0.0);
+            for (px, py) in block.iter() {
+                let v = ycbcr[(py.min(&(h-1)) * w + px.min(&(w-1))) as usize];
+                cb += v[1] / 4.0;
+                cr += v[2] / 4.0;
+            }
+            let luma = ycbcr[(y * w + x) as usize][0];
+            let r = luma + 1.402*cr;
+            let b = luma + 1.772*cb;
+            let g = (luma - 0.299*r - 0.114*b) / 0.587;
+            *pixel = image::Rgb([r.round().clamp(0.0, 255.0) as u8, g.round().clamp(0.0, 255.0) as u8, b.round().clamp(0.0, 255.0) as u8]);
+        }
+        out
+    }
+
+    #[test]
+    fn test_planes_are_read_separately() {
+        let layout = FrameLayout::fit(1280, 720, 2, 8).unwrap();
+        let planes: Vec<Vec<RgbImage>> = (0..3).map(|c| (0..2).map(|i| string_to_qrcode_with_version(format!("plane {} symbol {}", c, i), Version::Normal(3), EcLevel::M, 1).unwrap()).collect()).collect();
+        let plane_refs: Vec<&[RgbImage]> = planes.iter().map(|p| p.as_slice()).collect();
+        let frame = layout.compose_planes(&plane_refs).unwrap();
+        let channels = [Channel::Red, Channel::Green, Channel::Blue];
+        for (i, channel) in channels.iter().enumerate() {
+            let mut strings = strings_from_channel(&frame, *channel);
+            strings.sort();
+            assert_eq!(strings, vec![format!("plane {} symbol 0", i), format!("plane {} symbol 1", i)]);
+        }
+
+        // With modules many pixels wide, the planes survive chroma subsampling.
+        let subsampled = subsample_chroma(&frame);
+        for channel in channels.iter() {
+            assert_eq!(strings_from_channel(&subsampled, *channel).len(), 2);
+        }
+    }
+}
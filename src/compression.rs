@@ -0,0 +1,92 @@
+use std::io::{self, Read, Write};
+
+use crate::symbol::Compression;
+
+impl Compression {
+    /// Compress this data.
+    pub fn compress(self, data: &[u8]) -> Vec<u8> {
+        // Writing into a `Vec` cannot fail, so neither can any of these.
+        match self {
+            Compression::None => data.to_vec(),
+            Compression::Deflate => {
+                let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::best());
+                encoder.write_all(data).expect("writing to memory failed?!");
+                encoder.finish().expect("writing to memory failed?!")
+            },
+            Compression::Zstd => zstd::encode_all(data, 19).expect("writing to memory failed?!"),
+            Compression::Xz => {
+                let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+                encoder.write_all(data).expect("writing to memory failed?!");
+                encoder.finish().expect("writing to memory failed?!")
+            },
+        }
+    }
+
+    /// Undo `compress`, failing if the data was not compressed this way or was damaged.
+    ///
+    /// Decompression stops once the output is longer than `max_len`, so that a small piece of crafted data cannot fill up the memory; the output is then `max_len + 1` bytes long, which tells the caller that it was cut short.
+    pub fn decompress(self, data: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+        let limit = (max_len as u64).saturating_add(1);
+        let mut output = Vec::new();
+        match self {
+            Compression::None => output.extend_from_slice(&data[..data.len().min(max_len.saturating_add(1))]),
+            Compression::Deflate => { flate2::read::DeflateDecoder::new(data).take(limit).read_to_end(&mut output)?; },
+            Compression::Zstd => { zstd::stream::read::Decoder::new(data)?.take(limit).read_to_end(&mut output)?; },
+            Compression::Xz => { xz2::read::XzDecoder::new(data).take(limit).read_to_end(&mut output)?; },
+        }
+        Ok(output)
+    }
+
+    /// Compress this data, unless that does not make it any smaller, in which case it is left as it is and `Compression::None` is returned instead.
+    pub fn compress_if_smaller(self, data: &[u8]) -> (Compression, Vec<u8>) {
+        let compressed = self.compress(data);
+        if compressed.len() < data.len() { (self, compressed) } else { (Compression::None, data.to_vec()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::*;
+
+    const ALL: [Compression; 4] = [Compression::None, Compression::Deflate, Compression::Zstd, Compression::Xz];
+
+    #[test]
+    fn test_round_trip() {
+        let text = "{\"level\": \"info\", \"message\": \"frame decoded\"}\n".repeat(200);
+        for &compression in ALL.iter() {
+            let compressed = compression.compress(text.as_bytes());
+            if compression != Compression::None { assert!(compressed.len() * 5 < text.len(), "{} only got down to {} bytes", compression, compressed.len()); }
+            assert_eq!(compression.decompress(&compressed, usize::MAX).unwrap(), text.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_incompressible_data_is_left_alone() {
+        let noise: Vec<u8> = (0..2000).map(|_| rand::random()).collect();
+        for &compression in ALL.iter() {
+            assert_eq!(compression.compress_if_smaller(&noise), (Compression::None, noise.clone()));
+        }
+        assert_eq!(Compression::Zstd.compress_if_smaller(&[]), (Compression::None, vec![]));
+    }
+
+    #[test]
+    fn test_damaged_data_is_rejected() {
+        let text = "a line of a log file\n".repeat(100);
+        for &compression in [Compression::Deflate, Compression::Zstd, Compression::Xz].iter() {
+            let mut compressed = compression.compress(text.as_bytes());
+            compressed.truncate(compressed.len() / 2);
+            assert!(compression.decompress(&compressed, usize::MAX).is_err(), "{} accepted truncated data", compression);
+        }
+    }
+
+    #[test]
+    fn test_decompression_stops_at_limit() {
+        // A megabyte of zeros compresses to a few hundred bytes.
+        let zeros = vec![0; 1 << 20];
+        for &compression in ALL.iter() {
+            let compressed = compression.compress(&zeros);
+            assert_eq!(compression.decompress(&compressed, 1000).unwrap().len(), 1001, "{} was not cut short", compression);
+            assert_eq!(compression.decompress(&compressed, zeros.len()).unwrap(), zeros);
+        }
+    }
+}
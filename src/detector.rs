@@ -0,0 +1,204 @@
+use image::GrayImage;
+use thiserror::Error;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::qr_writer::EcLevel;
+
+/// The version, error correction level and mask of a decoded QR code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodeInfo {
+    /// The version of the QR code, from 1 to 40.
+    pub version: usize,
+    pub ecc_level: EcLevel,
+    /// The mask pattern of the QR code, from 0 to 7.
+    pub mask: u8,
+}
+
+/// Why a grid that was found could not be decoded.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("{detector} could not decode it: {message}")]
+pub struct DecodeError {
+    /// The name of the detector that found the grid.
+    pub detector: &'static str,
+    /// What the detector reported.
+    pub message: String,
+}
+
+/// A QR code grid found in a greyscale image, along with its text or why it could not be decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grid {
+    /// The corners of the grid in pixels: top left, top right, bottom right and bottom left of the symbol.
+    pub corners: [(i32, i32); 4],
+    pub decoded: Result<(CodeInfo, String), DecodeError>,
+    /// The name of the detector that found the grid, or decoded it if several found it.
+    pub detector: &'static str,
+}
+
+impl Grid {
+    /// Get the leftmost, topmost, rightmost and bottommost coordinates of the corners.
+    pub fn bounding_box(&self) -> (i32, i32, i32, i32) {
+        let xs = self.corners.iter().map(|corner| corner.0);
+        let ys = self.corners.iter().map(|corner| corner.1);
+        (xs.clone().min().unwrap(), ys.clone().min().unwrap(), xs.max().unwrap(), ys.max().unwrap())
+    }
+
+    /// The middle of the grid.
+    pub fn center(&self) -> (i32, i32) {
+        let (x, y) = self.corners.iter().fold((0, 0), |(x, y), corner| (x + corner.0, y + corner.1));
+        (x / 4, y / 4)
+    }
+
+    /// Whether this point is inside the bounding box of the grid.
+    pub fn covers(&self, (x, y): (i32, i32)) -> bool {
+        let (left, top, right, bottom) = self.bounding_box();
+        x >= left && x <= right && y >= top && y <= bottom
+    }
+}
+
+/// Something that finds QR code grids in a greyscale image and decodes them.
+///
+/// Backends other than rqrr can be added behind cargo features, and are then listed by `available` and found by `by_name`.
+pub trait SymbolDetector: fmt::Debug + Send + Sync {
+    /// A short name for the detector, as given on the command line.
+    fn name(&self) -> &'static str;
+
+    /// Find every grid in this image and try to decode each of them.
+    fn detect(&self, img: &GrayImage) -> Vec<Grid>;
+}
+
+/// Get the detector used when no other one is asked for.
+pub fn default_detector() -> Arc<dyn SymbolDetector> {
+    Arc::new(Rqrr)
+}
+
+/// Get every detector this program was built with, in order of preference.
+pub fn available() -> Vec<Arc<dyn SymbolDetector>> {
+    // Further backends are added here, each behind its own cargo feature.
+    let detectors: Vec<Arc<dyn SymbolDetector>> = vec![
+        Arc::new(Rqrr),
+    ];
+    detectors
+}
+
+/// Find a detector by its name, where `all` is every available detector combined by `TryAll`.
+pub fn by_name(name: &str) -> Option<Arc<dyn SymbolDetector>> {
+    if name == "all" { return Some(Arc::new(TryAll::new(available()))); }
+    available().into_iter().find(|detector| detector.name() == name)
+}
+
+/// The names `by_name` accepts.
+pub fn names() -> Vec<&'static str> {
+    available().iter().map(|detector| detector.name()).chain(std::iter::once("all")).collect()
+}
+
+/// The detector from the rqrr crate, which is always built in, so that there is always a detector to fall back on.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rqrr;
+
+impl SymbolDetector for Rqrr {
+    fn name(&self) -> &'static str { "rqrr" }
+
+    fn detect(&self, img: &GrayImage) -> Vec<Grid> {
+        let mut prep_img = rqrr::PreparedImage::prepare(img.clone());
+        prep_img.detect_grids().iter().map(|grid| {
+            let corners = [0, 1, 2, 3].map(|i| (grid.bounds[i].x, grid.bounds[i].y));
+            let decoded = match grid.decode() {
+                Ok((meta, content)) => {
+                    // rqrr reports the two format bits, which order the levels M, L, H, Q.
+                    let ecc_level = match meta.ecc_level {
+                        0 => EcLevel::M,
+                        1 => EcLevel::L,
+                        2 => EcLevel::H,
+                        _ => EcLevel::Q,
+                    };
+                    Ok((CodeInfo { version: meta.version.0, ecc_level, mask: meta.mask as u8 }, content))
+                },
+                Err(error) => Err(DecodeError { detector: self.name(), message: error.to_string() }),
+            };
+            Grid { corners, decoded, detector: self.name() }
+        }).collect()
+    }
+}
+
+/// Runs several detectors on each image and combines what they found.
+///
+/// A grid found by several of them is reported once, decoded by the first one that could decode it.
+#[derive(Debug, Clone)]
+pub struct TryAll {
+    detectors: Vec<Arc<dyn SymbolDetector>>,
+}
+
+impl TryAll {
+    pub fn new(detectors: Vec<Arc<dyn SymbolDetector>>) -> Self {
+        TryAll { detectors }
+    }
+}
+
+impl SymbolDetector for TryAll {
+    fn name(&self) -> &'static str { "all" }
+
+    fn detect(&self, img: &GrayImage) -> Vec<Grid> {
+        let mut output: Vec<Grid> = Vec::new();
+        for detector in self.detectors.iter() {
+            for grid in detector.detect(img) {
+                match output.iter_mut().find(|found| found.covers(grid.center())) {
+                    Some(found) => if found.decoded.is_err() && grid.decoded.is_ok() { *found = grid; },
+                    None => output.push(grid),
+                }
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detector::*;
+    use crate::qr_reader::{to_greyscale, Channel};
+    use crate::qr_writer::{string_to_qrcode_with_version, Version};
+
+    /// A detector that finds made-up grids, to check how they are combined.
+    #[derive(Debug)]
+    struct Fixed(&'static str, Vec<Grid>);
+
+    impl SymbolDetector for Fixed {
+        fn name(&self) -> &'static str { self.0 }
+        fn detect(&self, _img: &GrayImage) -> Vec<Grid> { self.1.clone() }
+    }
+
+    fn grid(detector: &'static str, left: i32, text: Option<&str>) -> Grid {
+        let decoded = match text {
+            Some(text) => Ok((CodeInfo { version: 1, ecc_level: EcLevel::L, mask: 0 }, text.to_string())),
+            None => Err(DecodeError { detector, message: "unreadable".to_string() }),
+        };
+        Grid { corners: [(left, 0), (left + 20, 0), (left + 20, 20), (left, 20)], decoded, detector }
+    }
+
+    #[test]
+    fn test_rqrr_reports_code_info() {
+        let code = string_to_qrcode_with_version("backend".to_string(), Version::Normal(3), EcLevel::Q, 4).unwrap();
+        let grids = Rqrr.detect(&to_greyscale(&code, Channel::Luma));
+        assert_eq!(grids.len(), 1);
+        let (info, content) = grids[0].decoded.clone().unwrap();
+        assert_eq!((info.version, info.ecc_level, content.as_str()), (3, EcLevel::Q, "backend"));
+        assert_eq!(grids[0].detector, "rqrr");
+    }
+
+    #[test]
+    fn test_try_all_combines_detectors() {
+        let first = Fixed("first", vec![grid("first", 0, None), grid("first", 100, Some("b"))]);
+        let second = Fixed("second", vec![grid("second", 2, Some("a")), grid("second", 101, Some("other")), grid("second", 200, Some("c"))]);
+        let grids = TryAll::new(vec![Arc::new(first), Arc::new(second)]).detect(&GrayImage::new(1, 1));
+        let found: Vec<_> = grids.iter().map(|grid| (grid.detector, grid.decoded.clone().ok().map(|(_, text)| text))).collect();
+        assert_eq!(found, vec![("second", Some("a".to_string())), ("first", Some("b".to_string())), ("second", Some("c".to_string()))]);
+    }
+
+    #[test]
+    fn test_detectors_are_found_by_name() {
+        assert_eq!(by_name("rqrr").unwrap().name(), "rqrr");
+        assert_eq!(by_name("all").unwrap().name(), "all");
+        assert!(by_name("zbar").is_none());
+        assert_eq!(names(), vec!["rqrr", "all"]);
+    }
+}
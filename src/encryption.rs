@@ -0,0 +1,298 @@
+use argon2::{Argon2, Algorithm, Params};
+use bech32::{FromBase32, ToBase32, Variant};
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use chacha20poly1305::aead::Aead;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use thiserror::Error;
+use x25519_dalek::{PublicKey, StaticSecret};
+use std::convert::TryInto;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::symbol::{Encryption, RecipientSymbol};
+
+/// The memory Argon2 uses by default, in KiB.
+pub const DEFAULT_KDF_MEMORY: u32 = Params::DEFAULT_M_COST;
+
+/// Errors that may occur while deriving a key or decrypting a file.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum EncryptionError {
+    /// The meta symbol asks for a key derivation that cannot be carried out.
+    #[error("the key derivation parameters cannot be used: {0}")]
+    InvalidParameters(String),
+
+    /// The file did not pass the AEAD's check. Its hash had already matched, so the key must be wrong.
+    #[error("the passphrase is wrong")]
+    WrongPassphrase,
+
+    /// The file was encrypted to recipients, so a passphrase cannot decrypt it.
+    #[error("the file was encrypted to recipients, so it needs an identity rather than a passphrase")]
+    NeedsIdentity,
+
+    /// The file was encrypted with a passphrase, so an identity cannot decrypt it.
+    #[error("the file was encrypted with a passphrase, so it needs a passphrase rather than an identity")]
+    NeedsPassphrase,
+
+    /// None of the identities could unwrap the key in any of the recipient symbols that were read.
+    #[error("none of the identities can decrypt it, using the {read} of its {recipients} recipient symbols that were read")]
+    NoMatchingIdentity { read: usize, recipients: usize },
+
+    /// A recipient or identity could not be parsed.
+    #[error("{0:?} is not a valid {1}")]
+    InvalidKey(String, &'static str),
+}
+
+/// The key a file is encrypted with, either derived from a passphrase or unwrapped with an identity.
+pub struct SecretKey([u8; 32]);
+
+impl SecretKey {
+    /// The key the manifest of a directory tree is encrypted with, which is derived from the file's key so that both can use the same nonce.
+    pub fn manifest_key(&self) -> SecretKey {
+        let mut key = [0; 32];
+        Hkdf::<Sha256>::new(None, &self.0).expand(MANIFEST_LABEL, &mut key).expect("32 bytes is a valid length for HKDF");
+        SecretKey(key)
+    }
+}
+
+/// The random key of a file encrypted to recipients, which is wrapped for each of them, and from which the file's `SecretKey` is derived.
+pub struct FileKey([u8; 16]);
+
+impl FileKey {
+    pub fn generate() -> Self { FileKey(rand::random()) }
+}
+
+/// Labels that keep keys derived for different purposes apart.
+const STANZA_LABEL: &[u8] = b"age-encryption.org/v1/X25519";
+const PAYLOAD_LABEL: &[u8] = b"payload";
+const MANIFEST_LABEL: &[u8] = b"manifest";
+
+/// An X25519 public key that a file can be encrypted to, written like an age recipient: `age1...`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Recipient(PublicKey);
+
+/// An X25519 private key that decrypts the files encrypted to its public key, written like an age identity: `AGE-SECRET-KEY-1...`.
+#[derive(Clone)]
+pub struct Identity(StaticSecret);
+
+const RECIPIENT_PREFIX: &str = "age";
+const IDENTITY_PREFIX: &str = "age-secret-key-";
+
+/// Decode a 32-byte key written in Bech32 with this prefix, in either case.
+pub(crate) fn decode_bech32_key(text: &str, prefix: &str) -> Option<[u8; 32]> {
+    let (hrp, data, variant) = bech32::decode(text).ok()?;
+    if hrp != prefix || variant != Variant::Bech32 { return None; }
+    Vec::<u8>::from_base32(&data).ok()?.as_slice().try_into().ok()
+}
+
+/// Write a 32-byte key in Bech32 with this prefix, in lowercase.
+pub(crate) fn encode_bech32_key(key: &[u8; 32], prefix: &str) -> String {
+    bech32::encode(prefix, key.to_base32(), Variant::Bech32).expect("the prefix is valid Bech32")
+}
+
+/// Derive the key that wraps a file key for a recipient, from the secret shared between an ephemeral key and the recipient's key.
+fn wrapping_key(shared: &[u8; 32], share: &PublicKey, recipient: &PublicKey) -> SecretKey {
+    let salt = [share.as_bytes().as_ref(), recipient.as_bytes().as_ref()].concat();
+    let mut key = [0; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared).expand(STANZA_LABEL, &mut key).expect("32 bytes is a valid length for HKDF");
+    SecretKey(key)
+}
+
+impl Recipient {
+    /// Wrap this file key so that only the matching identity can unwrap it, in the recipient symbol with this number.
+    pub fn wrap(&self, file_key: &FileKey, seq_id: u8, recipient: usize) -> RecipientSymbol {
+        let ephemeral = StaticSecret::from(rand::random::<[u8; 32]>());
+        let share = PublicKey::from(&ephemeral);
+        let shared = ephemeral.diffie_hellman(&self.0);
+        let key = wrapping_key(shared.as_bytes(), &share, &self.0);
+        let body = ChaCha20Poly1305::new(Key::from_slice(&key.0)).encrypt(&Nonce::default(), file_key.0.as_ref()).expect("encrypting in memory failed?!");
+        RecipientSymbol { seq_id, recipient, share: base64::encode(share.as_bytes()), body: base64::encode(body) }
+    }
+}
+
+impl FromStr for Recipient {
+    type Err = EncryptionError;
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let key = decode_bech32_key(text, RECIPIENT_PREFIX).ok_or_else(|| EncryptionError::InvalidKey(text.to_string(), "recipient"))?;
+        Ok(Recipient(PublicKey::from(key)))
+    }
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str(&encode_bech32_key(self.0.as_bytes(), RECIPIENT_PREFIX)) }
+}
+
+impl Identity {
+    pub fn generate() -> Self { Identity(StaticSecret::from(rand::random::<[u8; 32]>())) }
+
+    /// The public key that files are encrypted to for this identity.
+    pub fn to_public(&self) -> Recipient { Recipient(PublicKey::from(&self.0)) }
+
+    /// Unwrap the file key in this recipient symbol, if it was wrapped for this identity.
+    pub fn unwrap(&self, symb: &RecipientSymbol) -> Option<FileKey> {
+        let share = PublicKey::from(symb.get_share().ok()?);
+        let shared = self.0.diffie_hellman(&share);
+        // A share of low order gives a shared secret that anyone can work out.
+        if !shared.was_contributory() { return None; }
+        let key = wrapping_key(shared.as_bytes(), &share, &self.to_public().0);
+        let file_key = ChaCha20Poly1305::new(Key::from_slice(&key.0)).decrypt(&Nonce::default(), symb.get_body().ok()?.as_ref()).ok()?;
+        Some(FileKey(file_key.as_slice().try_into().ok()?))
+    }
+
+    /// Read the identities in an identity file, one on each line, skipping blank lines and comments starting with `#`.
+    pub fn parse_file(contents: &str) -> Result<Vec<Identity>, EncryptionError> {
+        contents.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')).map(str::parse).collect()
+    }
+}
+
+impl FromStr for Identity {
+    type Err = EncryptionError;
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        // The key itself must not end up in logs, so only its prefix is reported.
+        let key = decode_bech32_key(text, IDENTITY_PREFIX).ok_or_else(|| EncryptionError::InvalidKey(text.chars().take(16).collect::<String>() + "...", "identity"))?;
+        Ok(Identity(StaticSecret::from(key)))
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str(&encode_bech32_key(&self.0.to_bytes(), IDENTITY_PREFIX).to_uppercase()) }
+}
+
+impl Encryption {
+    /// Settings for encrypting a new file with a passphrase: a fresh salt and nonce, Argon2 with this much memory in KiB, and the number of passes and lanes it recommends.
+    pub fn generate(m_cost: u32) -> Self {
+        Encryption {
+            cipher: Encryption::CIPHER.to_string(),
+            kdf: Encryption::KDF_PASSPHRASE.to_string(),
+            salt: Some(base64::encode(rand::random::<[u8; Encryption::SALT_LEN]>())),
+            m_cost: Some(m_cost),
+            t_cost: Some(Params::DEFAULT_T_COST),
+            p_cost: Some(Params::DEFAULT_P_COST),
+            recipients: None,
+            nonce: base64::encode(rand::random::<[u8; Encryption::NONCE_LEN]>()),
+        }
+    }
+
+    /// Settings for encrypting a new file to this many recipients, with a fresh nonce.
+    pub fn for_recipients(recipients: usize) -> Self {
+        Encryption {
+            cipher: Encryption::CIPHER.to_string(),
+            kdf: Encryption::KDF_RECIPIENTS.to_string(),
+            salt: None,
+            m_cost: None,
+            t_cost: None,
+            p_cost: None,
+            recipients: Some(recipients),
+            nonce: base64::encode(rand::random::<[u8; Encryption::NONCE_LEN]>()),
+        }
+    }
+
+    /// Derive the key from this passphrase, which takes a noticeable amount of time and memory on purpose.
+    pub fn derive_key(&self, passphrase: &str) -> Result<SecretKey, EncryptionError> {
+        if !self.uses_passphrase() { return Err(EncryptionError::NeedsIdentity); }
+        let invalid = |error: &dyn std::fmt::Display| EncryptionError::InvalidParameters(error.to_string());
+        let salt = self.get_salt().map_err(|error| invalid(&error))?;
+        let (m_cost, t_cost, p_cost) = self.get_costs().map_err(|error| invalid(&error))?;
+        let params = Params::new(m_cost, t_cost, p_cost, Some(32)).map_err(|error| invalid(&error))?;
+        let mut key = [0; 32];
+        Argon2::new(Algorithm::Argon2id, argon2::Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key).map_err(|error| invalid(&error))?;
+        Ok(SecretKey(key))
+    }
+
+    /// Derive the key of a file encrypted to recipients from its file key.
+    pub fn payload_key(&self, file_key: &FileKey) -> Result<SecretKey, EncryptionError> {
+        if self.uses_passphrase() { return Err(EncryptionError::NeedsPassphrase); }
+        let nonce = self.get_nonce().map_err(|error| EncryptionError::InvalidParameters(error.to_string()))?;
+        let mut key = [0; 32];
+        Hkdf::<Sha256>::new(Some(&nonce), &file_key.0).expand(PAYLOAD_LABEL, &mut key).expect("32 bytes is a valid length for HKDF");
+        Ok(SecretKey(key))
+    }
+
+    fn cipher_and_nonce(&self, key: &SecretKey) -> Result<(ChaCha20Poly1305, [u8; Encryption::NONCE_LEN]), EncryptionError> {
+        let nonce = self.get_nonce().map_err(|error| EncryptionError::InvalidParameters(error.to_string()))?;
+        Ok((ChaCha20Poly1305::new(Key::from_slice(&key.0)), nonce))
+    }
+
+    /// Encrypt this data, appending the AEAD's tag.
+    pub fn encrypt(&self, key: &SecretKey, data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let (cipher, nonce) = self.cipher_and_nonce(key)?;
+        Ok(cipher.encrypt(Nonce::from_slice(&nonce), data).expect("encrypting in memory failed?!"))
+    }
+
+    /// Undo `encrypt`, failing if the key is not the one the data was encrypted with.
+    pub fn decrypt(&self, key: &SecretKey, data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let (cipher, nonce) = self.cipher_and_nonce(key)?;
+        cipher.decrypt(Nonce::from_slice(&nonce), data).map_err(|_| EncryptionError::WrongPassphrase)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encryption::*;
+
+    /// Settings with the smallest costs Argon2 allows, to keep the tests quick.
+    fn cheap() -> Encryption {
+        Encryption { t_cost: Some(1), ..Encryption::generate(8) }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let encryption = cheap();
+        let key = encryption.derive_key("correct horse battery staple").unwrap();
+        let encrypted = encryption.encrypt(&key, b"secret file").unwrap();
+        assert_eq!(encrypted.len(), b"secret file".len() + 16);
+        assert!(!encrypted.windows(6).any(|window| window == b"secret"));
+        assert_eq!(encryption.decrypt(&key, &encrypted).unwrap(), b"secret file");
+    }
+
+    #[test]
+    fn test_wrong_passphrase_is_rejected() {
+        let encryption = cheap();
+        let encrypted = encryption.encrypt(&encryption.derive_key("right").unwrap(), b"secret file").unwrap();
+        assert_eq!(encryption.decrypt(&encryption.derive_key("wrong").unwrap(), &encrypted), Err(EncryptionError::WrongPassphrase));
+        // The same passphrase with another salt gives another key.
+        let other_salt = Encryption { salt: cheap().salt, ..encryption.clone() };
+        assert_eq!(encryption.decrypt(&other_salt.derive_key("right").unwrap(), &encrypted), Err(EncryptionError::WrongPassphrase));
+    }
+
+    #[test]
+    fn test_unusable_costs_are_rejected() {
+        let encryption = Encryption { p_cost: Some(0), ..cheap() };
+        assert!(matches!(encryption.derive_key("passphrase"), Err(EncryptionError::InvalidParameters(_))));
+    }
+
+    #[test]
+    fn test_file_key_is_unwrapped_only_by_its_recipients() {
+        let (alice, bob, eve) = (Identity::generate(), Identity::generate(), Identity::generate());
+        let file_key = FileKey::generate();
+        let stanzas: Vec<RecipientSymbol> = [alice.to_public(), bob.to_public()].iter().enumerate().map(|(i, recipient)| recipient.wrap(&file_key, 7, i)).collect();
+        assert_eq!(alice.unwrap(&stanzas[0]).map(|key| key.0), Some(file_key.0));
+        assert_eq!(bob.unwrap(&stanzas[1]).map(|key| key.0), Some(file_key.0));
+        assert!(alice.unwrap(&stanzas[1]).is_none());
+        assert!(stanzas.iter().all(|stanza| eve.unwrap(stanza).is_none()));
+        let low_order = RecipientSymbol { share: base64::encode([0; 32]), ..stanzas[0].clone() };
+        assert!(alice.unwrap(&low_order).is_none());
+
+        let encryption = Encryption::for_recipients(2);
+        let key = encryption.payload_key(&file_key).unwrap();
+        let encrypted = encryption.encrypt(&key, b"for alice and bob").unwrap();
+        let unwrapped = bob.unwrap(&stanzas[1]).unwrap();
+        assert_eq!(encryption.decrypt(&encryption.payload_key(&unwrapped).unwrap(), &encrypted).unwrap(), b"for alice and bob");
+        assert_eq!(encryption.derive_key("a passphrase").err(), Some(EncryptionError::NeedsIdentity));
+    }
+
+    #[test]
+    fn test_keys_are_written_like_age_keys() {
+        // Alice's key pair from RFC 7748, section 6.1.
+        let identity: Identity = "AGE-SECRET-KEY-1WURK6ZNNRZJH60QKC9E9RVNXGH05CTU8A0QFJ243WLA628DE9S4QRFH26J".parse().unwrap();
+        assert_eq!(identity.to_public().to_string(), "age1s5s0qzvfxzn4gayt0hwtg0hhtgxm7wsdycup4a8t5j5ca25mfe4qt4hs7q");
+        assert_eq!(identity.to_string(), "AGE-SECRET-KEY-1WURK6ZNNRZJH60QKC9E9RVNXGH05CTU8A0QFJ243WLA628DE9S4QRFH26J");
+        let generated = Identity::generate();
+        assert_eq!(generated.to_public().to_string().parse::<Recipient>(), Ok(generated.to_public()));
+        assert!("age1notakey".parse::<Recipient>().is_err());
+        assert!(generated.to_public().to_string().parse::<Identity>().is_err());
+        let file = format!("# created: today\n# public key: {}\n{}\n\n", generated.to_public(), generated);
+        assert_eq!(Identity::parse_file(&file).unwrap().iter().map(Identity::to_public).collect::<Vec<_>>(), vec![generated.to_public()]);
+    }
+}
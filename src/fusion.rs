@@ -0,0 +1,316 @@
+use image::{Rgb, RgbImage};
+use log::debug;
+
+/// How the pixels of several frames are combined into one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FusionMethod {
+    /// Average the frames. This cancels out small noise best.
+    Mean,
+    /// Take the median of the frames. This ignores a few badly damaged frames completely.
+    Median,
+}
+
+/// Configuration for fusing consecutive frames that show the same symbols.
+#[derive(Debug, Clone)]
+pub struct FusionConfig {
+    /// How the frames of a group are combined.
+    pub method: FusionMethod,
+
+    /// Each frame is shifted by up to this many pixels in each direction to line it up with the first frame of its group.
+    pub max_shift: u32,
+
+    /// A frame starts a new group if more than this fraction of its pixels is very different from the first frame of the current group.
+    pub change_fraction: f32,
+
+    /// At most this many frames are fused together, which bounds the memory used.
+    pub max_frames: usize,
+}
+
+impl FusionConfig {
+    pub fn new(method: FusionMethod) -> Self {
+        FusionConfig { method, max_shift: 2, change_fraction: 0.01, max_frames: 16 }
+    }
+}
+
+/// Frames are compared in blocks of this many pixels in each direction when looking for changes and duplicates.
+const SAMPLE_STEP: u32 = 4;
+
+/// Only every this many pixels in each direction are compared when aligning frames, which is dense enough that noise averages out.
+const ALIGN_STEP: u32 = 2;
+
+/// Blocks whose average brightness differs by more than this are counted as changed, rather than as noise.
+const CHANGE_THRESHOLD: i32 = 96;
+
+/// Fuses runs of consecutive frames that show the same symbols, like the ones of a sequence encoded with persistence, into single frames with less noise.
+pub struct FrameFuser {
+    config: FusionConfig,
+    /// The frames of the current group, already lined up with the first one.
+    group: Vec<RgbImage>,
+}
+
+impl FrameFuser {
+    pub fn new(config: FusionConfig) -> Self {
+        FrameFuser { config, group: Vec::new() }
+    }
+
+    /// Add the next frame of the video.
+    ///
+    /// If this frame shows different symbols from the frames added before it, or the group is full, those frames are fused and returned along with how many there were.
+    pub fn push(&mut self, frame: RgbImage) -> Option<(RgbImage, usize)> {
+        let aligned = match self.group.first() {
+            None => { self.group.push(frame); return None; },
+            Some(reference) if reference.dimensions() != frame.dimensions() => None,
+            Some(reference) => {
+                let (dx, dy) = best_shift(reference, &frame, self.config.max_shift as i32);
+                let shifted = shift(&frame, dx, dy);
+                if changed_fraction(reference, &shifted) > self.config.change_fraction { None } else { Some(shifted) }
+            },
+        };
+        match aligned {
+            Some(aligned) if self.group.len() < self.config.max_frames => { self.group.push(aligned); None },
+            _ => {
+                let fused = self.finish();
+                self.group.push(frame);
+                fused
+            },
+        }
+    }
+
+    /// Fuse the frames that have been added since the last fused frame was returned, if there are any.
+    pub fn finish(&mut self) -> Option<(RgbImage, usize)> {
+        if self.group.is_empty() { return None; }
+        let group = std::mem::take(&mut self.group);
+        debug!("Fusing a group of {} frames", group.len());
+        let count = group.len();
+        let fused = match self.config.method {
+            FusionMethod::Mean => mean(&group),
+            FusionMethod::Median => median(&group),
+        };
+        Some((fused, count))
+    }
+}
+
+/// Frames are compared in blocks of this many pixels in each direction when looking for duplicates.
+///
+/// This is finer than `SAMPLE_STEP`, so that a single module changes at least a quarter of some block, even at one pixel per module.
+const DUPLICATE_STEP: u32 = 2;
+
+/// A block whose average brightness differs by more than this from the same block in the previous frame means the frame is not a duplicate.
+///
+/// This is much lower than `CHANGE_THRESHOLD`, because a single changed module must be noticed even if the rest of the frame is the same:
+/// a module that covers only a quarter of a block still moves its average by more than this if the code's contrast is over 128.
+const DUPLICATE_THRESHOLD: i32 = 32;
+
+/// Recognizes frames that show the same as the last frame that was kept, like the repeats of a sequence encoded with persistence, so that they need not be read again.
+#[derive(Default)]
+pub struct DuplicateFilter {
+    /// The size of the last frame that was kept, and the average brightness of each block of it.
+    kept: Option<((u32, u32), Vec<i32>)>,
+}
+
+impl DuplicateFilter {
+    pub fn new() -> Self { Default::default() }
+
+    /// Check whether this frame is the same as the last one kept, apart from a little noise. If it is not, it is kept.
+    pub fn is_duplicate(&mut self, frame: &RgbImage) -> bool {
+        let blocks = block_brightnesses(frame);
+        if let Some((dimensions, ref kept)) = self.kept {
+            if dimensions == frame.dimensions() && kept.iter().zip(blocks.iter()).all(|(a, b)| (a - b).abs() <= DUPLICATE_THRESHOLD) {
+                return true;
+            }
+        }
+        self.kept = Some((frame.dimensions(), blocks));
+        false
+    }
+}
+
+/// Get the average brightness of each `DUPLICATE_STEP` block of the frame, row by row.
+fn block_brightnesses(frame: &RgbImage) -> Vec<i32> {
+    let (w, h) = frame.dimensions();
+    let mut blocks = Vec::new();
+    for top in (0..h).step_by(DUPLICATE_STEP as usize) {
+        for left in (0..w).step_by(DUPLICATE_STEP as usize) {
+            blocks.push(block_brightness(frame, left, top, DUPLICATE_STEP));
+        }
+    }
+    blocks
+}
+
+/// Get the brightness of a pixel, roughly, for comparing frames.
+fn brightness(pixel: &Rgb<u8>) -> i32 {
+    (pixel[0] as i32 + 2 * pixel[1] as i32 + pixel[2] as i32) / 4
+}
+
+/// Get the total difference in brightness between the reference and the frame moved by this offset, over a sparse grid of pixels.
+fn sampled_difference(reference: &RgbImage, frame: &RgbImage, dx: i32, dy: i32, margin: u32) -> u64 {
+    let (w, h) = reference.dimensions();
+    let mut total = 0;
+    for y in (margin..h.saturating_sub(margin)).step_by(ALIGN_STEP as usize) {
+        for x in (margin..w.saturating_sub(margin)).step_by(ALIGN_STEP as usize) {
+            let moved = frame.get_pixel((x as i32 + dx) as u32, (y as i32 + dy) as u32);
+            total += (brightness(reference.get_pixel(x, y)) - brightness(moved)).unsigned_abs() as u64;
+        }
+    }
+    total
+}
+
+/// Find the offset of at most `max_shift` pixels in each direction that lines the frame up best with the reference.
+fn best_shift(reference: &RgbImage, frame: &RgbImage, max_shift: i32) -> (i32, i32) {
+    let mut best = ((0, 0), sampled_difference(reference, frame, 0, 0, max_shift as u32));
+    for dy in -max_shift..=max_shift {
+        for dx in -max_shift..=max_shift {
+            let difference = sampled_difference(reference, frame, dx, dy, max_shift as u32);
+            if difference < best.1 { best = ((dx, dy), difference); }
+        }
+    }
+    best.0
+}
+
+/// Move the content of a frame so that the pixel at `(x + dx, y + dy)` ends up at `(x, y)`, repeating the edge pixels to fill the gap.
+fn shift(frame: &RgbImage, dx: i32, dy: i32) -> RgbImage {
+    if (dx, dy) == (0, 0) { return frame.clone(); }
+    let (w, h) = frame.dimensions();
+    RgbImage::from_fn(w, h, |x, y| {
+        let sx = (x as i32 + dx).clamp(0, w as i32 - 1) as u32;
+        let sy = (y as i32 + dy).clamp(0, h as i32 - 1) as u32;
+        *frame.get_pixel(sx, sy)
+    })
+}
+
+/// Get the average brightness of the block of pixels of this size that starts at this corner.
+fn block_brightness(frame: &RgbImage, left: u32, top: u32, size: u32) -> i32 {
+    let (w, h) = frame.dimensions();
+    let (mut total, mut count) = (0, 0);
+    for y in top..(top + size).min(h) {
+        for x in left..(left + size).min(w) {
+            total += brightness(frame.get_pixel(x, y));
+            count += 1;
+        }
+    }
+    total / count.max(1)
+}
+
+/// Get the fraction of blocks of pixels whose brightness changed by more than noise would explain.
+///
+/// Comparing the averages of blocks rather than single pixels keeps strong noise from looking like a change of content.
+fn changed_fraction(reference: &RgbImage, frame: &RgbImage) -> f32 {
+    let (w, h) = reference.dimensions();
+    let (mut changed, mut total) = (0, 0);
+    for y in (0..h).step_by(SAMPLE_STEP as usize) {
+        for x in (0..w).step_by(SAMPLE_STEP as usize) {
+            if (block_brightness(reference, x, y, SAMPLE_STEP) - block_brightness(frame, x, y, SAMPLE_STEP)).abs() > CHANGE_THRESHOLD { changed += 1; }
+            total += 1;
+        }
+    }
+    changed as f32 / total.max(1) as f32
+}
+
+/// Average each subpixel over all the frames.
+fn mean(frames: &[RgbImage]) -> RgbImage {
+    let (w, h) = frames[0].dimensions();
+    let mut sums = vec![0u32; (w * h * 3) as usize];
+    for frame in frames {
+        for (sum, value) in sums.iter_mut().zip(frame.iter()) { *sum += *value as u32; }
+    }
+    let count = frames.len() as u32;
+    let data = sums.iter().map(|sum| ((sum + count / 2) / count) as u8).collect();
+    RgbImage::from_raw(w, h, data).expect("fused buffer has the size of the frames")
+}
+
+/// Take the median of each subpixel over all the frames.
+fn median(frames: &[RgbImage]) -> RgbImage {
+    let (w, h) = frames[0].dimensions();
+    let mut values = Vec::with_capacity(frames.len());
+    let data = (0..(w * h * 3) as usize).map(|i| {
+        values.clear();
+        values.extend(frames.iter().map(|frame| frame.as_ref()[i]));
+        values.sort_unstable();
+        values[values.len() / 2]
+    }).collect();
+    RgbImage::from_raw(w, h, data).expect("fused buffer has the size of the frames")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fusion::*;
+    use crate::qr_reader::strings_from_image;
+    use crate::qr_writer::{string_to_qrcode_with_version, Version, EcLevel};
+
+    /// Make copies of a frame with strong random noise, each moved by up to one pixel, like consecutive frames of a re-encoded video.
+    fn noisy_copies(frame: &RgbImage, count: usize, amplitude: i32) -> Vec<RgbImage> {
+        let mut seed = 987654321u32;
+        let mut random = move || { seed = seed.wrapping_mul(1103515245).wrapping_add(12345); (seed >> 16) as i32 };
+        (0..count).map(|i| {
+            let moved = shift(frame, (i % 3) as i32 - 1, 0);
+            RgbImage::from_fn(frame.width(), frame.height(), |x, y| {
+                let noise = random() % (2 * amplitude + 1) - amplitude;
+                let value = (moved.get_pixel(x, y)[0] as i32 + noise).clamp(0, 255) as u8;
+                Rgb([value, value, value])
+            })
+        }).collect()
+    }
+
+    #[test]
+    fn test_fusion_recovers_noisy_frames() {
+        let code = string_to_qrcode_with_version("fused".to_string(), Version::Normal(2), EcLevel::L, 4).unwrap();
+        let frames = noisy_copies(&code, 8, 180);
+        assert!(frames.iter().all(|frame| strings_from_image(frame).is_empty()));
+        for &method in [FusionMethod::Mean, FusionMethod::Median].iter() {
+            let mut fuser = FrameFuser::new(FusionConfig::new(method));
+            for frame in frames.iter() { assert!(fuser.push(frame.clone()).is_none()); }
+            let (fused, count) = fuser.finish().unwrap();
+            assert_eq!(count, 8);
+            assert_eq!(strings_from_image(&fused), vec!["fused"], "{:?} fusion failed", method);
+        }
+    }
+
+    #[test]
+    fn test_changed_frame_starts_new_group() {
+        let first = string_to_qrcode_with_version("first".to_string(), Version::Normal(2), EcLevel::L, 4).unwrap();
+        let second = string_to_qrcode_with_version("second".to_string(), Version::Normal(2), EcLevel::L, 4).unwrap();
+        let mut config = FusionConfig::new(FusionMethod::Mean);
+        config.max_frames = 3;
+        let mut fuser = FrameFuser::new(config);
+        let mut counts = Vec::new();
+        for frame in [&first, &first, &second, &second, &second, &second].iter() {
+            if let Some((fused, count)) = fuser.push((*frame).clone()) {
+                counts.push(count);
+                let expected = if counts.len() == 1 { &first } else { &second };
+                assert!(fused.as_ref() == expected.as_ref());
+            }
+        }
+        counts.push(fuser.finish().unwrap().1);
+        assert_eq!(counts, vec![2, 3, 1]);
+        assert!(fuser.finish().is_none());
+    }
+
+    #[test]
+    fn test_duplicate_frames_are_recognized() {
+        let first = string_to_qrcode_with_version("first".to_string(), Version::Normal(2), EcLevel::L, 4).unwrap();
+        // One module of the code changes, and the frames after it only differ by noise.
+        let mut changed = first.clone();
+        for y in 60..64 { for x in 60..64 { let pixel = changed.get_pixel_mut(x, y); pixel.data = [255 - pixel[0]; 3]; } }
+        let noisy = |seed: u32| RgbImage::from_fn(changed.width(), changed.height(), |x, y| {
+            let noise = ((x * 7 + y * 13 + seed) % 21) as i32 - 10;
+            Rgb([(changed.get_pixel(x, y)[0] as i32 + noise).clamp(0, 255) as u8; 3])
+        });
+        let mut filter = DuplicateFilter::new();
+        let results: Vec<bool> = [&first, &first, &changed, &noisy(0), &noisy(5), &first].iter().map(|frame| filter.is_duplicate(frame)).collect();
+        assert_eq!(results, vec![false, true, false, true, true, false]);
+    }
+
+    #[test]
+    fn test_single_module_change_is_not_a_duplicate() {
+        for module_size in 1..=4 {
+            let first = string_to_qrcode_with_version("first".to_string(), Version::Normal(2), EcLevel::L, module_size).unwrap();
+            // Flip one module, which only covers part of the blocks the frames are compared in unless the sizes line up.
+            let mut changed = first.clone();
+            let (left, top) = (11 * module_size, 13 * module_size);
+            for y in top..top + module_size { for x in left..left + module_size { let pixel = changed.get_pixel_mut(x, y); pixel.data = [255 - pixel[0]; 3]; } }
+            let mut filter = DuplicateFilter::new();
+            assert!(!filter.is_duplicate(&first));
+            assert!(!filter.is_duplicate(&changed), "a changed module of {} pixels was missed", module_size);
+            assert!(filter.is_duplicate(&changed));
+        }
+    }
+}
@@ -10,8 +10,23 @@ mod qr_reader;
 mod qr_writer;
 mod video;
 mod sequence;
+mod compositor;
+mod preprocess;
+mod fusion;
+mod tracking;
+mod pipeline;
+mod perspective;
+mod detector;
+mod merkle;
+mod compression;
+mod encryption;
+mod signature;
+mod output;
+mod archive;
 
 use image::open;
+use std::path::Path;
+use std::io::Write;
 
 fn main() {
     simple_logger::init().unwrap();
@@ -22,15 +37,404 @@ fn main() {
         (@subcommand qrread =>
             (about: "tests the QR-code symbol reader")
             (@arg file: -f +takes_value "file to read qr codes from")
+            (@arg compare_detectors: --("compare-detectors") "read the image with each available detector in turn and report how many grids each one read")
+            (@arg channels: --channels "read the red, green and blue planes as separate images")
+            (@arg channel: --channel +takes_value possible_value[luma red green blue] "which part of the colour to use as brightness (default: luma)")
+            (@arg preprocess: --preprocess +takes_value "comma-separated image preprocessing steps, from stretch[:CLIP_PERCENT], median[:RADIUS up to 16], upscale[:FACTOR up to 8], threshold[:RADIUS[:OFFSET]], level[:PERCENT] and sharpen")
+            (@arg no_second_chance: --("no-second-chance") "do not retry grids that fail to decode")
+            (@arg no_geometry_correction: --("no-geometry-correction") "do not straighten or mirror grids that fail to decode")
+            (@arg detector: --detector +takes_value +multiple number_of_values(1) "QR code detector to use, from rqrr and all, which combines every detector; or CHANNEL=DETECTOR to use it only for the luma, red, green or blue channel (default: rqrr)")
+        )
+        (@subcommand encode =>
+            (about: "encodes a file as a sequence of QR-code frames")
+            (@arg file: -f +takes_value +required "file, or directory tree, to encode")
+            (@arg output: -o +takes_value +required "directory to write the frame images to")
+            (@arg chunk_size: --("chunk-size") +takes_value "bytes of data per content symbol (default: as many as fit in the QR code)")
+            (@arg qr_version: --("qr-version") +takes_value "QR code version, from 1 to 40 (default: 40)")
+            (@arg ecc: --ecc +takes_value possible_value[L M Q H] "QR code error correction level (default: M)")
+            (@arg persist: --persist +takes_value "number of frames to show each symbol for (default: 1)")
+            (@arg meta_every: --("meta-every") +takes_value "number of content symbols between meta symbols (default: 20)")
+            (@arg symbols_per_frame: --("symbols-per-frame") +takes_value "number of symbols to place on each frame (default: 1)")
+            (@arg resolution: --resolution +takes_value "size of each frame in pixels, as WIDTHxHEIGHT (default: 1920x1080)")
+            (@arg grid: --grid +takes_value "arrange the symbols in a grid of COLUMNSxROWS (default: the grid giving each symbol the most room)")
+            (@arg margin: --margin +takes_value "minimum distance in pixels between a symbol and the edge of its cell (default: 16)")
+            (@arg rgb: --rgb "place a separate grid of symbols in each of the red, green and blue planes")
+            (@arg no_chunk_checksums: --("no-chunk-checksums") "leave the checksum out of each content symbol, which makes room for a few more bytes of data")
+            (@arg no_merkle_tree: --("no-merkle-tree") "leave out the Merkle tree that lets each chunk be checked against the meta symbol")
+            (@arg hash: --hash +takes_value "algorithm of the hash of the whole file: sha3-256, sha-256 or blake3 (default: sha3-256)")
+            (@arg compression: --compression +takes_value possible_value[none deflate zstd xz] "how to compress the file before splitting it into symbols; it is sent as it is if this does not make it smaller (default: zstd)")
+            (@arg encrypt: --encrypt "encrypt the file with a passphrase, which is asked for unless --passphrase-file is given")
+            (@arg passphrase_file: --("passphrase-file") +takes_value "encrypt the file with the passphrase in this file")
+            (@arg recipient: --recipient +takes_value +multiple number_of_values(1) "encrypt the file to this X25519 public key, written like an age recipient (age1...); may be given several times")
+            (@arg sign_key: --("sign-key") +takes_value "sign the file with the signing key in this file, such as one written by keygen --signing")
+            (@arg mime: --mime +takes_value "MIME type of the file, such as text/plain, for the decoder to report")
+            (@arg comment: --comment +takes_value "a comment on the file, for the decoder to report")
+            (@arg no_file_attributes: --("no-file-attributes") "do not send the modification time and permissions of the file")
+        )
+        (@subcommand decode =>
+            (about: "decodes the files in a video, or a directory of numbered frame images")
+            (@arg input: -i +takes_value +required "video or directory of frames to read")
+            (@arg output: -o +takes_value "directory to write the decoded files to, under the names their meta symbols give (default: current directory)")
+            (@arg output_file: --output +takes_value conflicts_with[output] "write the decoded file or directory to this path instead, if the input holds only one")
+            (@arg force: --force "replace files that already exist")
+            (@arg fuse: --fuse +takes_value possible_value[mean median] "combine consecutive frames showing the same symbols before reading them")
+            (@arg keep_duplicates: --("keep-duplicates") "read frames again even if they are the same as the one before them")
+            (@arg jobs: -j --jobs +takes_value "number of threads reading symbols from frames (default: one per CPU)")
+            (@arg full_scan_every: --("full-scan-every") +takes_value "search whole frames for symbols only once every this many frames, and otherwise only where symbols were found before; 0 searches every frame in full (default: 30)")
+            (@arg channels: --channels "read the red, green and blue planes as separate images")
+            (@arg channel: --channel +takes_value possible_value[luma red green blue] "which part of the colour to use as brightness (default: luma)")
+            (@arg preprocess: --preprocess +takes_value "comma-separated image preprocessing steps, from stretch[:CLIP_PERCENT], median[:RADIUS up to 16], upscale[:FACTOR up to 8], threshold[:RADIUS[:OFFSET]], level[:PERCENT] and sharpen")
+            (@arg no_second_chance: --("no-second-chance") "do not retry grids that fail to decode")
+            (@arg no_geometry_correction: --("no-geometry-correction") "do not straighten or mirror grids that fail to decode")
+            (@arg detector: --detector +takes_value +multiple number_of_values(1) "QR code detector to use, from rqrr and all, which combines every detector; or CHANNEL=DETECTOR to use it only for the luma, red, green or blue channel (default: rqrr)")
+            (@arg passphrase_file: --("passphrase-file") +takes_value "decrypt encrypted files with the passphrase in this file, instead of asking for it")
+            (@arg identity: --identity +takes_value +multiple number_of_values(1) "decrypt files encrypted to recipients with the identities in this file, such as one written by keygen or age-keygen; may be given several times")
+            (@arg trusted_keys: --("trusted-keys") +takes_value +multiple number_of_values(1) "trust signatures by the verifying keys in this file, one on each line; may be given several times")
+            (@arg trust: --trust +takes_value +multiple number_of_values(1) "trust signatures by this verifying key (datablast-sig1...); may be given several times")
+            (@arg require_signature: --("require-signature") "only write files signed by a trusted key")
+            (@arg max_file_size: --("max-file-size") +takes_value "largest file to decode, in bytes, before and after decompression (default: 1073741824)")
+            (@arg max_chunks: --("max-chunks") +takes_value "most content symbols a file may be split into (default: 4194304)")
+            (@arg max_chunk_size: --("max-chunk-size") +takes_value "most bytes of data a content symbol may carry (default: 4096)")
+            (@arg max_sequences: --("max-sequences") +takes_value "most files to decode from one video (default: 16)")
+            (@arg max_kdf_memory: --("max-kdf-memory") +takes_value "most memory, in KiB, that deriving a key from a passphrase may take (default: 1048576)")
+            (@arg max_kdf_passes: --("max-kdf-passes") +takes_value "most passes that deriving a key from a passphrase may take (default: 16)")
+            (@arg max_kdf_lanes: --("max-kdf-lanes") +takes_value "most lanes that deriving a key from a passphrase may use (default: 16)")
+        )
+        (@subcommand keygen =>
+            (about: "generates an identity, whose public key files can be encrypted to, or a signing key")
+            (@arg output: -o +takes_value +required "file to write the key to, which must not exist yet")
+            (@arg signing: --signing "generate an Ed25519 key to sign files with, instead of an identity")
         )
     ).get_matches();
 
     if let Some(matches) = matches.subcommand_matches("qrread") {
         let filename = matches.value_of("file").expect("file name required");
         info!("Loading image {} ...", filename);
-        let img = open(filename).expect("image invalid").to_rgb();
-        let symbols = qr_reader::symbols_from_image(img);
-        for symb in symbols.iter() {info!("Found symbol: {:?}", symb);}
+        let img = open(filename).expect("image invalid");
+        let config = reader_config(matches);
+        let detections = qr_reader::detect_with_config(&img, &config);
+        for detection in detections.iter() {
+            let rescued = if detection.rescued { ", decoded on a second chance" } else { "" };
+            if let (Some(meta), Some(ecc_level)) = (detection.meta, detection.ecc_level()) {
+                info!("Grid at {:?} in the {:?} channel is a version {} QR code with ECC level {:?} and mask {}, decoded by {}{}", detection.corners, detection.channel, meta.version, ecc_level, meta.mask, detection.detector, rescued);
+            }
+            match detection.symbol {
+                Ok(ref symb) => info!("Found symbol: {:?}", symb),
+                Err(ref error) => match detection.content {
+                    Some(ref content) => warn!("Grid at {:?} in the {:?} channel: {}; it holds {:?}", detection.corners, detection.channel, error, content),
+                    None => warn!("Grid at {:?} in the {:?} channel: {}", detection.corners, detection.channel, error),
+                },
+            }
+        }
+        info!("{}", qr_reader::FrameSummary::of(&detections));
+        if matches.is_present("compare_detectors") {
+            for detector in detector::available() {
+                let config = qr_reader::ReaderConfig { detector: detector.clone(), channel_detectors: Default::default(), ..config.clone() };
+                info!("With {}: {}", detector.name(), qr_reader::FrameSummary::of(&qr_reader::detect_with_config(&img, &config)));
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("encode") {
+        let filename = matches.value_of("file").expect("file name required");
+        let out_dir = Path::new(matches.value_of("output").expect("output directory required"));
+        let mut config = sequence::SequenceEncoderConfig::new();
+        if let Some(size) = matches.value_of("chunk_size") { config.max_bytes_per_data_symbol = Some(size.parse().expect("chunk size must be an integer")); }
+        if let Some(ver) = matches.value_of("qr_version") { config.qr_version = qr_writer::Version::Normal(ver.parse().expect("QR code version must be an integer")); }
+        if let Some(ecc) = matches.value_of("ecc") {
+            config.ecc_level = match ecc {
+                "L" => qr_writer::EcLevel::L,
+                "M" => qr_writer::EcLevel::M,
+                "Q" => qr_writer::EcLevel::Q,
+                _ => qr_writer::EcLevel::H,
+            };
+        }
+        if let Some(frames) = matches.value_of("persist") { config.persist_each_symbol_for_frames = frames.parse().expect("persist count must be an integer"); }
+        if let Some(count) = matches.value_of("meta_every") { config.data_symbols_between_meta_symbols = count.parse().expect("meta symbol interval must be an integer"); }
+        if let Some(count) = matches.value_of("symbols_per_frame") { config.symbols_per_frame = count.parse().expect("symbols per frame must be an integer"); }
+        if let Some(size) = matches.value_of("resolution") { let (w, h) = parse_dimensions(size); config.frame_width = w; config.frame_height = h; }
+        if let Some(grid) = matches.value_of("grid") { config.grid = Some(parse_dimensions(grid)); }
+        if let Some(margin) = matches.value_of("margin") { config.cell_margin = margin.parse().expect("margin must be an integer"); }
+        config.colour_multiplex = matches.is_present("rgb");
+        config.chunk_checksums = !matches.is_present("no_chunk_checksums");
+        config.merkle_tree = !matches.is_present("no_merkle_tree");
+        if let Some(hash) = matches.value_of("hash") {
+            config.hash_algorithm = match hash.parse() {
+                Ok(algorithm) => algorithm,
+                Err(error) => { error!("Invalid hash algorithm: {}", error); std::process::exit(1); },
+            };
+        }
+        if let Some(compression) = matches.value_of("compression") { config.compression = compression.parse().expect("compression was checked by clap"); }
+        for recipient in matches.values_of("recipient").into_iter().flatten() {
+            match recipient.parse() {
+                Ok(recipient) => config.recipients.push(recipient),
+                Err(error) => { error!("Invalid recipient: {}", error); std::process::exit(1); },
+            }
+        }
+        if matches.is_present("encrypt") || matches.is_present("passphrase_file") {
+            if !config.recipients.is_empty() { error!("A file can be encrypted with a passphrase or to recipients, but not both"); std::process::exit(1); }
+            config.passphrase = Some(passphrase(matches, true));
+        }
+        if let Some(path) = matches.value_of("sign_key") {
+            let parsed = std::fs::read_to_string(path).map_err(|error| error.to_string())
+                .and_then(|contents| signature::SigningKey::parse_file(&contents).map_err(|error| error.to_string()));
+            match parsed {
+                Ok(key) => { info!("Signing the file with {}", key.to_public()); config.signing_key = Some(key); },
+                Err(error) => { error!("Could not read the signing key in {:?}: {}", path, error); std::process::exit(1); },
+            }
+        }
+        let (multiplex, symbols_per_plane) = (config.colour_multiplex, config.symbols_per_frame);
+        let (version, ec_level) = (config.qr_version, config.ecc_level);
+        let layout = config.frame_layout();
+
+        let input = Path::new(filename);
+        config.metadata.mime = matches.value_of("mime").map(str::to_string);
+        config.metadata.comment = matches.value_of("comment").map(str::to_string);
+        if let Err(error) = config.metadata.validate() { error!("Invalid file metadata: {}", error); std::process::exit(1); }
+        if !matches.is_present("no_file_attributes") {
+            if let Ok(metadata) = std::fs::metadata(input) { (config.metadata.mode, config.metadata.mtime) = archive::mode_and_mtime(&metadata); }
+        }
+        let encoder = if input.is_dir() {
+            // A path like "." has no name of its own, so the directory's real name is sent.
+            let name = input.canonicalize().ok().and_then(|path| path.file_name().map(|name| name.to_string_lossy().to_string())).unwrap_or_else(|| "archive".to_string());
+            let (entries, data) = match archive::pack(input, config.hash_algorithm) {
+                Ok(archive) => archive,
+                Err(error) => { error!("Cannot read this directory: {}", error); std::process::exit(1); },
+            };
+            info!("Encoding {} files and directories, {} bytes in all", entries.len(), data.len());
+            sequence::SequenceEncoder::new_archive(&data, &entries, name, config)
+        } else {
+            let data = std::fs::read(filename).expect("could not read input file");
+            let name = input.file_name().expect("input path has no file name").to_string_lossy().to_string();
+            sequence::SequenceEncoder::new(data, name, config)
+        };
+        let encoder = match encoder {
+            Ok(encoder) => encoder,
+            Err(error) => { error!("Cannot encode this file: {}", error); std::process::exit(1); },
+        };
+        let layout = layout.expect("layout invalid despite validation");
+        info!("Encoding {} content symbols of {} bytes each into {} symbols on {} frames", encoder.data_chunks_count(), encoder.chunk_size(), encoder.total_len(), encoder.frames_count());
+        std::fs::create_dir_all(out_dir).expect("could not create output directory");
+        let mut frame_id = 0;
+        let mut last_frame: Option<(Vec<symbol::Symbol>, image::RgbImage)> = None;
+        for symbols in encoder.into_frames() {
+            let img = match last_frame {
+                Some((ref last_symbols, ref img)) if *last_symbols == symbols => img.clone(), // persisting frames are not rendered again
+                _ => {
+                    let codes: Vec<_> = symbols.iter().map(|symb| qr_writer::symbol_to_qrcode_with_version(symb, version, ec_level, 1).expect("symbol did not fit in QR code despite validation")).collect();
+                    let planes: Vec<&[image::RgbImage]> = codes.chunks(symbols_per_plane).collect();
+                    if multiplex { layout.compose_planes(&planes) } else { layout.compose(&codes) }.expect("symbols did not fit on frame despite validation")
+                },
+            };
+            img.save(out_dir.join(format!("{}.png", frame_id))).expect("could not write frame image");
+            frame_id += 1;
+            last_frame = Some((symbols, img));
+        }
+        info!("Wrote {} frames to {}", frame_id, out_dir.display());
+    } else if let Some(matches) = matches.subcommand_matches("decode") {
+        let input = matches.value_of("input").expect("input required");
+        let out_dir = Path::new(matches.value_of("output").unwrap_or("."));
+        let mut config = pipeline::PipelineConfig::new();
+        config.reader = reader_config(matches);
+        config.fusion = matches.value_of("fuse").map(|method| fusion::FusionConfig::new(match method {
+            "median" => fusion::FusionMethod::Median,
+            _ => fusion::FusionMethod::Mean,
+        }));
+        config.skip_duplicates = !matches.is_present("keep_duplicates");
+        if let Some(count) = matches.value_of("full_scan_every") { config.full_scan_every = count.parse().expect("full scan interval must be an integer"); }
+        if let Some(jobs) = matches.value_of("jobs") {
+            config.jobs = jobs.parse().expect("number of jobs must be an integer");
+            config.queue_len = 2 * config.jobs;
+        }
+        if let Some(size) = matches.value_of("max_file_size") { config.limits.max_file_size = size.parse().expect("maximum file size must be an integer"); }
+        if let Some(count) = matches.value_of("max_chunks") { config.limits.max_chunks = count.parse().expect("maximum number of chunks must be an integer"); }
+        if let Some(size) = matches.value_of("max_chunk_size") { config.limits.max_chunk_size = size.parse().expect("maximum chunk size must be an integer"); }
+        if let Some(count) = matches.value_of("max_sequences") { config.limits.max_sequences = count.parse().expect("maximum number of sequences must be an integer"); }
+        if let Some(memory) = matches.value_of("max_kdf_memory") { config.limits.max_kdf_memory = memory.parse().expect("maximum key derivation memory must be an integer"); }
+        if let Some(passes) = matches.value_of("max_kdf_passes") { config.limits.max_kdf_passes = passes.parse().expect("maximum key derivation passes must be an integer"); }
+        if let Some(lanes) = matches.value_of("max_kdf_lanes") { config.limits.max_kdf_lanes = lanes.parse().expect("maximum key derivation lanes must be an integer"); }
+
+        let output = pipeline::read_sequences(video::frames_from(input), &config);
+        if !output.complete { warn!("The input ended before every sequence was complete"); }
+        info!("Read {} frames, skipping {} duplicates: {}", output.frames, output.skipped, output.summary);
+        debug!("Searched the whole frame {} times", output.full_scans);
+
+        let mut decoders = output.decoders;
+        if decoders.is_empty() { error!("No sequences were found in the input"); std::process::exit(1); }
+        let output_file = matches.value_of("output_file").map(Path::new);
+        if output_file.is_some() && decoders.len() > 1 { error!("The input holds {} files, so --output cannot name the one to write; use -o to give a directory", decoders.len()); std::process::exit(1); }
+        let force = matches.is_present("force");
+        let mut identities = Vec::new();
+        for path in matches.values_of("identity").into_iter().flatten() {
+            let parsed = std::fs::read_to_string(path).map_err(|error| error.to_string())
+                .and_then(|contents| encryption::Identity::parse_file(&contents).map_err(|error| error.to_string()));
+            match parsed {
+                Ok(parsed) => identities.extend(parsed),
+                Err(error) => { error!("Could not read the identities in {:?}: {}", path, error); std::process::exit(1); },
+            }
+        }
+        let mut trusted = Vec::new();
+        for path in matches.values_of("trusted_keys").into_iter().flatten() {
+            let parsed = std::fs::read_to_string(path).map_err(|error| error.to_string())
+                .and_then(|contents| signature::VerifyingKey::parse_file(&contents).map_err(|error| error.to_string()));
+            match parsed {
+                Ok(parsed) => trusted.extend(parsed),
+                Err(error) => { error!("Could not read the trusted keys in {:?}: {}", path, error); std::process::exit(1); },
+            }
+        }
+        for key in matches.values_of("trust").into_iter().flatten() {
+            match key.parse() {
+                Ok(key) => trusted.push(key),
+                Err(error) => { error!("Invalid trusted key: {}", error); std::process::exit(1); },
+            }
+        }
+        let require_signature = matches.is_present("require_signature");
+        if require_signature && trusted.is_empty() { error!("--require-signature needs a trusted key, given with --trusted-keys or --trust"); std::process::exit(1); }
+        // The same passphrase is used for every encrypted file, and only asked for once.
+        let mut known_passphrase = None;
+        for decoder in decoders.iter_mut() {
+            let signature = decoder.signature_status(&trusted);
+            match signature {
+                signature::SignatureStatus::Trusted(key) => info!("{:?} is signed by the trusted key {}", decoder.file_name(), key),
+                signature::SignatureStatus::Untrusted(key) => warn!("{:?} is signed by {}, which is not a trusted key", decoder.file_name(), key),
+                signature::SignatureStatus::Unsigned if trusted.is_empty() => info!("{:?} is not signed", decoder.file_name()),
+                signature::SignatureStatus::Unsigned => warn!("{:?} is not signed", decoder.file_name()),
+                signature::SignatureStatus::Invalid => warn!("{:?} has a signature that does not match it, which anyone could have added, so it is treated as not signed", decoder.file_name()),
+            }
+            if require_signature && !matches!(signature, signature::SignatureStatus::Trusted(_)) { error!("{:?} is not signed by a trusted key, so it is not written", decoder.file_name()); continue; }
+            // The comment comes from the video, so it is quoted rather than printed as it is.
+            if let Some(mime) = &decoder.metadata().mime { info!("{:?} is of type {}", decoder.file_name(), mime); }
+            if let Some(comment) = &decoder.metadata().comment { info!("{:?} has the comment {:?}", decoder.file_name(), comment); }
+            let unlocked = match decoder.encryption().map(|encryption| encryption.uses_passphrase()) {
+                None => Ok(()),
+                Some(true) => decoder.unlock(known_passphrase.get_or_insert_with(|| passphrase(matches, false))),
+                Some(false) if identities.is_empty() => { error!("{:?} is encrypted to recipients; give an identity to decrypt it with --identity", decoder.file_name()); continue; },
+                Some(false) => decoder.unlock_with_identities(&identities),
+            };
+            if let Err(error) = unlocked { error!("Could not decrypt {:?}: {}", decoder.file_name(), error); continue; }
+            // The name comes from the video, so it must not be able to point outside the output directory.
+            let path = match output_file {
+                Some(path) => path.to_path_buf(),
+                None => match output::safe_path(decoder.file_name()) {
+                    Ok(name) => match output::find_symlink(out_dir, &name) {
+                        Some(link) => { error!("Not writing {:?}, because {} is a symbolic link, which could lead outside the output directory", decoder.file_name(), link.display()); continue; },
+                        None => out_dir.join(name),
+                    },
+                    Err(error) => { error!("Not writing {:?}, because its name is unsafe: {}; use --output to choose a name", decoder.file_name(), error); continue; },
+                },
+            };
+            let manifest = match decoder.collect_manifest() {
+                Ok(manifest) => manifest,
+                Err(error) => { error!("Could not read the list of files in {:?}: {}", decoder.file_name(), error); continue; },
+            };
+            match (decoder.collect_data(), manifest) {
+                (Ok(data), Some(entries)) => match archive::unpack(&path, &entries, &data, decoder.hash_algorithm(), force) {
+                    Ok(()) => {
+                        output::restore_metadata(&path, true, decoder.metadata().mode, decoder.metadata().mtime);
+                        info!("Wrote {} files and directories under {}", entries.len(), path.display());
+                    },
+                    Err(archive::ArchiveError::Write(path, error)) if error.kind() == std::io::ErrorKind::AlreadyExists => error!("Not unpacking {:?}, because {} already exists; use --force to replace it", decoder.file_name(), path.display()),
+                    Err(error) => error!("Could not unpack {:?}: {}", decoder.file_name(), error),
+                },
+                (Ok(data), None) => match output::write_file(&path, &data, force) {
+                    Ok(()) => {
+                        output::restore_metadata(&path, false, decoder.metadata().mode, decoder.metadata().mtime);
+                        info!("Wrote {}", path.display());
+                    },
+                    Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => error!("Not writing {}, because it already exists; use --force to replace it", path.display()),
+                    Err(error) => error!("Could not write {}: {}", path.display(), error),
+                },
+                (Err(error), _) => error!("Could not decode {:?}: {}", decoder.file_name(), error),
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("keygen") {
+        let path = matches.value_of("output").expect("output file required");
+        // Both kinds of key are written the same way: a comment with the public key, then the private key.
+        let (public, private) = if matches.is_present("signing") {
+            let key = signature::SigningKey::generate();
+            (key.to_public().to_string(), key.to_string())
+        } else {
+            let identity = encryption::Identity::generate();
+            (identity.to_public().to_string(), identity.to_string())
+        };
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        // Only the owner may read the private key.
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let written = options.open(path).and_then(|mut file| writeln!(file, "# public key: {}\n{}", public, private));
+        if let Err(error) = written { error!("Could not write the key to {:?}: {}", path, error); std::process::exit(1); }
+        info!("Wrote the key to {}; its public key is {}", path, public);
     } else {println!("Subcommand required");}
-    
+
+}
+
+/// Build the symbol reader configuration from the options shared by the subcommands that read images.
+fn reader_config(matches: &clap::ArgMatches) -> qr_reader::ReaderConfig {
+    let mut config = qr_reader::ReaderConfig::new();
+    config.channel = match matches.value_of("channel") {
+        Some("red") => qr_reader::Channel::Red,
+        Some("green") => qr_reader::Channel::Green,
+        Some("blue") => qr_reader::Channel::Blue,
+        _ => qr_reader::Channel::Luma,
+    };
+    config.separate_channels = matches.is_present("channels");
+    config.second_chance = !matches.is_present("no_second_chance");
+    config.correct_geometry = !matches.is_present("no_geometry_correction");
+    for choice in matches.values_of("detector").into_iter().flatten() {
+        let (channel, name) = match choice.split_once('=') {
+            Some((channel, name)) => (Some(channel), name),
+            None => (None, choice),
+        };
+        let chosen = match detector::by_name(name) {
+            Some(chosen) => chosen,
+            None => { error!("Unknown QR code detector {:?}, expected one of {}", name, detector::names().join(", ")); std::process::exit(1); },
+        };
+        match channel {
+            None => config.detector = chosen,
+            Some(channel) => {
+                let channel = match channel {
+                    "luma" => qr_reader::Channel::Luma,
+                    "red" => qr_reader::Channel::Red,
+                    "green" => qr_reader::Channel::Green,
+                    "blue" => qr_reader::Channel::Blue,
+                    _ => { error!("Unknown channel {:?} for detector {}, expected luma, red, green or blue", channel, name); std::process::exit(1); },
+                };
+                config.channel_detectors.insert(channel, chosen);
+            },
+        }
+    }
+    if let Some(steps) = matches.value_of("preprocess") {
+        config.preprocessing = match steps.parse() {
+            Ok(pipeline) => pipeline,
+            Err(error) => { error!("Invalid preprocessing: {}", error); std::process::exit(1); },
+        };
+    }
+    config
+}
+
+/// Read the passphrase from the file given with `--passphrase-file`, or else ask for it on the terminal, twice if it is a new one.
+fn passphrase(matches: &clap::ArgMatches, new: bool) -> String {
+    if let Some(path) = matches.value_of("passphrase_file") {
+        return match std::fs::read_to_string(path) {
+            Ok(contents) => contents.trim_end_matches(&['\r', '\n'][..]).to_string(),
+            Err(error) => { error!("Could not read the passphrase from {:?}: {}", path, error); std::process::exit(1); },
+        };
+    }
+    let ask = |prompt: &str| match rpassword::prompt_password(prompt) {
+        Ok(passphrase) => passphrase,
+        Err(error) => { error!("Could not read the passphrase: {}", error); std::process::exit(1); },
+    };
+    let passphrase = ask("Passphrase: ");
+    if new && ask("Passphrase again: ") != passphrase { error!("The passphrases do not match"); std::process::exit(1); }
+    passphrase
+}
+
+/// Parse a pair of numbers written as `AxB`, such as a resolution or grid size.
+fn parse_dimensions(value: &str) -> (u32, u32) {
+    let mut parts = value.split('x');
+    let first = parts.next().and_then(|a| a.parse().ok());
+    let second = parts.next().and_then(|b| b.parse().ok());
+    match (first, second, parts.next()) {
+        (Some(a), Some(b), None) => (a, b),
+        _ => { error!("Expected two numbers separated by 'x', like 1920x1080, but got {:?}", value); std::process::exit(1); },
+    }
 }
@@ -0,0 +1,140 @@
+use sha3::{Digest, Sha3_256};
+
+/// A hash of a chunk or of a node of the tree.
+pub type Hash = [u8; 32];
+
+/// Hash a chunk of data into a leaf of the tree.
+///
+/// Leaves and inner nodes start with different bytes, so that a node can never be passed off as a chunk.
+pub fn leaf_hash(data: &[u8]) -> Hash {
+    let mut hasher = Sha3_256::new();
+    hasher.update([0]);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+/// Hash two neighbouring nodes into their parent.
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha3_256::new();
+    hasher.update([1]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Get the level above this one, where each pair of nodes is hashed together and a node left without a partner is moved up as it is.
+fn parent_level(nodes: &[Hash]) -> Vec<Hash> {
+    nodes.chunks(2).map(|pair| match pair {
+        [left, right] => node_hash(left, right),
+        [single] => *single,
+        _ => unreachable!("chunks of 2 have 1 or 2 elements"),
+    }).collect()
+}
+
+/// Get every level of the tree over these leaves, from the leaves themselves up to the root.
+///
+/// Working these out hashes every node of the tree, so an encoder that needs many paths works them out once and passes them to `root_of_levels` and `auth_path_in_levels`.
+pub fn levels(leaves: &[Hash]) -> Vec<Vec<Hash>> {
+    let mut levels = vec![leaves.to_vec()];
+    while levels.last().expect("there is at least one level").len() > 1 {
+        let next = parent_level(levels.last().expect("there is at least one level"));
+        levels.push(next);
+    }
+    levels
+}
+
+/// Get the root of the tree over these leaves, or `None` if there are none.
+///
+/// For a block of `2^level` leaves starting at a multiple of that, or the shorter block at the end, this is the node `level` levels above them in the whole tree.
+pub fn root(leaves: &[Hash]) -> Option<Hash> {
+    root_of_levels(&levels(leaves))
+}
+
+/// Get the root of a tree from all of its levels, as `levels` gives them.
+pub fn root_of_levels(levels: &[Vec<Hash>]) -> Option<Hash> {
+    levels.last().and_then(|level| level.first()).copied()
+}
+
+/// Get the authentication path of a node, as `auth_path_in_levels` does, straight from the leaves.
+#[cfg(test)]
+pub fn auth_path(leaves: &[Hash], level: usize, index: usize) -> Vec<Hash> {
+    auth_path_in_levels(&levels(leaves), level, index)
+}
+
+/// Get the hashes needed to go from the node with this index, `level` levels above the leaves, up to the root: each of its ancestors' partners, from the bottom up.
+///
+/// The tree is given as all of its levels, as `levels` gives them.
+pub fn auth_path_in_levels(levels: &[Vec<Hash>], level: usize, index: usize) -> Vec<Hash> {
+    let mut path = Vec::new();
+    let mut index = index;
+    for nodes in levels.iter().skip(level) {
+        if nodes.len() <= 1 { break; }
+        if let Some(partner) = nodes.get(index ^ 1) { path.push(*partner); }
+        index /= 2;
+    }
+    path
+}
+
+/// Check that the node with this hash and index, `level` levels above the leaves of a tree over `leaves_count` leaves, belongs to the tree with this root, using its authentication path.
+pub fn verify(root: &Hash, leaves_count: usize, level: usize, index: usize, node: Hash, path: &[Hash]) -> bool {
+    if level >= usize::BITS as usize { return false; }
+    let mut count = leaves_count.div_ceil(1 << level);
+    if index >= count { return false; }
+    let (mut node, mut index, mut path) = (node, index, path.iter());
+    while count > 1 {
+        // The last node of a level without a partner is moved up as it is.
+        if index ^ 1 < count {
+            let partner = match path.next() { Some(partner) => partner, None => return false };
+            node = if index % 2 == 0 { node_hash(&node, partner) } else { node_hash(partner, &node) };
+        }
+        index /= 2;
+        count = count.div_ceil(2);
+    }
+    path.next().is_none() && node == *root
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::merkle::*;
+
+    fn leaves(count: usize) -> Vec<Hash> {
+        (0..count).map(|i| leaf_hash(format!("chunk {}", i).as_bytes())).collect()
+    }
+
+    #[test]
+    fn test_every_block_verifies() {
+        for count in 1..20 {
+            let leaves = leaves(count);
+            let tree_root = root(&leaves).unwrap();
+            let tree_levels = levels(&leaves);
+            assert_eq!(root_of_levels(&tree_levels), Some(tree_root));
+            for level in 0..6 {
+                let block = 1 << level;
+                for (index, block_leaves) in leaves.chunks(block).enumerate() {
+                    let node = root(block_leaves).unwrap();
+                    let path = auth_path(&leaves, level, index);
+                    assert_eq!(auth_path_in_levels(&tree_levels, level, index), path);
+                    assert!(verify(&tree_root, count, level, index, node, &path), "{} leaves, level {}, block {}", count, level, index);
+                    // The same hashes do not pass for any other block.
+                    assert!(!verify(&tree_root, count, level, index + 1, node, &path));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_tampering_is_noticed() {
+        let leaves = leaves(11);
+        let tree_root = root(&leaves).unwrap();
+        let path = auth_path(&leaves, 0, 6);
+        assert!(verify(&tree_root, 11, 0, 6, leaves[6], &path));
+        assert!(!verify(&tree_root, 11, 0, 6, leaf_hash(b"chunk 7"), &path));
+        let mut wrong_path = path.clone();
+        wrong_path[1][0] ^= 1;
+        assert!(!verify(&tree_root, 11, 0, 6, leaves[6], &wrong_path));
+        assert!(!verify(&tree_root, 11, 0, 6, leaves[6], &path[1..]));
+        assert!(!verify(&tree_root, 11, 0, 6, leaves[6], &[path.clone(), vec![leaves[0]]].concat()));
+        assert!(!verify(&tree_root, 11, 80, 0, leaves[6], &path));
+        assert_eq!(root(&[]), None);
+    }
+}
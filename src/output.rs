@@ -0,0 +1,165 @@
+use log::warn;
+use thiserror::Error;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Reasons why the file name in a meta symbol cannot be used as a path. The name comes from the video, so it may have been made to escape the output directory.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum UnsafeNameError {
+    #[error("the file name is empty")]
+    Empty,
+
+    #[error("the file name is an absolute path")]
+    Absolute,
+
+    #[error("the file name has a \".\" or \"..\" component, or an empty one")]
+    RelativeComponent,
+
+    #[error("the file name contains the control character {0:?}")]
+    ControlCharacter(char),
+
+    #[error("the file name contains {0:?}, which Windows takes as a drive or stream")]
+    Colon(char),
+
+    #[error("the file name contains {0:?}, which is a device on Windows")]
+    DeviceName(String),
+}
+
+/// Names that Windows opens as devices, whatever their extension.
+const DEVICE_NAMES: &[&str] = &["CON", "PRN", "AUX", "NUL", "CONIN$", "CONOUT$",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9"];
+
+/// Turn the file name from a meta symbol into a relative path that stays inside the output directory, on any platform.
+///
+/// Both `/` and `\` separate directories. Rather than quietly dropping the parts of a hostile name, the whole name is rejected.
+pub fn safe_path(name: &str) -> Result<PathBuf, UnsafeNameError> {
+    if name.is_empty() { return Err(UnsafeNameError::Empty); }
+    if let Some(c) = name.chars().find(|c| c.is_control()) { return Err(UnsafeNameError::ControlCharacter(c)); }
+    if name.starts_with(['/', '\\']) { return Err(UnsafeNameError::Absolute); }
+    if name.contains(':') { return Err(UnsafeNameError::Colon(':')); }
+    let mut path = PathBuf::new();
+    for component in name.split(['/', '\\']) {
+        if component.is_empty() || component == "." || component == ".." { return Err(UnsafeNameError::RelativeComponent); }
+        let stem = component.split('.').next().unwrap_or_default().trim_end();
+        if DEVICE_NAMES.iter().any(|device| device.eq_ignore_ascii_case(stem)) { return Err(UnsafeNameError::DeviceName(component.to_string())); }
+        path.push(component);
+    }
+    Ok(path)
+}
+
+/// Find a symbolic link at this relative path under the root, or in a directory on the way to it, which could lead a file outside the root.
+pub fn find_symlink(root: &Path, path: &Path) -> Option<PathBuf> {
+    let mut current = root.to_path_buf();
+    for component in path.components() {
+        current.push(component);
+        if current.symlink_metadata().map(|metadata| metadata.file_type().is_symlink()).unwrap_or(false) { return Some(current); }
+    }
+    None
+}
+
+/// Write a decoded file, creating the directories it is in. An existing file is only replaced if `overwrite` is set, and never through a symbolic link.
+///
+/// The directories on the way are not checked for links: use `find_symlink` first for paths that come from the video.
+pub fn write_file(path: &Path, data: &[u8], overwrite: bool) -> io::Result<()> {
+    if let Some(parent) = path.parent() { fs::create_dir_all(parent)?; }
+    let mut options = fs::OpenOptions::new();
+    options.write(true);
+    if overwrite { options.create(true).truncate(true); } else { options.create_new(true); }
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::custom_flags(&mut options, libc::O_NOFOLLOW);
+    options.open(path)?.write_all(data)
+}
+
+/// Restore the modification time and mode of a written file or directory, warning rather than failing if the platform does not allow it.
+///
+/// Only the permission bits of the mode are restored: a file from a video must not become setuid.
+pub fn restore_metadata(path: &Path, is_dir: bool, mode: Option<u32>, mtime: Option<u64>) {
+    if let Some(mtime) = mtime {
+        let file = if is_dir { fs::File::open(path) } else { fs::OpenOptions::new().write(true).open(path) };
+        // The time comes from the video, and may be too far in the future for the platform to hold.
+        match UNIX_EPOCH.checked_add(Duration::from_secs(mtime)) {
+            Some(time) => if let Err(error) = file.and_then(|file| file.set_modified(time)) { warn!("Could not restore the modification time of {}: {}", path.display(), error); },
+            None => warn!("Not restoring the modification time of {}, because {} seconds after 1970 cannot be represented", path.display(), mtime),
+        }
+    }
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        let permissions = std::os::unix::fs::PermissionsExt::from_mode(mode & 0o777);
+        if let Err(error) = fs::set_permissions(path, permissions) { warn!("Could not restore the mode of {}: {}", path.display(), error); }
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::output::*;
+
+    #[test]
+    fn test_plain_names_are_kept() {
+        assert_eq!(safe_path("report.pdf"), Ok(PathBuf::from("report.pdf")));
+        assert_eq!(safe_path("photos/cat.jpg"), Ok(Path::new("photos").join("cat.jpg")));
+        assert_eq!(safe_path("photos\\cat.jpg"), Ok(Path::new("photos").join("cat.jpg")));
+        assert_eq!(safe_path("..hidden"), Ok(PathBuf::from("..hidden")));
+        assert_eq!(safe_path("console.log"), Ok(PathBuf::from("console.log")));
+    }
+
+    #[test]
+    fn test_hostile_names_are_rejected() {
+        assert_eq!(safe_path(""), Err(UnsafeNameError::Empty));
+        assert_eq!(safe_path("/etc/passwd"), Err(UnsafeNameError::Absolute));
+        assert_eq!(safe_path("\\\\server\\share\\file"), Err(UnsafeNameError::Absolute));
+        assert_eq!(safe_path("C:\\Windows\\win.ini"), Err(UnsafeNameError::Colon(':')));
+        assert_eq!(safe_path("notes.txt:hidden"), Err(UnsafeNameError::Colon(':')));
+        assert_eq!(safe_path("../../.bashrc"), Err(UnsafeNameError::RelativeComponent));
+        assert_eq!(safe_path("photos/../../.ssh/authorized_keys"), Err(UnsafeNameError::RelativeComponent));
+        assert_eq!(safe_path("photos\\..\\..\\x"), Err(UnsafeNameError::RelativeComponent));
+        assert_eq!(safe_path("."), Err(UnsafeNameError::RelativeComponent));
+        assert_eq!(safe_path("photos//cat.jpg"), Err(UnsafeNameError::RelativeComponent));
+        assert_eq!(safe_path("photos/"), Err(UnsafeNameError::RelativeComponent));
+        assert_eq!(safe_path("report\n.pdf"), Err(UnsafeNameError::ControlCharacter('\n')));
+        assert_eq!(safe_path("evil\u{1b}[2J.txt"), Err(UnsafeNameError::ControlCharacter('\u{1b}')));
+        assert_eq!(safe_path("nul"), Err(UnsafeNameError::DeviceName("nul".to_string())));
+        assert_eq!(safe_path("logs/Com1.txt"), Err(UnsafeNameError::DeviceName("Com1.txt".to_string())));
+        assert_eq!(safe_path("AUX .tar.gz"), Err(UnsafeNameError::DeviceName("AUX .tar.gz".to_string())));
+    }
+
+    #[test]
+    fn test_existing_files_are_only_replaced_when_asked() {
+        let dir = std::env::temp_dir().join(format!("datablast-output-test-{}", std::process::id()));
+        let path = dir.join("nested").join("file.txt");
+        write_file(&path, b"first", false).unwrap();
+        assert_eq!(write_file(&path, b"second", false).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&path).unwrap(), b"first");
+        write_file(&path, b"2nd", true).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"2nd");
+        // A time that does not fit is left alone rather than crashing the decoder.
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        restore_metadata(&path, false, None, Some(u64::MAX));
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), modified);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks_are_not_written_through() {
+        let dir = std::env::temp_dir().join(format!("datablast-output-symlink-test-{}", std::process::id()));
+        let outside = dir.join("outside");
+        let out = dir.join("out");
+        fs::create_dir_all(&outside).unwrap();
+        fs::create_dir_all(&out).unwrap();
+        std::os::unix::fs::symlink(&outside, out.join("photos")).unwrap();
+        assert_eq!(find_symlink(&out, Path::new("photos/cat.jpg")), Some(out.join("photos")));
+        assert_eq!(find_symlink(&out, Path::new("videos/cat.mp4")), None);
+        // A link as the file itself is not followed, even when replacing files.
+        fs::write(outside.join("target.txt"), b"keep me").unwrap();
+        std::os::unix::fs::symlink(outside.join("target.txt"), out.join("file.txt")).unwrap();
+        assert_eq!(find_symlink(&out, Path::new("file.txt")), Some(out.join("file.txt")));
+        assert!(write_file(&out.join("file.txt"), b"overwritten", true).is_err());
+        assert_eq!(fs::read(outside.join("target.txt")).unwrap(), b"keep me");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
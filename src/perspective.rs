@@ -0,0 +1,116 @@
+use image::{GrayImage, Luma};
+
+/// A projective transformation of the plane, such as the one from a flat screen to a photo of it taken at an angle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Homography {
+    /// The transformation as a 3x3 matrix, row by row, with the last entry fixed at 1.
+    matrix: [f64; 9],
+}
+
+impl Homography {
+    /// Find the transformation that takes each of these four points to the matching one of those four.
+    ///
+    /// There is none if three of the points are on one line.
+    pub fn from_points(from: &[(f64, f64); 4], to: &[(f64, f64); 4]) -> Option<Self> {
+        // Each pair of points gives two linear equations in the eight unknown entries of the matrix.
+        let mut system = [[0.0; 9]; 8];
+        for (i, (&(x, y), &(u, v))) in from.iter().zip(to.iter()).enumerate() {
+            system[2 * i] = [x, y, 1.0, 0.0, 0.0, 0.0, -u * x, -u * y, u];
+            system[2 * i + 1] = [0.0, 0.0, 0.0, x, y, 1.0, -v * x, -v * y, v];
+        }
+        let solution = solve(system)?;
+        let mut matrix = [1.0; 9];
+        matrix[..8].copy_from_slice(&solution);
+        Some(Homography { matrix })
+    }
+
+    /// Transform a point.
+    pub fn apply(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        let m = &self.matrix;
+        let w = m[6] * x + m[7] * y + m[8];
+        ((m[0] * x + m[1] * y + m[2]) / w, (m[3] * x + m[4] * y + m[5]) / w)
+    }
+}
+
+/// Solve a system of 8 linear equations, each given as its 8 coefficients followed by its right-hand side, by Gaussian elimination.
+fn solve(mut system: [[f64; 9]; 8]) -> Option<[f64; 8]> {
+    for column in 0..8 {
+        let pivot = (column..8).max_by(|&a, &b| system[a][column].abs().total_cmp(&system[b][column].abs()))?;
+        if system[pivot][column].abs() < 1e-12 { return None; }
+        system.swap(column, pivot);
+        let pivot_row = system[column];
+        for (row, equation) in system.iter_mut().enumerate() {
+            if row == column { continue; }
+            let factor = equation[column] / pivot_row[column];
+            for (value, pivot_value) in equation[column..].iter_mut().zip(pivot_row[column..].iter()) { *value -= factor * pivot_value; }
+        }
+    }
+    let mut solution = [0.0; 8];
+    for (i, value) in solution.iter_mut().enumerate() { *value = system[i][8] / system[i][i]; }
+    Some(solution)
+}
+
+/// Get the brightness at a point between pixels by blending the four pixels around it, treating everything outside the image as white.
+fn sample(img: &GrayImage, (x, y): (f64, f64)) -> u8 {
+    let (x, y) = (x - 0.5, y - 0.5);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let pixel = |px: f64, py: f64| {
+        if px < 0.0 || py < 0.0 || px >= img.width() as f64 || py >= img.height() as f64 { 255.0 }
+        else { img.get_pixel(px as u32, py as u32)[0] as f64 }
+    };
+    let top = pixel(x0, y0) * (1.0 - fx) + pixel(x0 + 1.0, y0) * fx;
+    let bottom = pixel(x0, y0 + 1.0) * (1.0 - fx) + pixel(x0 + 1.0, y0 + 1.0) * fx;
+    (top * (1.0 - fy) + bottom * fy).round() as u8
+}
+
+/// Draw the image as it would look if the quadrilateral with these corners were a square `size` pixels wide, facing the camera,
+/// along with a border `margin` pixels wide of what surrounds it.
+///
+/// The corners are given in the order top left, top right, bottom right, bottom left of the square.
+pub fn rectify(img: &GrayImage, corners: &[(f64, f64); 4], size: u32, margin: u32) -> Option<GrayImage> {
+    let (low, high) = (margin as f64, (margin + size) as f64);
+    let square = [(low, low), (high, low), (high, high), (low, high)];
+    let to_image = Homography::from_points(&square, corners)?;
+    let side = size + 2 * margin;
+    Some(GrayImage::from_fn(side, side, |x, y| Luma([sample(img, to_image.apply((x as f64 + 0.5, y as f64 + 0.5)))])))
+}
+
+/// Draw the image as seen through a camera that maps its corners to these points, on a white background of this size.
+///
+/// This is the reverse of `rectify`, and is used by tests to imitate photos of a screen.
+#[cfg(test)]
+pub fn project(img: &GrayImage, corners: &[(f64, f64); 4], width: u32, height: u32) -> Option<GrayImage> {
+    let (w, h) = (img.width() as f64, img.height() as f64);
+    let to_image = Homography::from_points(corners, &[(0.0, 0.0), (w, 0.0), (w, h), (0.0, h)])?;
+    Some(GrayImage::from_fn(width, height, |x, y| Luma([sample(img, to_image.apply((x as f64 + 0.5, y as f64 + 0.5)))])))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::perspective::*;
+
+    #[test]
+    fn test_homography_maps_corners() {
+        let from = [(0.0, 0.0), (10.0, 0.0), (10.0, 10.0), (0.0, 10.0)];
+        let to = [(3.0, 1.0), (20.0, 4.0), (18.0, 30.0), (1.0, 15.0)];
+        let homography = Homography::from_points(&from, &to).unwrap();
+        for (a, b) in from.iter().zip(to.iter()) {
+            let (x, y) = homography.apply(*a);
+            assert!((x - b.0).abs() < 1e-9 && (y - b.1).abs() < 1e-9);
+        }
+        let collinear = [(0.0, 0.0), (1.0, 1.0), (2.0, 2.0), (0.0, 5.0)];
+        assert_eq!(Homography::from_points(&collinear, &to), None);
+    }
+
+    #[test]
+    fn test_rectify_undoes_project() {
+        let img = GrayImage::from_fn(40, 40, |x, y| Luma([if (x / 10 + y / 10) % 2 == 0 { 0 } else { 255 }]));
+        let corners = [(30.0, 20.0), (150.0, 40.0), (140.0, 110.0), (20.0, 140.0)];
+        let photo = project(&img, &corners, 180, 160).unwrap();
+        let straight = rectify(&photo, &corners, 40, 0).unwrap();
+        let wrong = img.pixels().zip(straight.pixels()).filter(|(a, b)| (a[0] as i32 - b[0] as i32).abs() > 128).count();
+        // Only pixels on the edges between squares may come out differently.
+        assert!(wrong < 40, "{} pixels differ", wrong);
+    }
+}
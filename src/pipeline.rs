@@ -0,0 +1,234 @@
+use image::RgbImage;
+use log::debug;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::sync_channel;
+use std::thread;
+
+use crate::fusion::{DuplicateFilter, FrameFuser, FusionConfig};
+use crate::qr_reader::{FrameSummary, ReaderConfig};
+use crate::sequence::{DecoderLimits, SequenceCollector, SequenceDecoder};
+use crate::tracking::RegionTracker;
+
+/// Configuration for reading the sequences from a stream of frames.
+#[derive(Debug, Clone)]
+pub struct PipelineConfig {
+    /// How symbols are read from each frame.
+    pub reader: ReaderConfig,
+
+    /// If this is set, consecutive frames showing the same symbols are fused before they are read.
+    pub fusion: Option<FusionConfig>,
+
+    /// Search whole frames for symbols only once every this many frames, as described for `RegionTracker`.
+    pub full_scan_every: usize,
+
+    /// If this is set, frames that are the same as the one before them are not read again.
+    pub skip_duplicates: bool,
+
+    /// The number of threads reading symbols from frames.
+    pub jobs: usize,
+
+    /// At most this many frames wait to be read at once, which bounds the memory used.
+    pub queue_len: usize,
+
+    /// Sequences that claim more than these limits are not decoded.
+    pub limits: DecoderLimits,
+}
+
+impl PipelineConfig {
+    pub fn new() -> Self { Default::default() }
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        let jobs = thread::available_parallelism().map(|jobs| jobs.get()).unwrap_or(1);
+        PipelineConfig {
+            reader: ReaderConfig::new(),
+            fusion: None,
+            full_scan_every: 30,
+            skip_duplicates: true,
+            jobs,
+            queue_len: 2 * jobs,
+            limits: DecoderLimits::default(),
+        }
+    }
+}
+
+/// What came of reading a stream of frames.
+pub struct PipelineOutput {
+    /// A decoder for each sequence that was found, in order of sequence ID.
+    pub decoders: Vec<SequenceDecoder>,
+    /// Whether every sequence was complete before the frames ran out.
+    pub complete: bool,
+    /// The number of frames that were read, counting each fused frame once.
+    pub frames: usize,
+    /// The number of frames that were not read because they were the same as the one before them.
+    pub skipped: usize,
+    /// How many grids were found in all those frames, and how many held symbols.
+    pub summary: FrameSummary,
+    /// The number of frames that were searched in full, which may include a few frames after the last one that was needed.
+    pub full_scans: usize,
+}
+
+/// Read the sequences from these frames, stopping as soon as every sequence that was found is complete.
+///
+/// One thread takes the frames from the source, fuses them and drops duplicates, `jobs` threads read symbols from them, and this thread collects the symbols.
+/// The symbols are collected in the order of the frames they were read from, so the result does not depend on the number of threads.
+pub fn read_sequences<F>(frames: F, config: &PipelineConfig) -> PipelineOutput where F: Iterator<Item = RgbImage> + Send {
+    let tracker = RegionTracker::new(config.full_scan_every);
+    let queue_len = config.queue_len.max(1);
+    // Each frame is sent along with its index and the number of duplicates skipped right before it.
+    let (frame_sender, frame_receiver) = sync_channel::<(usize, usize, RgbImage)>(queue_len);
+    let (result_sender, result_receiver) = sync_channel(queue_len);
+    // Shared by the readers, and dropped with the last of them, so that the source stops as soon as they do.
+    let frame_receiver = Arc::new(Mutex::new(frame_receiver));
+
+    let mut collector = SequenceCollector::new(config.limits);
+    let mut summary = FrameSummary::default();
+    let mut frames_read = 0;
+    let mut skipped = 0;
+    let mut complete = false;
+    thread::scope(|scope| {
+        let (fusion, skip_duplicates) = (config.fusion.clone(), config.skip_duplicates);
+        let source = scope.spawn(move || {
+            let mut fuser = fusion.map(FrameFuser::new);
+            let mut duplicates = DuplicateFilter::new();
+            let (mut index, mut skipped_before) = (0, 0);
+            let mut send = |frame: RgbImage| {
+                // A duplicate holds the same symbols as the frame before it, which are already being read.
+                if skip_duplicates && duplicates.is_duplicate(&frame) { skipped_before += 1; return true; }
+                let sent = frame_sender.send((index, skipped_before, frame)).is_ok();
+                index += 1;
+                skipped_before = 0;
+                sent
+            };
+            for frame in frames {
+                let ready = match fuser.as_mut() {
+                    Some(fuser) => fuser.push(frame).map(|(fused, count)| { debug!("Fused {} frames", count); fused }),
+                    None => Some(frame),
+                };
+                if let Some(frame) = ready {
+                    // The readers have all stopped, so there is no point in taking more frames from the source.
+                    if !send(frame) { return 0; }
+                }
+            }
+            if let Some((fused, _)) = fuser.as_mut().and_then(FrameFuser::finish) { send(fused); }
+            // The duplicates after the last frame that was sent.
+            skipped_before
+        });
+
+        for _ in 0..config.jobs.max(1) {
+            let (frame_receiver, result_sender, tracker) = (frame_receiver.clone(), result_sender.clone(), &tracker);
+            scope.spawn(move || loop {
+                let next = frame_receiver.lock().unwrap().recv();
+                let (index, skipped_before, frame) = match next {
+                    Ok(next) => next,
+                    Err(_) => break,
+                };
+                let detections = tracker.detect(index, &frame, &config.reader);
+                // The collector has stopped, so the remaining frames are not needed.
+                if result_sender.send((index, skipped_before, detections)).is_err() { break; }
+            });
+        }
+        drop((frame_receiver, result_sender));
+
+        let mut waiting = BTreeMap::new();
+        'results: for (index, skipped_before, detections) in result_receiver.iter() {
+            waiting.insert(index, (skipped_before, detections));
+            while let Some((skipped_before, detections)) = waiting.remove(&frames_read) {
+                skipped += skipped_before;
+                summary += FrameSummary::of(&detections);
+                for detection in detections {
+                    if let Ok(symb) = detection.symbol { collector.insert(symb); }
+                }
+                frames_read += 1;
+                tracker.forget_before(frames_read);
+                if collector.is_complete() { complete = true; break 'results; }
+            }
+        }
+        // Stop the readers, and with them the source.
+        drop(result_receiver);
+        let trailing = source.join().expect("the thread taking frames from the source panicked");
+        // Unless the source was read to the end, those duplicates were not needed.
+        if !complete { skipped += trailing; }
+    });
+
+    PipelineOutput { decoders: collector.into_decoders(), complete, frames: frames_read, skipped, summary, full_scans: tracker.full_scans() }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pipeline::*;
+    use crate::qr_writer::{symbol_to_qrcode_with_version, Version, EcLevel};
+    use crate::sequence::{SequenceEncoder, SequenceEncoderConfig};
+
+    /// Encode this data as frames, the way the `encode` command does.
+    fn encoded_frames(data: &[u8], symbols_per_frame: usize, persist: usize) -> Vec<RgbImage> {
+        let mut config = SequenceEncoderConfig::new();
+        config.persist_each_symbol_for_frames = persist;
+        config.qr_version = Version::Normal(8);
+        config.ecc_level = EcLevel::L;
+        config.data_symbols_between_meta_symbols = 5;
+        config.symbols_per_frame = symbols_per_frame;
+        config.frame_width = 640;
+        config.frame_height = 360;
+        let layout = config.frame_layout().unwrap();
+        let encoder = SequenceEncoder::new(data, "piped.bin".to_string(), config).unwrap();
+        encoder.into_frames().map(|symbols| {
+            let codes: Vec<_> = symbols.iter().map(|symb| symbol_to_qrcode_with_version(symb, Version::Normal(8), EcLevel::L, 1).unwrap()).collect();
+            layout.compose(&codes).unwrap()
+        }).collect()
+    }
+
+    #[test]
+    fn test_result_does_not_depend_on_jobs() {
+        let data: Vec<u8> = (0..2000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let frames = encoded_frames(&data, 2, 1);
+        let mut results = Vec::new();
+        for &jobs in [1, 2, 5].iter() {
+            let mut config = PipelineConfig::new();
+            config.jobs = jobs;
+            config.queue_len = 3;
+            config.full_scan_every = 4;
+            let output = read_sequences(frames.clone().into_iter(), &config);
+            assert!(output.complete);
+            assert_eq!(output.decoders.len(), 1);
+            assert_eq!(output.decoders[0].collect_data().unwrap(), data);
+            results.push((output.frames, output.summary));
+        }
+        assert!(results.iter().all(|result| *result == results[0]), "results differ: {:?}", results);
+    }
+
+    #[test]
+    fn test_stops_when_complete() {
+        let data = b"stop reading as soon as this is decoded".to_vec();
+        let frames = encoded_frames(&data, 1, 1);
+        let count = frames.len();
+        let mut config = PipelineConfig::new();
+        config.jobs = 3;
+        // The source never ends, so this only returns if the pipeline stops taking frames from it.
+        let output = read_sequences(frames.into_iter().cycle(), &config);
+        assert!(output.complete);
+        assert_eq!(output.frames, count);
+        assert_eq!(output.decoders[0].collect_data().unwrap(), data);
+    }
+
+    #[test]
+    fn test_duplicate_frames_are_skipped() {
+        let data: Vec<u8> = (0..600u32).map(|i| (i * 13 % 256) as u8).collect();
+        let frames = encoded_frames(&data, 1, 3);
+        let mut config = PipelineConfig::new();
+        config.jobs = 2;
+        config.skip_duplicates = false;
+        let every_frame = read_sequences(frames.clone().into_iter(), &config);
+        assert_eq!(every_frame.skipped, 0);
+
+        config.skip_duplicates = true;
+        let output = read_sequences(frames.clone().into_iter(), &config);
+        assert_eq!(output.decoders[0].collect_data().unwrap(), data);
+        // Only the first of every three frames is read, up to the frame that completes the sequence.
+        assert_eq!(output.frames + output.skipped, every_frame.frames);
+        assert_eq!(output.frames, every_frame.frames.div_ceil(3));
+    }
+
+}
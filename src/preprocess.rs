@@ -0,0 +1,332 @@
+use image::{GrayImage, Luma, FilterType};
+use image::imageops::{resize, filter3x3};
+use std::str::FromStr;
+use thiserror::Error;
+
+/// A single change made to a greyscale image to make the QR codes in it easier to find.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Step {
+    /// Stretch the brightness so that the darkest `clip_percent` percent of pixels become black and the brightest `clip_percent` percent become white.
+    ///
+    /// This undoes the washed-out look of re-encoded video.
+    ContrastStretch { clip_percent: f32 },
+
+    /// Replace each pixel with the median of the square of pixels within `radius` of it, which is at most `MAX_MEDIAN_RADIUS` when parsed.
+    ///
+    /// This removes speckles and softens the edges of compression blocks, while keeping the edges of modules.
+    Median { radius: u32 },
+
+    /// Enlarge the image this many times, smoothly, which is at most `MAX_UPSCALE` when parsed.
+    ///
+    /// This gives the detector more pixels to work with when modules are only one or two pixels wide.
+    Upscale { factor: u32 },
+
+    /// Make each pixel black if it is darker than the average of the square of pixels within `radius` of it by more than `offset`, and white otherwise.
+    ///
+    /// Unlike a single threshold, this copes with uneven lighting across the frame.
+    AdaptiveThreshold { radius: u32, offset: i32 },
+
+    /// Make each pixel black if it is darker than the level `percent` percent of the way from the darkest to the brightest pixel in the image, and white otherwise.
+    Level { percent: u32 },
+
+    /// Make edges crisper, by subtracting the neighbouring pixels from each pixel.
+    Sharpen,
+}
+
+impl Step {
+    /// The largest radius a median filter may be given, beyond which it takes far too long and blurs away whole modules.
+    pub const MAX_MEDIAN_RADIUS: u32 = 16;
+
+    /// The most times an image may be enlarged, beyond which the enlarged frames no longer fit in memory.
+    pub const MAX_UPSCALE: u32 = 8;
+
+    /// Apply this step to an image.
+    pub fn apply(&self, img: GrayImage) -> GrayImage {
+        match *self {
+            Step::ContrastStretch { clip_percent } => contrast_stretch(img, clip_percent),
+            Step::Median { radius } => median(&img, radius),
+            Step::Upscale { factor } => {
+                if factor <= 1 { return img; }
+                resize(&img, img.width() * factor, img.height() * factor, FilterType::Triangle)
+            },
+            Step::AdaptiveThreshold { radius, offset } => adaptive_threshold(&img, radius, offset),
+            Step::Level { percent } => level(img, percent),
+            Step::Sharpen => filter3x3(&img, &[0.0, -1.0, 0.0, -1.0, 5.0, -1.0, 0.0, -1.0, 0.0]),
+        }
+    }
+
+    /// The number of times this step enlarges the image.
+    pub fn scale(&self) -> u32 {
+        match *self {
+            Step::Upscale { factor } => factor.max(1),
+            _ => 1,
+        }
+    }
+}
+
+/// Errors that may occur while parsing a description of a preprocessing pipeline.
+#[derive(Error, Debug, Eq, PartialEq)]
+pub enum PipelineParseError {
+    /// The name of the step is not one of `stretch`, `median`, `upscale`, `threshold`, `level` and `sharpen`.
+    #[error("unknown preprocessing step {0:?}; expected one of stretch, median, upscale, threshold, level and sharpen")]
+    UnknownStep(String),
+
+    /// A parameter of a step is not a number, or is out of range.
+    #[error("invalid parameter {value:?} for preprocessing step {step}")]
+    InvalidParameter { step: String, value: String },
+
+    /// A step was given more parameters than it takes.
+    #[error("too many parameters for preprocessing step {0}")]
+    TooManyParameters(String),
+}
+
+impl FromStr for Step {
+    type Err = PipelineParseError;
+
+    /// Parse a step written as its name followed by its parameters, separated by colons, such as `threshold:20:5`.
+    ///
+    /// Parameters that are left out get default values.
+    fn from_str(description: &str) -> Result<Self, Self::Err> {
+        let mut parts = description.trim().split(':');
+        let name = parts.next().unwrap_or("").to_string();
+        let params: Vec<&str> = parts.collect();
+        let invalid = |value: &str| PipelineParseError::InvalidParameter { step: name.clone(), value: value.to_string() };
+        let param = |i: usize, default: f64, min: f64| -> Result<f64, PipelineParseError> {
+            match params.get(i) {
+                None => Ok(default),
+                Some(value) => match value.parse::<f64>() {
+                    Ok(number) if number >= min => Ok(number),
+                    _ => Err(invalid(value)),
+                },
+            }
+        };
+        // Sizes and counts are parsed as integers, so that a fraction is refused rather than silently dropped.
+        let integer = |i: usize, default: i64, min: i64, max: i64| -> Result<i64, PipelineParseError> {
+            match params.get(i) {
+                None => Ok(default),
+                Some(value) => match value.parse::<i64>() {
+                    Ok(number) if number >= min && number <= max => Ok(number),
+                    _ => Err(invalid(value)),
+                },
+            }
+        };
+        let (step, max_params) = match name.as_str() {
+            "stretch" => (Step::ContrastStretch { clip_percent: param(0, 1.0, 0.0)? as f32 }, 1),
+            "median" => (Step::Median { radius: integer(0, 1, 1, Step::MAX_MEDIAN_RADIUS as i64)? as u32 }, 1),
+            "upscale" => (Step::Upscale { factor: integer(0, 2, 1, Step::MAX_UPSCALE as i64)? as u32 }, 1),
+            "threshold" => (Step::AdaptiveThreshold { radius: integer(0, 25, 1, u32::MAX as i64)? as u32, offset: integer(1, 5, -255, 255)? as i32 }, 2),
+            "level" => (Step::Level { percent: integer(0, 50, 0, i64::MAX)?.min(100) as u32 }, 1),
+            "sharpen" => (Step::Sharpen, 0),
+            _ => { return Err(PipelineParseError::UnknownStep(name)); },
+        };
+        if params.len() > max_params { return Err(PipelineParseError::TooManyParameters(name)); }
+        Ok(step)
+    }
+}
+
+/// A list of steps applied in order to every image before looking for QR codes in it.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Pipeline {
+    pub steps: Vec<Step>,
+}
+
+impl Pipeline {
+    /// Create a pipeline that leaves images unchanged.
+    pub fn new() -> Self { Default::default() }
+
+    /// Add a step to the end of this pipeline.
+    pub fn then(mut self, step: Step) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    /// Apply every step of this pipeline to an image.
+    pub fn apply(&self, img: GrayImage) -> GrayImage {
+        self.steps.iter().fold(img, |img, step| step.apply(img))
+    }
+
+    /// The number of times this pipeline enlarges the image, which is needed to map positions back onto the original image.
+    pub fn scale(&self) -> u32 {
+        self.steps.iter().map(Step::scale).product()
+    }
+}
+
+impl FromStr for Pipeline {
+    type Err = PipelineParseError;
+
+    /// Parse a pipeline written as a comma-separated list of steps, such as `stretch,median,upscale:2,threshold`.
+    fn from_str(description: &str) -> Result<Self, Self::Err> {
+        let mut pipeline = Pipeline::new();
+        for step in description.split(',').filter(|s| !s.trim().is_empty()) {
+            pipeline = pipeline.then(step.parse()?);
+        }
+        Ok(pipeline)
+    }
+}
+
+/// Stretch the brightness of the image so that it covers the whole range, ignoring this percentage of outliers at each end.
+fn contrast_stretch(mut img: GrayImage, clip_percent: f32) -> GrayImage {
+    let mut histogram = [0usize; 256];
+    for pixel in img.pixels() { histogram[pixel[0] as usize] += 1; }
+    let total = (img.width() * img.height()) as usize;
+    let clip = (total as f32 * clip_percent.clamp(0.0, 49.0) / 100.0) as usize;
+
+    let mut seen = 0;
+    let mut low = 0;
+    while low < 255 && seen + histogram[low] <= clip { seen += histogram[low]; low += 1; }
+    let mut seen = 0;
+    let mut high = 255;
+    while high > 0 && seen + histogram[high] <= clip { seen += histogram[high]; high -= 1; }
+    if high <= low { return img; }
+
+    let mut lookup = [0u8; 256];
+    for (value, entry) in lookup.iter_mut().enumerate() {
+        let stretched = (value as i32 - low as i32) * 255 / (high as i32 - low as i32);
+        *entry = stretched.clamp(0, 255) as u8;
+    }
+    for pixel in img.pixels_mut() { pixel[0] = lookup[pixel[0] as usize]; }
+    img
+}
+
+/// Make each pixel black or white depending on whether it is darker than a level this many percent of the way from the darkest to the brightest pixel.
+fn level(mut img: GrayImage, percent: u32) -> GrayImage {
+    let darkest = img.pixels().map(|p| p[0]).min().unwrap_or(0) as u32;
+    let brightest = img.pixels().map(|p| p[0]).max().unwrap_or(255) as u32;
+    let threshold = darkest + (brightest - darkest) * percent / 100;
+    for pixel in img.pixels_mut() { pixel[0] = if (pixel[0] as u32) < threshold { 0 } else { 255 }; }
+    img
+}
+
+/// Replace each pixel with the median of the square of pixels within this radius of it.
+///
+/// This keeps a histogram of the window and slides it along each row, so the cost per pixel grows with the radius, not its square.
+fn median(img: &GrayImage, radius: u32) -> GrayImage {
+    let (w, h) = img.dimensions();
+    let r = radius as i64;
+    let pixel = |x: i64, y: i64| img.get_pixel(x.clamp(0, w as i64 - 1) as u32, y.clamp(0, h as i64 - 1) as u32)[0] as usize;
+    let window = ((2 * r + 1) * (2 * r + 1)) as usize;
+    let mut out = GrayImage::new(w, h);
+    for y in 0..h as i64 {
+        let mut histogram = [0usize; 256];
+        for dy in -r..=r {
+            for dx in -r..=r { histogram[pixel(dx, y + dy)] += 1; }
+        }
+        for x in 0..w as i64 {
+            if x > 0 {
+                for dy in -r..=r {
+                    histogram[pixel(x - r - 1, y + dy)] -= 1;
+                    histogram[pixel(x + r, y + dy)] += 1;
+                }
+            }
+            let mut seen = 0;
+            let mut value = 0;
+            while seen + histogram[value] <= window / 2 { seen += histogram[value]; value += 1; }
+            out.put_pixel(x as u32, y as u32, Luma([value as u8]));
+        }
+    }
+    out
+}
+
+/// Make each pixel black or white depending on whether it is darker than the average of its surroundings.
+fn adaptive_threshold(img: &GrayImage, radius: u32, offset: i32) -> GrayImage {
+    let (w, h) = (img.width() as usize, img.height() as usize);
+    // integral[y][x] is the sum of all pixels above and to the left of (x, y), not including that row and column
+    let mut integral = vec![0u64; (w + 1) * (h + 1)];
+    for y in 0..h {
+        let mut row_sum = 0;
+        for x in 0..w {
+            row_sum += img.get_pixel(x as u32, y as u32)[0] as u64;
+            integral[(y + 1) * (w + 1) + x + 1] = integral[y * (w + 1) + x + 1] + row_sum;
+        }
+    }
+    let r = radius as usize;
+    let mut out = GrayImage::new(w as u32, h as u32);
+    for y in 0..h {
+        let (top, bottom) = (y.saturating_sub(r), (y + r + 1).min(h));
+        for x in 0..w {
+            let (left, right) = (x.saturating_sub(r), (x + r + 1).min(w));
+            let sum = integral[bottom * (w + 1) + right] + integral[top * (w + 1) + left]
+                    - integral[top * (w + 1) + right] - integral[bottom * (w + 1) + left];
+            let mean = (sum / ((bottom - top) * (right - left)) as u64) as i32;
+            let value = img.get_pixel(x as u32, y as u32)[0] as i32;
+            out.put_pixel(x as u32, y as u32, Luma([if value + offset < mean { 0 } else { 255 }]));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::preprocess::*;
+    use crate::qr_reader::strings_from_greyscale;
+    use crate::qr_writer::{string_to_qrcode_with_version, Version, EcLevel};
+
+    /// Render a QR code with little contrast, lit unevenly from one side, and blurred by downscaling, as in a poor video frame.
+    fn degraded_code(text: &str) -> GrayImage {
+        let code = string_to_qrcode_with_version(text.to_string(), Version::Normal(4), EcLevel::L, 4).unwrap();
+        let (w, h) = code.dimensions();
+        let faded = GrayImage::from_fn(w, h, |x, y| {
+            let light = 60 + 120 * x / w; // from dim on the left to bright on the right
+            Luma([(light + code.get_pixel(x, y)[0] as u32 * 40 / 255) as u8])
+        });
+        resize(&faded, w * 3 / 5, h * 3 / 5, FilterType::Triangle)
+    }
+
+    #[test]
+    fn test_parse_pipeline() {
+        let pipeline: Pipeline = "stretch, median:2,upscale,threshold:10:-3,level:40,sharpen".parse().unwrap();
+        assert_eq!(pipeline.steps, vec![
+            Step::ContrastStretch { clip_percent: 1.0 },
+            Step::Median { radius: 2 },
+            Step::Upscale { factor: 2 },
+            Step::AdaptiveThreshold { radius: 10, offset: -3 },
+            Step::Level { percent: 40 },
+            Step::Sharpen,
+        ]);
+        assert_eq!(pipeline.scale(), 2);
+        assert_eq!("".parse::<Pipeline>().unwrap(), Pipeline::new());
+        assert_eq!("blur".parse::<Pipeline>(), Err(PipelineParseError::UnknownStep("blur".to_string())));
+        assert!(matches!("median:0".parse::<Pipeline>(), Err(PipelineParseError::InvalidParameter { .. })));
+        assert_eq!("median:16,upscale:8".parse::<Pipeline>().unwrap().scale(), 8);
+        // A fraction is refused rather than rounded down.
+        for description in ["median:1.5", "upscale:2.9", "threshold:10:-3.7", "threshold:2.5", "level:40.5"].iter() {
+            let value = description.rsplit(':').next().unwrap().to_string();
+            assert!(matches!(description.parse::<Pipeline>(), Err(PipelineParseError::InvalidParameter { value: ref invalid, .. }) if *invalid == value), "{} was accepted", description);
+        }
+        assert!(matches!("median:17".parse::<Pipeline>(), Err(PipelineParseError::InvalidParameter { .. })));
+        assert!(matches!("upscale:100000".parse::<Pipeline>(), Err(PipelineParseError::InvalidParameter { .. })));
+        assert_eq!("upscale:2:3".parse::<Pipeline>(), Err(PipelineParseError::TooManyParameters("upscale".to_string())));
+        assert_eq!("sharpen:1".parse::<Pipeline>(), Err(PipelineParseError::TooManyParameters("sharpen".to_string())));
+    }
+
+    #[test]
+    fn test_steps() {
+        let gradient = GrayImage::from_fn(100, 1, |x, _| Luma([100 + x as u8 / 2]));
+        let stretched = Step::ContrastStretch { clip_percent: 0.0 }.apply(gradient);
+        assert_eq!((stretched.get_pixel(0, 0)[0], stretched.get_pixel(99, 0)[0]), (0, 255));
+
+        let mut speckled = GrayImage::from_pixel(5, 5, Luma([200]));
+        speckled.put_pixel(2, 2, Luma([0]));
+        assert!(Step::Median { radius: 1 }.apply(speckled).pixels().all(|p| p[0] == 200));
+
+        let upscaled = Step::Upscale { factor: 3 }.apply(GrayImage::new(4, 5));
+        assert_eq!(upscaled.dimensions(), (12, 15));
+
+        let mut dot = GrayImage::from_pixel(9, 9, Luma([150]));
+        dot.put_pixel(4, 4, Luma([120]));
+        let binary = Step::AdaptiveThreshold { radius: 2, offset: 5 }.apply(dot);
+        assert_eq!(binary.get_pixel(4, 4)[0], 0);
+        assert!(binary.pixels().filter(|p| p[0] == 0).count() == 1);
+
+        let levelled = Step::Level { percent: 50 }.apply(GrayImage::from_fn(4, 1, |x, _| Luma([100 + 20 * x as u8])));
+        assert_eq!(levelled.pixels().map(|p| p[0]).collect::<Vec<_>>(), vec![0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn test_pipeline_recovers_degraded_code() {
+        let img = degraded_code("recovered");
+        assert!(strings_from_greyscale(img.clone()).is_empty());
+        let pipeline: Pipeline = "stretch,upscale:3,threshold:15".parse().unwrap();
+        assert_eq!(strings_from_greyscale(pipeline.apply(img)), vec!["recovered"]);
+    }
+}
@@ -1,47 +1,385 @@
-use image::RgbImage;
-use rqrr;
-use log::{error, warn, info, debug, trace};
+use image::{ColorType, GenericImageView, GrayImage, Pixel, Rgb};
+use image::imageops::{flip_horizontal, grayscale};
+use log::{warn, info, debug};
+use thiserror::Error;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::AddAssign;
+use std::sync::Arc;
 
-use crate::symbol::{Symbol, symbol_from_string};
+use crate::symbol::{Symbol, SymbolDecodeError, symbol_from_string};
+use crate::preprocess::{Pipeline, Step};
+use crate::qr_writer::EcLevel;
+use crate::perspective::rectify;
+use crate::detector::{CodeInfo, DecodeError, Grid, SymbolDetector, default_detector};
+
+/// Which part of each pixel's colour is used as its brightness when looking for QR codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Channel {
+    /// The perceived brightness, with the ITU-R BT.709 weights used by HD video.
+    #[default]
+    Luma,
+    /// Only the red channel.
+    Red,
+    /// Only the green channel.
+    Green,
+    /// Only the blue channel.
+    Blue,
+}
+
+impl Channel {
+    /// Get the brightness of a pixel, given as red, green, blue and alpha.
+    ///
+    /// Transparent pixels are treated as if they were drawn over a white background.
+    fn brightness(self, rgba: [u8; 4]) -> u8 {
+        let [r, g, b, a] = rgba;
+        let value = match self {
+            Channel::Luma => Rgb([r, g, b]).to_luma()[0] as u32,
+            Channel::Red => r as u32,
+            Channel::Green => g as u32,
+            Channel::Blue => b as u32,
+        };
+        ((value * a as u32 + 255 * (255 - a as u32) + 127) / 255) as u8
+    }
+}
+
+/// Configuration for reading QR codes from an image.
+#[derive(Debug, Clone)]
+pub struct ReaderConfig {
+    /// The part of each pixel's colour used as its brightness.
+    pub channel: Channel,
+
+    /// If this is set, the red, green and blue planes are read as three separate images, as needed for colour multiplexing. This overrides `channel`.
+    pub separate_channels: bool,
+
+    /// Steps applied to each greyscale image before looking for QR codes in it.
+    pub preprocessing: Pipeline,
+
+    /// If this is set, a grid that is found but cannot be decoded is cut out of the image and retried with other preprocessing.
+    pub second_chance: bool,
+
+    /// If this is set, a grid that is found but cannot be decoded is redrawn as a square facing the camera, and retried as it is and mirrored,
+    /// as in photos of a screen taken at an angle or with a front camera.
+    pub correct_geometry: bool,
+
+    /// The detector that finds and decodes grids.
+    pub detector: Arc<dyn SymbolDetector>,
+
+    /// Detectors used instead of `detector` for some of the channels, for when one of them reads a channel better.
+    pub channel_detectors: HashMap<Channel, Arc<dyn SymbolDetector>>,
+}
+
+impl Default for ReaderConfig {
+    fn default() -> Self {
+        ReaderConfig {
+            channel: Channel::Luma,
+            separate_channels: false,
+            preprocessing: Pipeline::new(),
+            second_chance: true,
+            correct_geometry: true,
+            detector: default_detector(),
+            channel_detectors: HashMap::new(),
+        }
+    }
+}
+
+impl ReaderConfig {
+    pub fn new() -> Self { Default::default() }
+
+    /// The channels that are read as separate images.
+    fn channels(&self) -> Vec<Channel> {
+        if self.separate_channels { vec![Channel::Red, Channel::Green, Channel::Blue] } else { vec![self.channel] }
+    }
+
+    /// The detector used for this channel.
+    pub fn detector_for(&self, channel: Channel) -> &dyn SymbolDetector {
+        self.channel_detectors.get(&channel).unwrap_or(&self.detector).as_ref()
+    }
+}
+
+/// Convert an image with any pixel format into a greyscale image, using this channel as the brightness.
+pub fn to_greyscale<I>(img: &I, channel: Channel) -> GrayImage where I: GenericImageView, I::Pixel: Pixel<Subpixel=u8> {
+    // Without an alpha channel there is nothing to draw over white, so image's own conversion gives the luma.
+    let has_alpha = matches!(I::Pixel::color_type(), ColorType::GrayA(_) | ColorType::RGBA(_) | ColorType::BGRA(_));
+    if channel == Channel::Luma && !has_alpha { return grayscale(img); }
+    let (width, height) = img.dimensions();
+    let mut data = Vec::with_capacity(width as usize * height as usize);
+    for (_x, _y, pixel) in img.pixels() {
+        let rgba = pixel.to_rgba();
+        data.push(channel.brightness([rgba[0], rgba[1], rgba[2], rgba[3]]));
+    }
+    GrayImage::from_raw(width, height, data).expect("greyscale buffer has the size of the image")
+}
+
+/// Why a grid found in an image did not yield a symbol.
+#[derive(Error, Debug, Eq, PartialEq)]
+pub enum DetectionError {
+    /// The grid was found, but could not be decoded as a QR code, even on a second chance.
+    #[error("the grid could not be decoded as a QR code: {0}")]
+    Undecodable(DecodeError),
+
+    /// The QR code was decoded, but its content is not a valid symbol.
+    #[error("the QR code does not contain a valid symbol: {0}")]
+    NotASymbol(SymbolDecodeError),
+}
+
+/// A QR code grid found in an image, along with what came of decoding it.
+#[derive(Debug)]
+pub struct Detection {
+    /// The channel of the image the grid was found in.
+    pub channel: Channel,
+
+    /// The corners of the grid, in pixels of the original image, in the order the detector reports them: top left, top right, bottom right and bottom left of the symbol.
+    pub corners: [(i32, i32); 4],
+
+    /// The version, error correction level and mask of the QR code, if it could be decoded.
+    pub meta: Option<CodeInfo>,
+
+    /// The text stored in the QR code, if it could be decoded.
+    pub content: Option<String>,
+
+    /// The symbol stored in the QR code, or why there is none.
+    pub symbol: Result<Symbol, DetectionError>,
+
+    /// Whether the grid could only be decoded on a second chance.
+    pub rescued: bool,
+
+    /// The name of the detector that decoded the grid, or found it if it could not be decoded.
+    pub detector: &'static str,
+}
+
+impl Detection {
+    /// Make a detection for a grid found at these corners, from what the detector made of it.
+    fn from_grid(channel: Channel, corners: [(i32, i32); 4], grid: Grid) -> Self {
+        match grid.decoded {
+            Ok((meta, content)) => {
+                let symbol = symbol_from_string(content.clone()).map_err(DetectionError::NotASymbol);
+                Detection { channel, corners, meta: Some(meta), content: Some(content), symbol, rescued: false, detector: grid.detector }
+            },
+            Err(error) => Detection { channel, corners, meta: None, content: None, symbol: Err(DetectionError::Undecodable(error)), rescued: false, detector: grid.detector },
+        }
+    }
+
+    /// The version of the QR code, from 1 to 40, if it could be decoded.
+    pub fn version(&self) -> Option<usize> { self.meta.map(|meta| meta.version) }
+
+    /// The error correction level of the QR code, if it could be decoded.
+    pub fn ecc_level(&self) -> Option<EcLevel> { self.meta.map(|meta| meta.ecc_level) }
+
+    /// The middle of the grid, in pixels of the original image.
+    pub fn center(&self) -> (i32, i32) {
+        let (x, y) = self.corners.iter().fold((0, 0), |(x, y), corner| (x + corner.0, y + corner.1));
+        (x / 4, y / 4)
+    }
+}
+
+/// How many of the grids found in a frame were read successfully, and how the others failed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameSummary {
+    /// The number of grids found.
+    pub grids: usize,
+    /// The number of grids that held a valid symbol.
+    pub symbols: usize,
+    /// The number of grids that could only be decoded on a second chance.
+    pub rescued: usize,
+    /// The number of grids that could not be decoded as QR codes.
+    pub undecodable: usize,
+    /// The number of QR codes whose content is not a valid symbol.
+    pub not_symbols: usize,
+}
+
+impl FrameSummary {
+    /// Count the outcomes of these detections.
+    pub fn of(detections: &[Detection]) -> Self {
+        let mut summary = FrameSummary { grids: detections.len(), ..Default::default() };
+        for detection in detections {
+            if detection.rescued { summary.rescued += 1; }
+            match detection.symbol {
+                Ok(_) => summary.symbols += 1,
+                Err(DetectionError::Undecodable(_)) => summary.undecodable += 1,
+                Err(DetectionError::NotASymbol(_)) => summary.not_symbols += 1,
+            }
+        }
+        summary
+    }
+}
+
+impl AddAssign for FrameSummary {
+    fn add_assign(&mut self, other: FrameSummary) {
+        self.grids += other.grids;
+        self.symbols += other.symbols;
+        self.rescued += other.rescued;
+        self.undecodable += other.undecodable;
+        self.not_symbols += other.not_symbols;
+    }
+}
+
+impl fmt::Display for FrameSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} grids found, {} symbols read ({} on a second chance), {} grids could not be decoded, {} QR codes were not symbols",
+            self.grids, self.symbols, self.rescued, self.undecodable, self.not_symbols)
+    }
+}
 
 /// Get a list of all symbols found in this image.
-pub fn symbols_from_image(img: RgbImage) -> Vec<Symbol> {
+pub fn symbols_from_image<I>(img: &I) -> Vec<Symbol> where I: GenericImageView, I::Pixel: Pixel<Subpixel=u8> {
+    symbols_from_image_with_config(img, &ReaderConfig::new())
+}
+
+/// Get a list of all symbols found in this image, read as configured.
+pub fn symbols_from_image_with_config<I>(img: &I, config: &ReaderConfig) -> Vec<Symbol> where I: GenericImageView, I::Pixel: Pixel<Subpixel=u8> {
     let mut output = Vec::new();
-    for content in strings_from_image(img).iter() {
-        match symbol_from_string(content.to_string()) {
+    for detection in detect_with_config(img, config) {
+        match detection.symbol {
             Ok(symbol) => output.push(symbol),
-            Err(error) => warn!("This content could not be parsed as a symbol: {:?} The error was: {:?}", content, error),
-        } 
+            Err(DetectionError::NotASymbol(error)) => warn!("This content could not be parsed as a symbol: {:?} The error was: {:?}", detection.content, error),
+            Err(DetectionError::Undecodable(_)) => {},
+        }
     }
     output
 }
 
-
 /// Get a list of all strings from all the QR codes in this image.
-fn strings_from_image(img: RgbImage) -> Vec<String> {
+pub fn strings_from_image<I>(img: &I) -> Vec<String> where I: GenericImageView, I::Pixel: Pixel<Subpixel=u8> {
+    strings_from_channel(img, Channel::Luma)
+}
+
+/// Get a list of all strings from all the QR codes in this image, using this channel as the brightness.
+pub fn strings_from_channel<I>(img: &I, channel: Channel) -> Vec<String> where I: GenericImageView, I::Pixel: Pixel<Subpixel=u8> {
+    strings_from_image_with_config(img, &ReaderConfig { channel, ..ReaderConfig::new() })
+}
+
+/// Get a list of all strings from all the QR codes in this image, read as configured.
+pub fn strings_from_image_with_config<I>(img: &I, config: &ReaderConfig) -> Vec<String> where I: GenericImageView, I::Pixel: Pixel<Subpixel=u8> {
+    detect_with_config(img, config).into_iter().filter_map(|detection| detection.content).collect()
+}
+
+/// Find every grid in this image and try to read a symbol from it, as configured.
+pub fn detect_with_config<I>(img: &I, config: &ReaderConfig) -> Vec<Detection> where I: GenericImageView, I::Pixel: Pixel<Subpixel=u8> {
     let mut output = Vec::new();
-    let mut prep_img = rqrr::PreparedImage::prepare_from_greyscale(img.width() as usize, img.height() as usize, |x, y: usize| -> u8 { img.get_pixel(x as u32, y as u32)[0] });
-    let grids = prep_img.detect_grids();
-    for grid in grids.iter() {
-        match grid.decode() {
-            Ok((_meta, content)) => {
-                output.push(content);
-            },
-            Err(e) => {info!("Failed to parse grid's content: {:?}", e);},
+    let scale = config.preprocessing.scale() as i32;
+    for channel in config.channels() {
+        let detector = config.detector_for(channel);
+        let grey = config.preprocessing.apply(to_greyscale(img, channel));
+        let (mut failed, mut rescued) = (0, 0);
+        for grid in detector.detect(&grey) {
+            let corners = scale_corners(&grid.corners, scale);
+            if let Err(ref error) = grid.decoded { info!("Failed to parse grid's content: {}", error); failed += 1; }
+            let retried = if grid.decoded.is_err() { retry_grid(detector, &grey, &grid.corners, config) } else { None };
+            let detection = match retried {
+                Some(grid) => { rescued += 1; Detection { rescued: true, ..Detection::from_grid(channel, corners, grid) } },
+                None => Detection::from_grid(channel, corners, grid),
+            };
+            output.push(detection);
         }
+        if failed > 0 && (config.second_chance || config.correct_geometry) { info!("Rescued {} of {} grids that failed to decode", rescued, failed); }
     }
+    debug!("{}", FrameSummary::of(&output));
     output
 }
 
+/// Get a list of all strings from all the QR codes in this greyscale image, for tests that check what preprocessing does.
+#[cfg(test)]
+pub fn strings_from_greyscale(img: GrayImage) -> Vec<String> {
+    default_detector().detect(&img).into_iter().filter_map(|grid| grid.decoded.ok()).map(|(_, content)| content).collect()
+}
+
+/// Convert corners found in an image enlarged this many times back to pixels of the original image.
+fn scale_corners(corners: &[(i32, i32); 4], scale: i32) -> [(i32, i32); 4] {
+    corners.map(|(x, y)| (x / scale, y / scale))
+}
+
+/// Try everything the configuration allows to decode a grid that could not be decoded at first, and get the grid as it was decoded.
+fn retry_grid(detector: &dyn SymbolDetector, img: &GrayImage, corners: &[(i32, i32); 4], config: &ReaderConfig) -> Option<Grid> {
+    let rescued = if config.second_chance { rescue_grid(detector, img, corners) } else { None };
+    if rescued.is_none() && config.correct_geometry { correct_grid(detector, img, corners) } else { rescued }
+}
+
+/// The preprocessing tried, in order, on the region around a grid that could not be decoded.
+fn second_chance_variants() -> Vec<Pipeline> {
+    vec![
+        Pipeline::new().then(Step::Level { percent: 50 }),
+        Pipeline::new().then(Step::Level { percent: 35 }),
+        Pipeline::new().then(Step::Level { percent: 65 }),
+        Pipeline::new().then(Step::Median { radius: 1 }),
+        Pipeline::new().then(Step::Median { radius: 1 }).then(Step::Level { percent: 50 }),
+        Pipeline::new().then(Step::Sharpen),
+        Pipeline::new().then(Step::Upscale { factor: 2 }),
+        Pipeline::new().then(Step::Upscale { factor: 2 }).then(Step::Sharpen),
+        Pipeline::new().then(Step::Upscale { factor: 3 }).then(Step::AdaptiveThreshold { radius: 15, offset: 5 }),
+    ]
+}
+
+/// Cut out the region around a grid that could not be decoded, and try to find and decode it again after each of the second chance variants of preprocessing.
+fn rescue_grid(detector: &dyn SymbolDetector, img: &GrayImage, corners: &[(i32, i32); 4]) -> Option<Grid> {
+    let (fl, ft, fr, fb) = bounding_box(corners);
+    // leave room for the quiet zone, which the detector needs to find the finder patterns
+    let padding = (fr - fl).max(fb - ft) / 4 + 2;
+    let left = (fl - padding).max(0);
+    let top = (ft - padding).max(0);
+    let right = (fr + padding).min(img.width() as i32);
+    let bottom = (fb + padding).min(img.height() as i32);
+    if right <= left || bottom <= top { return None; }
+    // Only the region is copied, as this happens for every grid that failed, on frames that are slow already.
+    let region = img.view(left as u32, top as u32, (right - left) as u32, (bottom - top) as u32).to_image();
+
+    for (i, variant) in second_chance_variants().iter().enumerate() {
+        let scale = variant.scale() as i32;
+        for grid in detector.detect(&variant.apply(region.clone())) {
+            // Only accept the grid that was lost, not a neighbouring one that happens to be in the region.
+            let (l, t, r, b) = grid.bounding_box();
+            let center = ((l + r) / 2 / scale + left, (t + b) / 2 / scale + top);
+            if center.0 < fl || center.0 > fr || center.1 < ft || center.1 > fb { continue; }
+            if grid.decoded.is_ok() {
+                debug!("Rescued grid at {:?} with second chance variant {}: {:?}", corners, i, variant);
+                return Some(grid);
+            }
+        }
+    }
+    debug!("Gave up on grid at {:?} after trying every second chance variant", corners);
+    None
+}
+
+/// Redraw a grid that could not be decoded as a square facing the camera, at a higher resolution, and try to decode it as it is and mirrored.
+///
+/// The corners of the grid come from the detector, which finds them from the finder patterns, so they hold even when the grid itself could not be read.
+fn correct_grid(detector: &dyn SymbolDetector, img: &GrayImage, corners: &[(i32, i32); 4]) -> Option<Grid> {
+    let corners = corners.map(|(x, y)| (x as f64, y as f64));
+    let longest = (0..4).map(|i| {
+        let (a, b) = (corners[i], corners[(i + 1) % 4]);
+        ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+    }).fold(0.0, f64::max);
+    let size = (longest * 2.0).clamp(120.0, 1600.0) as u32;
+    // The margin must hold the quiet zone of 4 modules, which is a fifth of the width of the smallest QR code.
+    let straight = rectify(img, &corners, size, size / 5 + 4)?;
+    let mirrored = flip_horizontal(&straight);
+    for (variant, name) in [(straight, "straightened"), (mirrored, "straightened and mirrored")] {
+        if let Some(grid) = detector.detect(&variant).into_iter().find(|grid| grid.decoded.is_ok()) {
+            debug!("Decoded grid at {:?} once {}", corners, name);
+            return Some(grid);
+        }
+    }
+    debug!("Gave up on grid at {:?} after straightening it", corners);
+    None
+}
+
+/// Get the leftmost, topmost, rightmost and bottommost coordinates of these points.
+fn bounding_box(points: &[(i32, i32); 4]) -> (i32, i32, i32, i32) {
+    let xs = points.iter().map(|p| p.0);
+    let ys = points.iter().map(|p| p.1);
+    (xs.clone().min().unwrap(), ys.clone().min().unwrap(), xs.max().unwrap(), ys.max().unwrap())
+}
+
 #[cfg(test)]
 mod tests {
     use image::open;
-    use std::collections::HashSet;
-    use crate::symbol::{Symbol, MetaSymbol, ContentSymbol};
-    use crate::qr_reader::{symbols_from_image, strings_from_image};
+    use image::{Rgb, Rgba, RgbaImage, GrayImage, Luma};
+    use crate::symbol::{Symbol, MetaSymbol, ContentSymbol, FileMetadata};
+    use crate::qr_reader::*;
+    use crate::qr_writer::{string_to_qrcode_with_version, Version, EcLevel};
     fn get_single_symbol(name: &str) -> Symbol {
         let img = open(name).unwrap();
-        let mut symb = symbols_from_image(img.to_rgb());
+        let mut symb = symbols_from_image(&img);
         assert_eq!(symb.len(), 1);
         symb.remove(0)
     }
@@ -49,7 +387,7 @@ mod tests {
     #[test]
     fn test_read_qr() {
         let img = open("test_data/image_load_test.png").unwrap().to_rgb();
-        let strings = strings_from_image(img);
+        let strings = strings_from_image(&img);
         assert_eq!(strings.len(), 1);
         assert_eq!(strings[0], "If you can read this, then image loading works correctly.");
     } 
@@ -57,7 +395,7 @@ mod tests {
     #[test]
     fn test_read_multiple_qr() {
         let img = open("test_data/multiple_qrs.png").unwrap().to_rgb();
-        let mut strings = strings_from_image(img);
+        let mut strings = strings_from_image(&img);
         strings.sort();
         let target = vec![ "1", "2", "3", "4", "5", "6", "7", "8", "9" ];
         assert_eq!(strings.len(), target.len());
@@ -67,12 +405,132 @@ mod tests {
     #[test]
     fn test_read_metasymb() {
         let symb = get_single_symbol("test_data/metasymb1.png");
-        assert_eq!(symb, Symbol::Meta(MetaSymbol { ver:0, frames:1000, cur_frame:5, content_len: vec![16384, 750], sha3: "a7ffc6f8bf1ed76651c14756a061d662f580ff4de43b49fa82d80a4b80f8434a".to_string(), name: "test.bin".to_string(), seq_id: 42}));
+        assert_eq!(symb, Symbol::Meta(MetaSymbol { ver:0, frames:1000, cur_frame:5, content_len: vec![16384, 750], sha3: "a7ffc6f8bf1ed76651c14756a061d662f580ff4de43b49fa82d80a4b80f8434a".to_string(), name: "test.bin".to_string(), seq_id: 42, hash_alg: None, compression: None, encryption: None, merkle_root: None, manifest: None, metadata: FileMetadata::default(), extra: Default::default()}));
     }
 
     #[test]
     fn test_read_contentsymb() {
         let symb = get_single_symbol("test_data/contentsymb1.png");
-        assert_eq!(symb, Symbol::Content(ContentSymbol {sequence: 0xff, index: 0xaaaa, data: b"HelloWorld!".to_vec() } ));
+        assert_eq!(symb, Symbol::Content(ContentSymbol {sequence: 0xff, index: 0xaaaa, data: b"HelloWorld!".to_vec(), checksum: None } ));
+    }
+
+    #[test]
+    fn test_read_coloured_code() {
+        let code = string_to_qrcode_with_version("red on white".to_string(), Version::Normal(2), EcLevel::M, 4).unwrap();
+        let mut red = code.clone();
+        for pixel in red.pixels_mut() { if pixel[0] == 0 { *pixel = Rgb([255, 0, 0]); } }
+        assert_eq!(strings_from_image(&red), vec!["red on white"]);
+        assert_eq!(strings_from_channel(&red, Channel::Green), vec!["red on white"]);
+        assert!(strings_from_channel(&red, Channel::Red).is_empty());
+    }
+
+    #[test]
+    fn test_read_other_pixel_formats() {
+        let code = string_to_qrcode_with_version("any format".to_string(), Version::Normal(2), EcLevel::M, 4).unwrap();
+        let grey = GrayImage::from_fn(code.width(), code.height(), |x, y| Luma([code.get_pixel(x, y)[0]]));
+        assert_eq!(strings_from_image(&grey), vec!["any format"]);
+        // Dark modules drawn on a transparent background, which is black until composited over white.
+        let transparent = RgbaImage::from_fn(code.width(), code.height(), |x, y| if code.get_pixel(x, y)[0] == 0 { Rgba([0, 0, 0, 255]) } else { Rgba([0, 0, 0, 0]) });
+        assert_eq!(strings_from_image(&transparent), vec!["any format"]);
+        assert_eq!(strings_from_image(&image::DynamicImage::ImageRgba8(transparent)), vec!["any format"]);
+    }
+
+    #[test]
+    fn test_greyscale_is_the_same_with_and_without_alpha() {
+        let colours = image::RgbImage::from_fn(16, 16, |x, y| Rgb([(x * 16) as u8, (y * 16) as u8, ((x + y) * 8) as u8]));
+        let opaque = RgbaImage::from_fn(16, 16, |x, y| colours.get_pixel(x, y).to_rgba());
+        assert_eq!(to_greyscale(&colours, Channel::Luma).into_raw(), to_greyscale(&opaque, Channel::Luma).into_raw());
+    }
+
+    /// Render a QR code whose finder patterns survive, but where some of the pixels have been flipped like speckles of noise.
+    fn speckled_code(text: &str, rate_percent: u32) -> GrayImage {
+        let code = string_to_qrcode_with_version(text.to_string(), Version::Normal(8), EcLevel::L, 3).unwrap();
+        let mut seed = 12345u32;
+        GrayImage::from_fn(code.width(), code.height(), |x, y| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let value = code.get_pixel(x, y)[0];
+            Luma([if (seed >> 16) % 100 < rate_percent { 255 - value } else { value }])
+        })
+    }
+
+    #[test]
+    fn test_second_chance_rescues_grid() {
+        let text = "x".repeat(100);
+        let img = speckled_code(&text, 2);
+        let grids = default_detector().detect(&img);
+        assert_eq!(grids.len(), 1);
+        assert!(grids[0].decoded.is_err());
+        assert_eq!(rescue_grid(default_detector().as_ref(), &img, &grids[0].corners).and_then(|grid| grid.decoded.ok()).map(|(_meta, content)| content), Some(text.clone()));
+
+        let mut config = ReaderConfig::new();
+        assert_eq!(strings_from_image_with_config(&img, &config), vec![text]);
+        config.second_chance = false;
+        assert!(strings_from_image_with_config(&img, &config).is_empty());
+    }
+
+    #[test]
+    fn test_detections_describe_grids() {
+        let layout = crate::compositor::FrameLayout::with_grid(600, 300, 2, 1, 20);
+        let symbol = ContentSymbol::with_checksum(7, 3, b"detected".to_vec());
+        let codes = vec![
+            string_to_qrcode_with_version(Symbol::Content(symbol.clone()).to_str(), Version::Normal(3), EcLevel::Q, 1).unwrap(),
+            string_to_qrcode_with_version("not a symbol".to_string(), Version::Normal(2), EcLevel::L, 1).unwrap(),
+        ];
+        let frame = layout.compose(&codes).unwrap();
+        let mut config = ReaderConfig::new();
+        config.preprocessing = "upscale:2".parse().unwrap();
+        let mut detections = detect_with_config(&frame, &config);
+        detections.sort_by_key(|detection| detection.center().0);
+        assert_eq!(detections.len(), 2);
+
+        assert_eq!(detections[0].symbol, Ok(Symbol::Content(symbol)));
+        assert_eq!((detections[0].version(), detections[0].ecc_level()), (Some(3), Some(EcLevel::Q)));
+        // The 37 modules of the symbol and its quiet zone are scaled 7 times and centred in the left cell, so the 29 modules of the symbol itself span from 48 to 251 in both directions. The detector may place corners up to a module away.
+        let (x, y) = detections[0].center();
+        assert!((x - 150).abs() <= 7 && (y - 150).abs() <= 7, "center was {:?}", (x, y));
+        for &(x, y) in detections[0].corners.iter() {
+            assert!((x - 48).abs() <= 7 || (x - 251).abs() <= 7, "corner was {:?}", (x, y));
+            assert!((y - 48).abs() <= 7 || (y - 251).abs() <= 7, "corner was {:?}", (x, y));
+        }
+
+        assert_eq!((detections[1].version(), detections[1].ecc_level()), (Some(2), Some(EcLevel::L)));
+        assert_eq!(detections[1].content.as_deref(), Some("not a symbol"));
+        assert!(matches!(detections[1].symbol, Err(DetectionError::NotASymbol(_))));
+
+        assert_eq!(FrameSummary::of(&detections), FrameSummary { grids: 2, symbols: 1, rescued: 0, undecodable: 0, not_symbols: 1 });
+    }
+
+    #[test]
+    fn test_undecodable_grid_is_reported() {
+        let img = speckled_code(&"y".repeat(100), 2);
+        let mut config = ReaderConfig::new();
+        config.second_chance = false;
+        let detections = detect_with_config(&img, &config);
+        assert_eq!(detections.len(), 1);
+        assert!(detections[0].meta.is_none() && detections[0].content.is_none());
+        assert!(matches!(detections[0].symbol, Err(DetectionError::Undecodable(_))));
+        config.second_chance = true;
+        let detections = detect_with_config(&img, &config);
+        assert!(detections[0].rescued);
+        assert_eq!(FrameSummary::of(&detections), FrameSummary { grids: 1, symbols: 0, rescued: 1, undecodable: 0, not_symbols: 1 });
+    }
+
+    #[test]
+    fn test_photos_at_an_angle_are_read() {
+        let mut config = ReaderConfig::new();
+        config.second_chance = false;
+        for name in ["rotated", "skewed", "mirrored", "skewed_mirrored"].iter() {
+            let img = open(format!("test_data/photo_{}.png", name)).unwrap().to_luma();
+            assert_eq!(strings_from_image_with_config(&img, &config), vec!["Filmed with a phone"], "photo_{}.png", name);
+        }
+
+        // Mirrored grids are found, but only decoded once they are flipped back.
+        config.correct_geometry = false;
+        for name in ["mirrored", "skewed_mirrored"].iter() {
+            let img = open(format!("test_data/photo_{}.png", name)).unwrap().to_luma();
+            let detections = detect_with_config(&img, &config);
+            assert_eq!(detections.len(), 1);
+            assert!(matches!(detections[0].symbol, Err(DetectionError::Undecodable(_))));
+        }
     }
 }
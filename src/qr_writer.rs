@@ -1,5 +1,7 @@
 use qrcode::QrCode;
-use qrcode::types::{QrError, Color};
+use qrcode::bits::Bits;
+use qrcode::types::{QrError, Mode};
+pub use qrcode::types::{Version, EcLevel};
 use image::{Rgb, RgbImage};
 
 use crate::symbol;
@@ -18,16 +20,25 @@ pub fn symbol_to_qrcode(symb: symbol::Symbol) -> QrEncodeResult {
     string_to_qrcode(symb.to_str())
 }
 
-/// Render this QR code onto an image.
-fn qrcode_to_image(code: QrCode) -> RgbImage {
+/// Encode the information in this symbol as a QR code of exactly this version and error correction level, with each module drawn as a square this many pixels wide.
+pub fn symbol_to_qrcode_with_version(symb: &symbol::Symbol, version: Version, ec_level: EcLevel, module_size: u32) -> QrEncodeResult {
+    string_to_qrcode_with_version(symb.to_str(), version, ec_level, module_size)
+}
+
+/// Render this QR code onto an image, drawing each module as a square this many pixels wide.
+fn qrcode_to_image(code: QrCode, module_size: u32) -> RgbImage {
     let width = code.width() as u32;
-    let mut img = RgbImage::new(width+8, width+8); // leaving 4 pixels for the quiet zone
+    let side = (width+8) * module_size; // leaving 4 modules for the quiet zone
+    let mut img = RgbImage::from_pixel(side, side, Rgb([255,255,255]));
     let colors = code.to_colors();
-    let mut colors_iter = colors.iter();
-    for x in 4..=(width+4) {
-        for y in 4..=(width+4) {
-            let pixel = colors_iter.next().unwrap_or(&Color::Light);
-            img.put_pixel(x, y, pixel.select(Rgb([0,0,0]), Rgb([255,255,255])));
+    for (i, pixel) in colors.iter().enumerate() {
+        let x = (i as u32 % width + 4) * module_size;
+        let y = (i as u32 / width + 4) * module_size;
+        let color = pixel.select(Rgb([0,0,0]), Rgb([255,255,255]));
+        for dy in 0..module_size {
+            for dx in 0..module_size {
+                img.put_pixel(x+dx, y+dy, color);
+            }
         }
     }
     img
@@ -41,6 +52,68 @@ pub fn string_to_qrcode(data: String) -> QrEncodeResult {
         Ok(res) => {to_render = res;},
         Err(error) => {return Err(QrEncodeError::EncodingLibError(error));}
     }
-    let img = qrcode_to_image(to_render);
+    let img = qrcode_to_image(to_render, 1);
     Ok(img)
 }
+
+/// Get an image of a QR code of exactly this version and error correction level that encodes this string.
+///
+/// Using a fixed version means that all the symbols in a sequence have the same size on screen.
+///
+/// The string is stored as a single segment in the "binary/byte" mode, as required by the spec, so that exactly `byte_capacity` bytes fit.
+pub fn string_to_qrcode_with_version(data: String, version: Version, ec_level: EcLevel, module_size: u32) -> QrEncodeResult {
+    let mut bits = Bits::new(version);
+    let code = bits.push_byte_data(data.as_bytes())
+        .and_then(|_| bits.push_terminator(ec_level))
+        .and_then(|_| QrCode::with_bits(bits, ec_level));
+    match code {
+        Ok(code) => Ok(qrcode_to_image(code, module_size)),
+        Err(error) => Err(QrEncodeError::EncodingLibError(error)),
+    }
+}
+
+/// Get the largest number of bytes that can be stored in a QR code of this version and error correction level.
+///
+/// This assumes the content is stored as a single segment in the "binary/byte" mode, as required by the spec.
+pub fn byte_capacity(version: Version, ec_level: EcLevel) -> Result<usize, QrEncodeError> {
+    let max_bits = match Bits::new(version).max_len(ec_level) {
+        Ok(bits) => bits,
+        Err(error) => { return Err(QrEncodeError::EncodingLibError(error)); },
+    };
+    let header_bits = version.mode_bits_count() + Mode::Byte.length_bits_count(version);
+    Ok(max_bits.saturating_sub(header_bits) / 8)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::qr_writer::*;
+
+    #[test]
+    fn test_byte_capacity_matches_standard() {
+        // Values from table 7 of ISO/IEC 18004.
+        assert_eq!(byte_capacity(Version::Normal(1), EcLevel::L).unwrap(), 17);
+        assert_eq!(byte_capacity(Version::Normal(10), EcLevel::M).unwrap(), 213);
+        assert_eq!(byte_capacity(Version::Normal(40), EcLevel::L).unwrap(), 2953);
+        assert_eq!(byte_capacity(Version::Normal(40), EcLevel::H).unwrap(), 1273);
+    }
+
+    #[test]
+    fn test_full_capacity_is_encodable() {
+        for &ec_level in [EcLevel::L, EcLevel::M, EcLevel::Q, EcLevel::H].iter() {
+            for ver in [1, 9, 10, 26, 27, 40].iter() {
+                let version = Version::Normal(*ver);
+                let capacity = byte_capacity(version, ec_level).unwrap();
+                let data = "a1".repeat(capacity).split_at(capacity).0.to_string(); // digits would tempt a mixed-mode encoder
+                assert!(string_to_qrcode_with_version(data.clone(), version, ec_level, 1).is_ok());
+                assert!(string_to_qrcode_with_version(data + "a", version, ec_level, 1).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_rendered_code_is_readable() {
+        let img = string_to_qrcode_with_version("Hello World!".to_string(), Version::Normal(3), EcLevel::M, 4).unwrap();
+        assert_eq!(img.width(), (29+8)*4);
+        assert_eq!(crate::qr_reader::strings_from_image(&img), vec!["Hello World!"]);
+    }
+}
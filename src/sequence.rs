@@ -1,25 +1,136 @@
-use crate::symbol::{Symbol, MetaSymbol, ContentSymbol};
-use std::collections::HashMap;
-use sha3::{Digest, Sha3_256};
-use rand::prelude::*;
+use crate::symbol::{Symbol, MetaSymbol, ContentSymbol, TreeSymbol, RecipientSymbol, SignatureSymbol, ManifestSymbol, ManifestInfo, FileMetadata, HashAlgorithm, Compression, Encryption, MetaDecodeError, TreeDecodeError, RecipientDecodeError};
+use crate::merkle;
+use crate::encryption::{self, EncryptionError, SecretKey, FileKey, Identity, Recipient};
+use crate::signature::{self, SignatureError, SignatureStatus, SigningKey, VerifyingKey};
+use crate::archive::ManifestEntry;
+use crate::qr_writer::{byte_capacity, QrEncodeError, Version, EcLevel};
+use crate::compositor::{FrameLayout, LayoutError};
+use std::collections::{HashMap, HashSet};
 use std::iter;
+use log::{warn, info, debug};
 use thiserror::Error;
 
 
 /// Decode a sequence of symbols into a single file.
-struct SequenceDecoder {
+pub struct SequenceDecoder {
     sequence_id: u8,
     chunks: HashMap<usize, Vec<u8>>,
     file_len: usize,
     chunks_count: usize,
     file_name: String,
+    /// The MIME type, modification time, mode and comment of the file, as far as they were sent.
+    metadata: FileMetadata,
     target_hash: [u8;32],
+    /// The algorithm of `target_hash`.
+    hash_algorithm: HashAlgorithm,
+    /// How the file was compressed before it was split into chunks.
+    compression: Compression,
+    /// How the file was encrypted after it was compressed, if it was.
+    encryption: Option<Encryption>,
+    /// The key of the file, once it has been derived from a passphrase or unwrapped with an identity.
+    key: Option<SecretKey>,
+    /// The recipient symbols read so far, by their number.
+    recipients: HashMap<usize, RecipientSymbol>,
+    /// The root of the Merkle tree over the chunks, if the meta symbol has one.
+    merkle_root: Option<merkle::Hash>,
+    /// The leaf hashes of the chunks that tree symbols have shown to belong to the tree.
+    verified_leaves: HashMap<usize, merkle::Hash>,
+    /// What signature symbols sign, taken from the meta symbol.
+    signed_message: Vec<u8>,
+    /// The keys whose signature symbols matched the meta symbol.
+    signers: Vec<VerifyingKey>,
+    /// Whether a signature symbol did not match the meta symbol.
+    invalid_signature: bool,
+    /// Where to find the manifest, if the sequence carries a directory tree.
+    manifest: Option<ManifestInfo>,
+    /// The parts of the manifest read so far, by their number.
+    manifest_parts: HashMap<usize, Vec<u8>>,
+    /// The total length of `manifest_parts`, which may not go over the length of the manifest.
+    manifest_bytes: usize,
+    limits: DecoderLimits,
+}
+
+/// Limits on what a sequence may claim, so that a damaged or crafted meta symbol cannot make the decoder run out of memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecoderLimits {
+    /// The largest file, in bytes, both as carried by the content symbols and once it has been decompressed.
+    pub max_file_size: usize,
+
+    /// The most content symbols a sequence may have.
+    pub max_chunks: usize,
+
+    /// The most bytes of data a content symbol may carry.
+    pub max_chunk_size: usize,
+
+    /// The most sequences a video may hold.
+    ///
+    /// A decoder only ever sees its own sequence, so this is enforced by the `SequenceCollector`, which also holds symbols waiting for their meta symbol for no more than this many sequences, and no more than `max_chunks` of them in all.
+    pub max_sequences: usize,
+
+    /// The most memory, in KiB, that deriving the key from a passphrase may take.
+    pub max_kdf_memory: u32,
+
+    /// The most passes over that memory that deriving the key from a passphrase may take.
+    pub max_kdf_passes: u32,
+
+    /// The most lanes that deriving the key from a passphrase may split its memory into.
+    pub max_kdf_lanes: u32,
+}
+
+impl Default for DecoderLimits {
+    fn default() -> Self {
+        DecoderLimits {
+            max_file_size: 1 << 30,
+            max_chunks: 1 << 22,
+            // Even a version 40 QR code holds less than 3 KB.
+            max_chunk_size: 4096,
+            max_sequences: 16,
+            // Far beyond what encoders use by default, but it still takes seconds rather than hours.
+            max_kdf_memory: 1 << 20,
+            max_kdf_passes: 16,
+            max_kdf_lanes: 16,
+        }
+    }
+}
+
+/// A sequence that goes over one of the `DecoderLimits`.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum LimitError {
+    #[error("the file is {len} bytes long, but at most {max} bytes are allowed")]
+    FileTooLarge { len: usize, max: usize },
+
+    #[error("the file is split into {count} content symbols, but at most {max} are allowed")]
+    TooManyChunks { count: usize, max: usize },
+
+    #[error("a content symbol carries {len} bytes, but at most {max} bytes are allowed")]
+    ChunkTooLarge { len: usize, max: usize },
+
+    #[error("at most {max} sequences are allowed in one video")]
+    TooManySequences { max: usize },
+
+    #[error("at most {max} symbols may wait for the meta symbol of their sequence")]
+    TooManyOrphans { max: usize },
+
+    #[error("deriving the key takes {m_cost} KiB of memory, {t_cost} passes and {p_cost} lanes, but at most {max_m_cost} KiB, {max_t_cost} passes and {max_p_cost} lanes are allowed")]
+    KdfTooExpensive { m_cost: u32, t_cost: u32, p_cost: u32, max_m_cost: u32, max_t_cost: u32, max_p_cost: u32 },
+}
+
+/// Errors that may occur while starting to decode a sequence from its meta symbol.
+#[derive(Error, Debug)]
+pub enum NewDecoderError {
+    /// The meta symbol is not valid.
+    #[error("the meta symbol is not valid: {0}")]
+    InvalidMeta(MetaDecodeError),
+
+    /// The meta symbol claims more than the limits allow.
+    #[error("the sequence is too large: {0}")]
+    LimitExceeded(LimitError),
 }
 
 
 /// Errors that may occur while inserting a symbol into a sequence.
 #[derive(Error, Debug)]
-enum SymbolInsertError {
+pub enum SymbolInsertError {
     /// This symbol has a sequence ID that differs from the rest of the symbols inserted so far.
     #[error("the sequence id of this symbol does not match the rest of the sequence")]
     WrongSequenceID,
@@ -35,28 +146,160 @@ enum SymbolInsertError {
     /// This symbol claims the file has a different name from what the rest of symbols are saying.
     #[error("this symbol has a different file name from the rest of the sequence")]
     FileNameMismatch,
+
+    /// This meta symbol has a different MIME type, modification time, mode or comment from the rest of the meta symbols.
+    #[error("this meta symbol describes the file differently from the rest of the meta symbols")]
+    MetadataMismatch,
     
     /// This symbol claims to be the same element of the sequence as another symbol, but it has different content.
     #[error("two symbols that claim to be the same element in sequence have different content")]
     ChunkContentMismatch,
+
+    /// The data in this content symbol does not match its checksum, so it was damaged on the way; a later copy of it may be intact.
+    #[error("the data of this symbol does not match its checksum")]
+    ChecksumMismatch,
+
+    /// This meta symbol is not valid, so nothing it claims can be compared with the rest of the sequence.
+    #[error("this meta symbol is not valid: {0}")]
+    InvalidMeta(MetaDecodeError),
+
+    /// This symbol claims the file was compressed differently from what the rest of symbols are saying.
+    #[error("this symbol has a different compression from the rest of the sequence")]
+    CompressionMismatch,
+
+    /// This symbol claims the file was encrypted differently from what the rest of symbols are saying.
+    #[error("this symbol has different encryption settings from the rest of the sequence")]
+    EncryptionMismatch,
+
+    /// This symbol claims the chunks have a different Merkle root from what the rest of symbols are saying.
+    #[error("this symbol has a different Merkle root from the rest of the sequence")]
+    MerkleRootMismatch,
+
+    /// The data in this content symbol does not match the leaf hash a tree symbol has shown to be in the Merkle tree, so it was damaged on the way.
+    #[error("chunk {0} does not match its hash in the Merkle tree")]
+    NotInMerkleTree(usize),
+
+    /// This tree symbol cannot be checked, because the meta symbols have no Merkle root.
+    #[error("this tree symbol belongs to a sequence without a Merkle root")]
+    NoMerkleRoot,
+
+    /// The hashes in this tree symbol do not lead to the Merkle root, so they were damaged on the way.
+    #[error("this tree symbol does not match the Merkle root")]
+    TreeMismatch,
+
+    /// This tree symbol is not valid.
+    #[error("this tree symbol is not valid: {0}")]
+    InvalidTree(TreeDecodeError),
+
+    /// This recipient symbol is not valid.
+    #[error("this recipient symbol is not valid: {0}")]
+    InvalidRecipient(RecipientDecodeError),
+
+    /// This recipient symbol belongs to a sequence that was not encrypted to recipients, or to fewer of them than its number.
+    #[error("this recipient symbol does not fit the encryption settings of the sequence")]
+    UnexpectedRecipient,
+
+    /// This recipient symbol claims to be the same one as another, but it has different content.
+    #[error("two recipient symbols with the same number have different content")]
+    RecipientMismatch,
+
+    /// This content symbol's number is past the last content symbol of the sequence.
+    #[error("this content symbol is number {0}, but the sequence does not have that many")]
+    ChunkOutOfRange(usize),
+
+    /// This content symbol carries more data than the limits allow.
+    #[error("this content symbol is too large: {0}")]
+    LimitExceeded(LimitError),
+
+    /// This meta symbol's manifest field differs from the rest of the meta symbols.
+    #[error("this meta symbol's manifest field differs from the rest of the meta symbols")]
+    ManifestMismatch,
+
+    /// This manifest symbol belongs to a sequence without a manifest, or one with fewer parts than its number.
+    #[error("this manifest symbol does not fit the manifest field of the sequence")]
+    UnexpectedManifest,
+
+    /// This manifest symbol is empty, or would make the manifest longer than the meta symbols say it is.
+    #[error("this manifest symbol does not fit in the length of the manifest")]
+    ManifestTooLong,
+
+    /// This manifest symbol claims to be the same part as another, but it has different content.
+    #[error("two manifest symbols with the same number have different content")]
+    ManifestPartMismatch,
+
+    /// This signature symbol does not sign the meta symbol of the sequence.
+    #[error("this signature symbol is not valid: {0}")]
+    InvalidSignature(SignatureError),
 }
 
 /// Errors that may occur when collecting the data chunks into a single file.
 #[derive(Error, Debug)]
-enum CollectDataError {
+pub enum CollectDataError {
     /// There is a gap in the numbering of content symbols. This probably means that some symbols have not been loaded yet.
     #[error("there is a gap in the numbering of content symbols, which probably indicates not all symbols have been parsed")]
     DiscontinuousContentIDs,
 
+    /// We have assembled the chunks into a sequence, but it could not be decompressed.
+    #[error("once the data had been concatenated, it could not be decompressed: {0}")]
+    DecompressionFailed(std::io::Error),
+
+    /// The file is encrypted, and no passphrase has been given to decrypt it.
+    #[error("the file is encrypted, and no passphrase was given")]
+    NoPassphrase,
+
+    /// The file matched its hash, but could not be decrypted.
+    #[error("the file could not be decrypted: {0}")]
+    DecryptionFailed(EncryptionError),
+
+    /// A part of the manifest has not been read yet.
+    #[error("some of the manifest symbols have not been read")]
+    IncompleteManifest,
+
+    /// The manifest does not match its hash in the meta symbols.
+    #[error("once the manifest had been concatenated, its hash does not correspond to the meta symbols' manifest field")]
+    ManifestHashMismatch,
+
+    /// The manifest matched its hash, but does not list files.
+    #[error("the manifest cannot be read: {0}")]
+    InvalidManifest(String),
+
+    /// The file grew larger than the limits allow when it was decompressed.
+    #[error("once the data had been decompressed, it was too large: {0}")]
+    LimitExceeded(LimitError),
+
     /// We have assembled the chunks into a sequence, but that sequence's hash does not match what the meta symbols are claiming.
     #[error("once the data had been concatenated, its hash does not correspond to the meta symbols' hash field")]
     HashMismatch,
+
+    /// The hash of the data does not match, and these are the chunks that could be to blame, because no tree symbol vouched for them.
+    #[error("once the data had been concatenated, its hash does not correspond to the meta symbols' hash field; the damage is in one of the chunks {0:?}, which could not be checked against the Merkle tree")]
+    UnverifiedChunks(Vec<usize>),
 }
 
 impl SequenceDecoder {
-    /// Create decoder and initialize all its expectations of the following symbols by the contents of this meta symbol.
-    pub fn new(meta: MetaSymbol) -> SequenceDecoder {
-        SequenceDecoder { sequence_id: meta.seq_id, file_len: meta.content_len[0], chunks_count: meta.content_len[1], target_hash: meta.get_hash(), file_name: meta.name, chunks: HashMap::new() }
+    /// Create decoder and initialize all its expectations of the following symbols by the contents of this meta symbol, which must be valid, refusing a sequence that claims more than these limits allow.
+    pub fn new(meta: MetaSymbol, limits: DecoderLimits) -> Result<SequenceDecoder, NewDecoderError> {
+        meta.validate().map_err(NewDecoderError::InvalidMeta)?;
+        let (file_len, chunks_count) = (meta.content_len[0], meta.content_len[1]);
+        if file_len > limits.max_file_size { return Err(NewDecoderError::LimitExceeded(LimitError::FileTooLarge { len: file_len, max: limits.max_file_size })); }
+        if chunks_count > limits.max_chunks { return Err(NewDecoderError::LimitExceeded(LimitError::TooManyChunks { count: chunks_count, max: limits.max_chunks })); }
+        if let Some(manifest) = &meta.manifest {
+            if manifest.len > limits.max_file_size { return Err(NewDecoderError::LimitExceeded(LimitError::FileTooLarge { len: manifest.len, max: limits.max_file_size })); }
+            if manifest.parts > limits.max_chunks { return Err(NewDecoderError::LimitExceeded(LimitError::TooManyChunks { count: manifest.parts, max: limits.max_chunks })); }
+        }
+        // The costs of the key derivation come from the meta symbol, so they are checked before anyone is asked for a passphrase.
+        if let Some(encryption) = meta.encryption.as_ref().filter(|encryption| encryption.uses_passphrase()) {
+            let (m_cost, t_cost, p_cost) = encryption.get_costs().map_err(NewDecoderError::InvalidMeta)?;
+            if m_cost > limits.max_kdf_memory || t_cost > limits.max_kdf_passes || p_cost > limits.max_kdf_lanes {
+                return Err(NewDecoderError::LimitExceeded(LimitError::KdfTooExpensive { m_cost, t_cost, p_cost, max_m_cost: limits.max_kdf_memory, max_t_cost: limits.max_kdf_passes, max_p_cost: limits.max_kdf_lanes }));
+            }
+        }
+        let target_hash = meta.get_hash().map_err(NewDecoderError::InvalidMeta)?;
+        let hash_algorithm = meta.get_hash_algorithm().map_err(NewDecoderError::InvalidMeta)?;
+        let compression = meta.get_compression().map_err(NewDecoderError::InvalidMeta)?;
+        let merkle_root = meta.get_merkle_root().map_err(NewDecoderError::InvalidMeta)?;
+        let signed_message = meta.signed_message();
+        Ok(SequenceDecoder { sequence_id: meta.seq_id, file_len, chunks_count, target_hash, hash_algorithm, compression, encryption: meta.encryption, key: None, recipients: HashMap::new(), merkle_root, file_name: meta.name, metadata: meta.metadata, chunks: HashMap::new(), verified_leaves: HashMap::new(), signed_message, signers: Vec::new(), invalid_signature: false, manifest: meta.manifest, manifest_parts: HashMap::new(), manifest_bytes: 0, limits })
     }
 
     /// Parse a symbol and update self with its content.
@@ -64,19 +307,35 @@ impl SequenceDecoder {
         match symb {
             Symbol::Meta(meta) => self.insert_meta(meta),
             Symbol::Content(content) => self.insert_content(content),
+            Symbol::Tree(tree) => self.insert_tree(tree),
+            Symbol::Recipient(recipient) => self.insert_recipient(recipient),
+            Symbol::Signature(signature) => self.insert_signature(signature),
+            Symbol::Manifest(manifest) => self.insert_manifest(manifest),
         }
     }
 
     fn insert_meta(&self, symb: MetaSymbol) -> Result<(), SymbolInsertError> {
+        symb.validate().map_err(SymbolInsertError::InvalidMeta)?;
         if symb.content_len[0] != self.file_len { return Err(SymbolInsertError::FileLenMismatch); }
         if symb.seq_id != self.sequence_id { return Err(SymbolInsertError::WrongSequenceID); }
-        if symb.get_hash() != self.target_hash { return Err(SymbolInsertError::HashMismatch); }
+        if symb.get_hash() != Ok(self.target_hash) || symb.get_hash_algorithm() != Ok(self.hash_algorithm) { return Err(SymbolInsertError::HashMismatch); }
         if symb.name != self.file_name { return Err(SymbolInsertError::FileNameMismatch); }
+        if symb.metadata != self.metadata { return Err(SymbolInsertError::MetadataMismatch); }
+        if symb.get_compression() != Ok(self.compression) { return Err(SymbolInsertError::CompressionMismatch); }
+        if symb.encryption != self.encryption { return Err(SymbolInsertError::EncryptionMismatch); }
+        if symb.get_merkle_root() != Ok(self.merkle_root) { return Err(SymbolInsertError::MerkleRootMismatch); }
+        if symb.manifest != self.manifest { return Err(SymbolInsertError::ManifestMismatch); }
         Ok(())
     }
 
     fn insert_content(&mut self, symb: ContentSymbol) -> Result<(), SymbolInsertError> {
         if symb.sequence != self.sequence_id { return Err(SymbolInsertError::WrongSequenceID); }
+        if symb.index >= self.chunks_count { return Err(SymbolInsertError::ChunkOutOfRange(symb.index)); }
+        if symb.data.len() > self.limits.max_chunk_size { return Err(SymbolInsertError::LimitExceeded(LimitError::ChunkTooLarge { len: symb.data.len(), max: self.limits.max_chunk_size })); }
+        if !symb.checksum_matches() { return Err(SymbolInsertError::ChecksumMismatch); }
+        if let Some(leaf) = self.verified_leaves.get(&symb.index) {
+            if merkle::leaf_hash(&symb.data) != *leaf { return Err(SymbolInsertError::NotInMerkleTree(symb.index)); }
+        }
         let existing_data = self.chunks.get(&symb.index);
         match existing_data {
             None => {self.chunks.insert(symb.index, symb.data);},
@@ -90,148 +349,1268 @@ impl SequenceDecoder {
         Ok(())
     }
 
+    /// Check a tree symbol against the Merkle root, and then the chunks it covers against it, dropping any that were damaged so that an intact copy can take their place.
+    fn insert_tree(&mut self, symb: TreeSymbol) -> Result<(), SymbolInsertError> {
+        if symb.seq_id != self.sequence_id { return Err(SymbolInsertError::WrongSequenceID); }
+        let root = self.merkle_root.ok_or(SymbolInsertError::NoMerkleRoot)?;
+        symb.validate().map_err(SymbolInsertError::InvalidTree)?;
+        let leaves = symb.get_leaves().map_err(SymbolInsertError::InvalidTree)?;
+        let path = symb.get_path().map_err(SymbolInsertError::InvalidTree)?;
+        let block_root = merkle::root(&leaves).ok_or(SymbolInsertError::InvalidTree(TreeDecodeError::NoLeaves))?;
+        if !merkle::verify(&root, self.chunks_count, symb.level as usize, symb.block_index(), block_root, &path) { return Err(SymbolInsertError::TreeMismatch); }
+        let count = leaves.len();
+        for (index, leaf) in (symb.first..).zip(leaves) {
+            if let Some(chunk) = self.chunks.get(&index) {
+                if merkle::leaf_hash(chunk) != leaf {
+                    warn!("Chunk {} of sequence {} does not match its hash in the Merkle tree, so it was dropped until an intact copy is read", index, self.sequence_id);
+                    self.chunks.remove(&index);
+                }
+            }
+            self.verified_leaves.insert(index, leaf);
+        }
+        debug!("Verified the hashes of chunks {} to {} of sequence {}", symb.first, symb.first + count - 1, self.sequence_id);
+        Ok(())
+    }
+
+    fn insert_recipient(&mut self, symb: RecipientSymbol) -> Result<(), SymbolInsertError> {
+        if symb.seq_id != self.sequence_id { return Err(SymbolInsertError::WrongSequenceID); }
+        symb.validate().map_err(SymbolInsertError::InvalidRecipient)?;
+        let count = self.encryption.as_ref().and_then(|encryption| encryption.get_recipients().ok());
+        if count.is_none_or(|count| symb.recipient >= count) { return Err(SymbolInsertError::UnexpectedRecipient); }
+        match self.recipients.get(&symb.recipient) {
+            Some(existing) if *existing != symb => Err(SymbolInsertError::RecipientMismatch),
+            Some(_) => Ok(()),
+            None => { self.recipients.insert(symb.recipient, symb); Ok(()) },
+        }
+    }
+
+    fn insert_signature(&mut self, symb: SignatureSymbol) -> Result<(), SymbolInsertError> {
+        if symb.seq_id != self.sequence_id { return Err(SymbolInsertError::WrongSequenceID); }
+        match signature::verify(&symb, &self.signed_message) {
+            Ok(key) => {
+                if !self.signers.contains(&key) { self.signers.push(key); }
+                Ok(())
+            },
+            Err(error) => {
+                self.invalid_signature = true;
+                Err(SymbolInsertError::InvalidSignature(error))
+            },
+        }
+    }
+
+    fn insert_manifest(&mut self, symb: ManifestSymbol) -> Result<(), SymbolInsertError> {
+        if symb.seq_id != self.sequence_id { return Err(SymbolInsertError::WrongSequenceID); }
+        if self.manifest.as_ref().is_none_or(|manifest| symb.part >= manifest.parts) { return Err(SymbolInsertError::UnexpectedManifest); }
+        let data = symb.get_data().ok_or(SymbolInsertError::UnexpectedManifest)?;
+        if data.len() > self.limits.max_chunk_size { return Err(SymbolInsertError::LimitExceeded(LimitError::ChunkTooLarge { len: data.len(), max: self.limits.max_chunk_size })); }
+        match self.manifest_parts.get(&symb.part) {
+            Some(existing) if *existing != data => Err(SymbolInsertError::ManifestPartMismatch),
+            Some(_) => Ok(()),
+            None => {
+                // The parts are only kept while they fit in the length the meta symbol gives, which the limits have bounded.
+                let len = self.manifest.as_ref().map_or(0, |manifest| manifest.len);
+                if data.is_empty() || self.manifest_bytes + data.len() > len { return Err(SymbolInsertError::ManifestTooLong); }
+                self.manifest_bytes += data.len();
+                self.manifest_parts.insert(symb.part, data);
+                Ok(())
+            },
+        }
+    }
+
+    /// Whether the sequence was signed by one of these trusted keys, by another key, or not at all, from the signature symbols read so far.
+    ///
+    /// A signature by a trusted key vouches for the name and hash of the file, so the file is only as trustworthy as the hash check in `collect_data`.
+    /// A valid signature outweighs any that do not match, which anyone could have added.
+    pub fn signature_status(&self, trusted: &[VerifyingKey]) -> SignatureStatus {
+        if let Some(key) = self.signers.iter().find(|key| trusted.contains(key)) { return SignatureStatus::Trusted(*key); }
+        match self.signers.first() {
+            Some(key) => SignatureStatus::Untrusted(*key),
+            None if self.invalid_signature => SignatureStatus::Invalid,
+            None => SignatureStatus::Unsigned,
+        }
+    }
+
+    /// The algorithm the meta symbols use for the hash of the file.
+    pub fn hash_algorithm(&self) -> HashAlgorithm { self.hash_algorithm }
+
+    /// How the file is encrypted, if it is; then `unlock` or `unlock_with_identities` must be called before it can be collected.
+    pub fn encryption(&self) -> Option<&Encryption> { self.encryption.as_ref() }
+
+    /// Derive the key to decrypt the file from this passphrase. Whether it is the right one only shows once the file is collected.
+    pub fn unlock(&mut self, passphrase: &str) -> Result<(), EncryptionError> {
+        if let Some(encryption) = &self.encryption { self.key = Some(encryption.derive_key(passphrase)?); }
+        Ok(())
+    }
+
+    /// Unwrap the key of the file with any of these identities, from any of the recipient symbols read so far.
+    pub fn unlock_with_identities(&mut self, identities: &[Identity]) -> Result<(), EncryptionError> {
+        let encryption = match &self.encryption { Some(encryption) => encryption, None => return Ok(()) };
+        if encryption.uses_passphrase() { return Err(EncryptionError::NeedsPassphrase); }
+        let file_key = self.recipients.values().find_map(|symb| identities.iter().find_map(|identity| identity.unwrap(symb)));
+        match file_key {
+            Some(file_key) => { self.key = Some(encryption.payload_key(&file_key)?); Ok(()) },
+            None => Err(EncryptionError::NoMatchingIdentity { read: self.recipients.len(), recipients: encryption.recipients.unwrap_or(0) }),
+        }
+    }
+
+    /// The name of the file this sequence encodes, as claimed by its meta symbols.
+    pub fn file_name(&self) -> &str { &self.file_name }
+
+    /// The MIME type, modification time, mode and comment of the file, if the encoder sent them.
+    pub fn metadata(&self) -> &FileMetadata { &self.metadata }
+
+    /// Whether a chunk has been loaded for every content symbol in the sequence, and every part of the manifest if it has one.
+    pub fn is_complete(&self) -> bool {
+        self.chunks.len() >= self.chunks_count && self.manifest.as_ref().is_none_or(|manifest| self.manifest_parts.len() >= manifest.parts)
+    }
+
+    /// Assemble the manifest of the directory tree, if the sequence carries one, checking it against its hash and decrypting it if the file is encrypted.
+    pub fn collect_manifest(&self) -> Result<Option<Vec<ManifestEntry>>, CollectDataError> {
+        let manifest = match &self.manifest { Some(manifest) => manifest, None => return Ok(None) };
+        let mut data = Vec::new();
+        for part in 0..manifest.parts {
+            data.extend(self.manifest_parts.get(&part).ok_or(CollectDataError::IncompleteManifest)?);
+        }
+        if data.len() != manifest.len || manifest.get_hash().ok() != Some(self.hash_algorithm.digest(&data)) { return Err(CollectDataError::ManifestHashMismatch); }
+        if let Some(encryption) = &self.encryption {
+            let key = self.key.as_ref().ok_or(CollectDataError::NoPassphrase)?;
+            data = encryption.decrypt(&key.manifest_key(), &data).map_err(CollectDataError::DecryptionFailed)?;
+        }
+        serde_json::from_slice(&data).map(Some).map_err(|error| CollectDataError::InvalidManifest(error.to_string()))
+    }
+
     /// Try to assemble a complete file out of the chunks loaded in.
-    fn collect_data(&self) -> Result<Vec<u8>, CollectDataError> {
+    pub fn collect_data(&self) -> Result<Vec<u8>, CollectDataError> {
         let mut outp = Vec::new();
         let mut keys = Vec::new();
         for key in self.chunks.keys() {
             keys.push(key);
         }
         keys.sort();
-        let mut last_key = 0;
-        for key in keys.iter() {
-            if *key - last_key > 1 { return Err(CollectDataError::DiscontinuousContentIDs); }
+        if keys.len() != self.chunks_count { return Err(CollectDataError::DiscontinuousContentIDs); }
+        for (expected, key) in keys.iter().enumerate() {
+            if **key != expected { return Err(CollectDataError::DiscontinuousContentIDs); }
             outp.extend(self.chunks.get(key).unwrap());
         }
-        let mut hasher = Sha3_256::new();
-        hasher.update(&outp);
-        let hash = hasher.finalize();
-        if hash.as_slice() != self.target_hash { return Err(CollectDataError::HashMismatch); }
-        Ok(outp)
+        if let Some(encryption) = &self.encryption {
+            // The hash is of the encrypted file, so a file that matches it and still fails to decrypt was given the wrong passphrase.
+            if self.hash_algorithm.digest(&outp) != self.target_hash { return Err(self.blame_damage(CollectDataError::HashMismatch)); }
+            let key = self.key.as_ref().ok_or(CollectDataError::NoPassphrase)?;
+            let compressed = encryption.decrypt(key, &outp).map_err(CollectDataError::DecryptionFailed)?;
+            return self.decompress(&compressed);
+        }
+        // Otherwise the hash is of the file before it was compressed.
+        match self.decompress(&outp) {
+            Ok(file) if self.hash_algorithm.digest(&file) == self.target_hash => Ok(file),
+            Err(error @ CollectDataError::LimitExceeded(_)) => Err(error),
+            Ok(_) => Err(self.blame_damage(CollectDataError::HashMismatch)),
+            Err(error) => Err(self.blame_damage(error)),
+        }
+    }
+
+    /// Decompress the file, as long as it stays within the size limit.
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, CollectDataError> {
+        let max = self.limits.max_file_size;
+        let file = self.compression.decompress(data, max).map_err(CollectDataError::DecompressionFailed)?;
+        if file.len() > max { return Err(CollectDataError::LimitExceeded(LimitError::FileTooLarge { len: file.len(), max })); }
+        Ok(file)
+    }
+
+    /// Chunks whose leaf hashes are known are intact, so if the file is damaged, the damage must be in one of the others; name them instead of this error, if there are any.
+    fn blame_damage(&self, error: CollectDataError) -> CollectDataError {
+        let unverified: Vec<usize> = (0..self.chunks_count).filter(|index| !self.verified_leaves.contains_key(index)).collect();
+        if self.merkle_root.is_some() && !unverified.is_empty() { CollectDataError::UnverifiedChunks(unverified) } else { error }
+    }
+}
+
+/// Sort symbols from anywhere in a video into their sequences, starting a decoder for each sequence once its meta symbol has been seen.
+#[derive(Default)]
+pub struct SequenceCollector {
+    decoders: HashMap<u8, SequenceDecoder>,
+    /// Symbols whose sequence's meta symbol has not been seen yet.
+    orphans: HashMap<u8, Vec<Symbol>>,
+    /// The number of symbols in `orphans`, across all sequences.
+    orphans_count: usize,
+    /// Sequences whose meta symbol was dropped, whose other symbols are dropped too.
+    refused: HashSet<u8>,
+    limits: DecoderLimits,
+}
+
+impl SequenceCollector {
+    /// Create a collector whose decoders refuse sequences that claim more than these limits allow.
+    pub fn new(limits: DecoderLimits) -> Self { SequenceCollector { limits, ..Default::default() } }
+
+    /// Add a symbol to the sequence it belongs to. Symbols that contradict the rest of their sequence are logged and dropped.
+    pub fn insert(&mut self, symb: Symbol) {
+        let seq_id = match &symb {
+            Symbol::Meta(meta) => meta.seq_id,
+            Symbol::Content(content) => content.sequence,
+            Symbol::Tree(tree) => tree.seq_id,
+            Symbol::Recipient(recipient) => recipient.seq_id,
+            Symbol::Signature(signature) => signature.seq_id,
+            Symbol::Manifest(manifest) => manifest.seq_id,
+        };
+        if self.refused.contains(&seq_id) {
+            debug!("Dropped a symbol of sequence {}, whose meta symbol was dropped", seq_id);
+            return;
+        }
+        if !self.decoders.contains_key(&seq_id) {
+            match symb {
+                Symbol::Meta(meta) => {
+                    let orphans = self.orphans.remove(&seq_id).unwrap_or_default();
+                    self.orphans_count -= orphans.len();
+                    if self.decoders.len() >= self.limits.max_sequences {
+                        warn!("Dropped the meta symbol of sequence {}: {}", seq_id, LimitError::TooManySequences { max: self.limits.max_sequences });
+                        self.refused.insert(seq_id);
+                        return;
+                    }
+                    info!("Found meta symbol for sequence {}, which encodes {:?}", seq_id, meta.name);
+                    let mut decoder = match SequenceDecoder::new(meta, self.limits) {
+                        Ok(decoder) => decoder,
+                        Err(error) => { warn!("Dropped the meta symbol of sequence {}: {}", seq_id, error); self.refused.insert(seq_id); return; },
+                    };
+                    for orphan in orphans {
+                        if let Err(error) = decoder.insert_new(orphan) { warn!("Dropped a symbol of sequence {}: {}", seq_id, error); }
+                    }
+                    self.decoders.insert(seq_id, decoder);
+                },
+                orphan => self.insert_orphan(seq_id, orphan),
+            }
+            return;
+        }
+        let decoder = self.decoders.get_mut(&seq_id).expect("decoder exists, as checked above");
+        if let Err(error) = decoder.insert_new(symb) { warn!("Dropped a symbol of sequence {}: {}", seq_id, error); }
+    }
+
+    /// Hold a symbol until the meta symbol of its sequence is seen, as long as no more symbols are held than the limits allow.
+    fn insert_orphan(&mut self, seq_id: u8, orphan: Symbol) {
+        // Only so many sequences can be decoded, so there is no use holding symbols for more of them.
+        let waiting = self.orphans.len() + if self.orphans.contains_key(&seq_id) { 0 } else { 1 };
+        let error = if self.decoders.len() + waiting > self.limits.max_sequences {
+            Some(LimitError::TooManySequences { max: self.limits.max_sequences })
+        } else if self.orphans_count >= self.limits.max_chunks {
+            Some(LimitError::TooManyOrphans { max: self.limits.max_chunks })
+        } else {
+            None
+        };
+        match error {
+            Some(error) => warn!("Dropped a symbol of sequence {}: {}", seq_id, error),
+            None => {
+                self.orphans.entry(seq_id).or_default().push(orphan);
+                self.orphans_count += 1;
+            },
+        }
+    }
+
+    /// Whether at least one sequence has been found and every sequence found has all of its chunks.
+    pub fn is_complete(&self) -> bool {
+        !self.decoders.is_empty() && self.orphans.is_empty() && self.decoders.values().all(SequenceDecoder::is_complete)
+    }
+
+    /// Stop collecting, and get the decoder of every sequence whose meta symbol was seen, ordered by sequence ID.
+    pub fn into_decoders(self) -> Vec<SequenceDecoder> {
+        for (seq_id, orphans) in self.orphans.iter() {
+            warn!("Found {} symbols of sequence {}, but never its meta symbol", orphans.len(), seq_id);
+        }
+        let mut decoders: Vec<SequenceDecoder> = self.decoders.into_values().collect();
+        decoders.sort_by_key(|decoder| decoder.sequence_id);
+        decoders
     }
 }
 
 /// Configuration for sequence encoder.
-struct SequenceEncoderConfig {
+pub struct SequenceEncoderConfig {
     /// Each symbol will be emitted this many times before moving on to the next one.
     pub persist_each_symbol_for_frames: usize,
 
-    /// At most this many bytes will be encoded in each data symbol.
-    pub max_bytes_per_data_symbol: usize,
+    /// At most this many bytes will be encoded in each data symbol. If this is `None`, as many bytes as fit in the QR code are used.
+    pub max_bytes_per_data_symbol: Option<usize>,
 
     /// After each meta symbol, there will be this many data symbols, and after that another meta symbol will be placed.
     pub data_symbols_between_meta_symbols: usize,
+
+    /// Every symbol will be rendered as a QR code of this version.
+    pub qr_version: Version,
+
+    /// Every symbol will be rendered as a QR code with this error correction level.
+    pub ecc_level: EcLevel,
+
+    /// This many symbols will be placed on each frame.
+    pub symbols_per_frame: usize,
+
+    /// Each frame will be this many pixels wide.
+    pub frame_width: u32,
+
+    /// Each frame will be this many pixels high.
+    pub frame_height: u32,
+
+    /// The symbols on a frame will be placed in a grid with this many columns and rows. If this is `None`, the grid that gives each symbol the most room is used.
+    pub grid: Option<(u32, u32)>,
+
+    /// Each symbol will be kept at least this many pixels away from the edges of its cell in the grid.
+    pub cell_margin: u32,
+
+    /// If this is set, each frame carries three grids of symbols, one in each of the red, green and blue planes.
+    ///
+    /// This triples the number of symbols per frame, but needs a video that keeps colour detail; see the README.
+    pub colour_multiplex: bool,
+
+    /// If this is set, each content symbol carries a checksum of its data, so that a damaged chunk is noticed as soon as it is read.
+    pub chunk_checksums: bool,
+
+    /// The algorithm of the hash of the whole file in the meta symbols.
+    pub hash_algorithm: HashAlgorithm,
+
+    /// The file is compressed this way before it is split into chunks, unless that does not make it any smaller.
+    pub compression: Compression,
+
+    /// If this is set, the file is encrypted with a key derived from this passphrase after it is compressed.
+    pub passphrase: Option<String>,
+
+    /// If this is not empty, the file is encrypted after it is compressed, with a key that is wrapped for each of these public keys in a recipient symbol.
+    pub recipients: Vec<Recipient>,
+
+    /// If this is set, a signature symbol carries a signature of the meta symbol with this key.
+    pub signing_key: Option<SigningKey>,
+
+    /// The MIME type, modification time, mode and comment sent in the meta symbols. They make every meta symbol larger.
+    pub metadata: FileMetadata,
+
+    /// The memory used to derive the key from the passphrase, in KiB. More memory makes each guess at the passphrase slower, for the decoder too.
+    pub kdf_memory: u32,
+
+    /// If this is set, the meta symbols carry the root of a Merkle tree over the chunks, and tree symbols carry the hashes to check each chunk against it.
+    ///
+    /// The tree is left out if its symbols, or the meta symbol with the root added, do not fit in the QR code.
+    pub merkle_tree: bool,
 }
 
 
 impl SequenceEncoderConfig {
-    fn new() -> Self { Default::default() }
+    pub fn new() -> Self { Default::default() }
+
+    /// The number of colour planes that carry their own grid of symbols.
+    pub fn planes_per_frame(&self) -> usize { if self.colour_multiplex { 3 } else { 1 } }
+
+    /// Get the arrangement of symbols on each frame (or on each colour plane of a frame), checking that the configured number of symbols fits.
+    pub fn frame_layout(&self) -> Result<FrameLayout, LayoutError> {
+        let layout = match self.grid {
+            Some((columns, rows)) => FrameLayout::with_grid(self.frame_width, self.frame_height, columns, rows, self.cell_margin),
+            None => FrameLayout::fit(self.frame_width, self.frame_height, self.symbols_per_frame, self.cell_margin)?,
+        };
+        layout.check_capacity(self.symbols_per_frame)?;
+        Ok(layout)
+    }
+
+    /// Get the largest number of data bytes that fit in a content symbol, given how many content symbols there will be.
+    fn max_chunk_size_for_count(&self, chunks_count: usize) -> Result<usize, SequenceEncoderError> {
+        let capacity = match byte_capacity(self.qr_version, self.ecc_level) {
+            Ok(capacity) => capacity,
+            Err(error) => { return Err(SequenceEncoderError::InvalidQrSettings(error)); },
+        };
+        let last_index = chunks_count.saturating_sub(1);
+        let mut header_len = ContentSymbol::header_len(last_index);
+        if self.chunk_checksums { header_len += ContentSymbol::CHECKSUM_LEN; }
+        let base64_len = capacity.saturating_sub(header_len);
+        Ok(base64_len / 4 * 3) // every 3 bytes of data become 4 characters of Base64
+    }
+
+    /// Work out how many bytes of a file of this length go into each content symbol, checking that they fit in the QR code.
+    pub fn chunk_size(&self, data_len: usize) -> Result<usize, SequenceEncoderError> {
+        match self.max_bytes_per_data_symbol {
+            Some(0) => Err(SequenceEncoderError::ZeroChunkSize),
+            Some(requested) => {
+                let max = self.max_chunk_size_for_count(chunks_count(data_len, requested))?;
+                if requested > max { return Err(SequenceEncoderError::ChunkTooLarge { requested, max }); }
+                Ok(requested)
+            },
+            None => {
+                // Larger chunk indices take more hex digits in the header, which leaves less room for data,
+                // which means more chunks. Start optimistic and grow the header until it is large enough.
+                let mut assumed_count = 1;
+                loop {
+                    let size = self.max_chunk_size_for_count(assumed_count)?;
+                    if size == 0 { return Err(SequenceEncoderError::NoRoomForData); }
+                    let count = chunks_count(data_len, size);
+                    if ContentSymbol::header_len(count.saturating_sub(1)) <= ContentSymbol::header_len(assumed_count.saturating_sub(1)) {
+                        return Ok(size);
+                    }
+                    assumed_count = count;
+                }
+            },
+        }
+    }
 }
 
 impl Default for SequenceEncoderConfig {
     fn default() -> Self {
         SequenceEncoderConfig {
             persist_each_symbol_for_frames: 1,
-            max_bytes_per_data_symbol: 4096,
+            max_bytes_per_data_symbol: None,
             data_symbols_between_meta_symbols: 20,
+            qr_version: Version::Normal(40),
+            ecc_level: EcLevel::M,
+            symbols_per_frame: 1,
+            frame_width: 1920,
+            frame_height: 1080,
+            grid: None,
+            cell_margin: 16,
+            colour_multiplex: false,
+            chunk_checksums: true,
+            hash_algorithm: HashAlgorithm::Sha3_256,
+            compression: Compression::Zstd,
+            passphrase: None,
+            recipients: Vec::new(),
+            signing_key: None,
+            metadata: FileMetadata::default(),
+            kdf_memory: encryption::DEFAULT_KDF_MEMORY,
+            merkle_tree: true,
         }
     }
 }
 
-/* COMMENTED OUT BECAUSE I CAN'T FIGURE OUT HOW TO MAKE THIS WORK 
-struct SequenceEncoder {
-    sequence_id: u8,
-    data: Vec<u8>,
-    config: Option<SequenceEncoderConfig>,
-    current_frame: Option<usize>,
-    data_index: usize,
-    cur_data_chunk_count: usize,
-    hash_cached: Option<String>,
+/// Get the number of chunks of at most this size that it takes to hold this many bytes.
+fn chunks_count(data_len: usize, chunk_size: usize) -> usize {
+    data_len.div_ceil(chunk_size)
+}
+
+/// Errors that may occur while preparing to encode a sequence.
+#[derive(Error, Debug)]
+pub enum SequenceEncoderError {
+    /// The QR code version or error correction level cannot be used.
+    #[error("the QR code settings are not usable: {0}")]
+    InvalidQrSettings(QrEncodeError),
+
+    /// The user asked for chunks of zero bytes.
+    #[error("the number of bytes per content symbol must be at least 1")]
+    ZeroChunkSize,
+
+    /// The user asked for chunks that are too large to fit in the QR code once the content symbol's header and Base64 are added.
+    #[error("a content symbol with {requested} bytes of data does not fit in the QR code; at most {max} bytes per symbol fit with these QR code settings")]
+    ChunkTooLarge { requested: usize, max: usize },
+
+    /// The QR code is so small that not even a single byte of data fits next to the content symbol's header.
+    #[error("the QR code is too small to hold any data in a content symbol")]
+    NoRoomForData,
+
+    /// The meta symbol, which is not split into chunks, does not fit in the QR code.
+    #[error("the meta symbol takes {needed} bytes, but only {capacity} bytes fit in the QR code; try a shorter file name or a larger QR code")]
+    MetaTooLarge { needed: usize, capacity: usize },
+
+    /// There must be at least one content symbol between meta symbols, or the sequence would never end.
+    #[error("there must be at least one data symbol between meta symbols")]
+    NoDataSymbolsBetweenMeta,
+
+    /// The symbols cannot be arranged on the frame as configured.
+    #[error("the symbols cannot be placed on the frame: {0}")]
+    InvalidLayout(LayoutError),
+
+    /// Each symbol must be shown for at least one frame.
+    #[error("each symbol must be shown for at least one frame")]
+    ZeroPersistence,
+
+    /// A file can be encrypted with a passphrase or to recipients, but not both.
+    #[error("the file can be encrypted with a passphrase or to recipients, but not both")]
+    PassphraseAndRecipients,
+
+    /// The QR code is so small that not even a single byte of the manifest fits next to the manifest symbol's header.
+    #[error("the QR code is too small to hold any of the manifest in a manifest symbol")]
+    NoRoomForManifest,
+
+    /// The signature symbol does not fit in the QR code.
+    #[error("the signature symbol takes {needed} bytes, but only {capacity} bytes fit in the QR code; try a larger QR code")]
+    SignatureTooLarge { needed: usize, capacity: usize },
+
+    /// A recipient symbol does not fit in the QR code.
+    #[error("a recipient symbol takes {needed} bytes, but only {capacity} bytes fit in the QR code; try a larger QR code")]
+    RecipientTooLarge { needed: usize, capacity: usize },
+
+    /// The key could not be derived from the passphrase.
+    #[error("the file cannot be encrypted: {0}")]
+    Encryption(EncryptionError),
 }
 
-enum SequenceEncoderConfigSetError {
-    AlreadyIterating,
+/// Encode a file into a sequence of symbols.
+pub struct SequenceEncoder {
+    sequence_id: u8,
+    /// The file as it is carried by the content symbols, after compression.
+    data: Vec<u8>,
+    /// How the file was actually compressed, which may be `Compression::None` even if the configuration asked for something else.
+    compression: Compression,
+    /// How the file was encrypted after it was compressed, if it was.
+    encryption: Option<Encryption>,
+    /// The key of the file wrapped for each recipient, if it was encrypted to recipients.
+    recipient_symbols: Vec<RecipientSymbol>,
+    /// The signature of the meta symbol, if the file is signed. It is made once the meta symbol is settled.
+    signature_symbol: Option<SignatureSymbol>,
+    /// Where to find the manifest, if this is a directory tree, and the symbols that carry it.
+    manifest: Option<ManifestInfo>,
+    manifest_symbols: Vec<ManifestSymbol>,
+    file_name: String,
+    config: SequenceEncoderConfig,
+    chunk_size: usize,
+    hash: String,
+    /// The Merkle tree, if there is one: every level of it, from the leaf hashes of the chunks up to the root, and the level of the blocks each tree symbol covers.
+    tree: Option<(Vec<Vec<merkle::Hash>>, u32)>,
+    current_frame: usize,
 }
 
 impl SequenceEncoder {
-    pub fn new<T: AsRef<[u8]>>(data: T) -> SequenceEncoder {
-        SequenceEncoder { sequence_id: rand::random(), data: data.as_ref().to_vec(), config: None, current_frame: None, data_index: 0, cur_data_chunk_count: 0, hash_cached: None}
+    /// Create an encoder for this data, checking up front that every symbol it will produce fits in the configured QR code.
+    pub fn new<T: AsRef<[u8]>>(data: T, file_name: String, config: SequenceEncoderConfig) -> Result<SequenceEncoder, SequenceEncoderError> {
+        SequenceEncoder::with_manifest(data.as_ref(), file_name, None, config)
     }
 
-    pub fn set_config(&mut self, config: SequenceEncoderConfig) -> Result<(), SequenceEncoderConfigSetError> {
-        if self.current_frame.is_some() { return Err(SequenceEncoderConfigSetError::AlreadyIterating); }
-        self.config = Some(config);
-        Ok(())
+    /// Create an encoder for a directory tree: the concatenated contents of its files, which are listed in this manifest, and the name of its root.
+    pub fn new_archive(data: &[u8], entries: &[ManifestEntry], dir_name: String, config: SequenceEncoderConfig) -> Result<SequenceEncoder, SequenceEncoderError> {
+        let manifest = serde_json::to_vec(entries).expect("JSON serialization failed?!");
+        SequenceEncoder::with_manifest(data, dir_name, Some(manifest), config)
+    }
+
+    fn with_manifest(data: &[u8], file_name: String, manifest: Option<Vec<u8>>, config: SequenceEncoderConfig) -> Result<SequenceEncoder, SequenceEncoderError> {
+        if config.data_symbols_between_meta_symbols == 0 { return Err(SequenceEncoderError::NoDataSymbolsBetweenMeta); }
+        if config.persist_each_symbol_for_frames == 0 { return Err(SequenceEncoderError::ZeroPersistence); }
+        let layout = config.frame_layout().map_err(SequenceEncoderError::InvalidLayout)?;
+        layout.scale_for(config.qr_version.width() as u32 + 8).map_err(SequenceEncoderError::InvalidLayout)?;
+        if config.passphrase.is_some() && !config.recipients.is_empty() { return Err(SequenceEncoderError::PassphraseAndRecipients); }
+        let sequence_id = rand::random();
+        let original = data;
+        let (compression, data) = config.compression.compress_if_smaller(original);
+        if compression != config.compression { debug!("{} compression does not make this file smaller, so it is sent as it is", config.compression); }
+        let mut recipient_symbols = Vec::new();
+        let encryption = if let Some(passphrase) = &config.passphrase {
+            let encryption = Encryption::generate(config.kdf_memory);
+            let key = encryption.derive_key(passphrase).map_err(SequenceEncoderError::Encryption)?;
+            Some((encryption, key))
+        } else if !config.recipients.is_empty() {
+            let encryption = Encryption::for_recipients(config.recipients.len());
+            let file_key = FileKey::generate();
+            recipient_symbols = config.recipients.iter().enumerate().map(|(index, recipient)| recipient.wrap(&file_key, sequence_id, index)).collect();
+            let key = encryption.payload_key(&file_key).map_err(SequenceEncoderError::Encryption)?;
+            Some((encryption, key))
+        } else {
+            None
+        };
+        let (encryption, data, hash, manifest) = match encryption {
+            Some((encryption, key)) => {
+                let data = encryption.encrypt(&key, &data).map_err(SequenceEncoderError::Encryption)?;
+                // The hash of the original file would let anyone check a guess at its contents, so the hash is of the encrypted file.
+                let hash = hex::encode(config.hash_algorithm.digest(&data));
+                // The manifest names the files and holds their hashes, so it is encrypted too.
+                let manifest = manifest.map(|manifest| encryption.encrypt(&key.manifest_key(), &manifest)).transpose().map_err(SequenceEncoderError::Encryption)?;
+                (Some(encryption), data, hash, manifest)
+            },
+            None => {
+                let hash = hex::encode(config.hash_algorithm.digest(original));
+                (None, data, hash, manifest)
+            },
+        };
+        let chunk_size = config.chunk_size(data.len())?;
+        let capacity = byte_capacity(config.qr_version, config.ecc_level).map_err(SequenceEncoderError::InvalidQrSettings)?;
+        let (manifest, manifest_symbols) = match manifest {
+            Some(manifest) => {
+                // The header is longest with the largest part number, which is less than the number of bytes.
+                let header = ManifestSymbol::new(sequence_id, manifest.len(), &[]).to_str().len();
+                let part_len = capacity.saturating_sub(header) / 4 * 3;
+                if part_len == 0 { return Err(SequenceEncoderError::NoRoomForManifest); }
+                let symbols: Vec<ManifestSymbol> = manifest.chunks(part_len).enumerate().map(|(part, data)| ManifestSymbol::new(sequence_id, part, data)).collect();
+                let info = ManifestInfo { parts: symbols.len(), len: manifest.len(), hash: hex::encode(config.hash_algorithm.digest(&manifest)) };
+                (Some(info), symbols)
+            },
+            None => (None, Vec::new()),
+        };
+        let mut enc = SequenceEncoder { sequence_id, data, compression, encryption, recipient_symbols, signature_symbol: None, manifest, manifest_symbols, file_name, config, chunk_size, hash, tree: None, current_frame: 0 };
+        if enc.config.merkle_tree { enc.plan_tree(capacity); }
+
+        // The meta symbol is longest when its frame numbers are largest.
+        let needed = enc.meta_symbol(enc.total_len().saturating_sub(1)).to_str().len();
+        if needed > capacity { return Err(SequenceEncoderError::MetaTooLarge { needed, capacity }); }
+        if let Some(needed) = enc.recipient_symbols.iter().map(|symb| symb.to_str().len()).max() {
+            if needed > capacity { return Err(SequenceEncoderError::RecipientTooLarge { needed, capacity }); }
+        }
+        // The signed message leaves out the frame numbers, so the signature does not depend on where it is placed.
+        enc.signature_symbol = enc.config.signing_key.as_ref().map(|key| key.sign(&enc.meta_symbol(0)));
+        if let Some(symb) = &enc.signature_symbol {
+            let needed = symb.to_str().len();
+            if needed > capacity { return Err(SequenceEncoderError::SignatureTooLarge { needed, capacity }); }
+        }
+        Ok(enc)
     }
 
-    pub fn new_with_config<T: AsRef<[u8]>>(data: T, config: SequenceEncoderConfig) -> Self {
-        let mut enc = Self::new(data);
-        enc.set_config(config);
-        enc
+    /// Build the Merkle tree over the chunks, with blocks as large as fit in a tree symbol, unless no tree symbol or no meta symbol with the root fits in this many bytes.
+    fn plan_tree(&mut self, capacity: usize) {
+        let leaves: Vec<merkle::Hash> = self.data.chunks(self.chunk_size).map(merkle::leaf_hash).collect();
+        let count = leaves.len();
+        if count == 0 { return; }
+        // The levels are worked out once, as every meta symbol needs the root and every tree symbol needs a path.
+        let levels = merkle::levels(&leaves);
+        let mut level = None;
+        for candidate in 0..usize::BITS {
+            let block_len = 1usize << candidate;
+            // The first block has a partner at every level, so its path is the longest, and the last block has the longest index.
+            let longest = TreeSymbol::new(self.sequence_id, candidate, (count - 1) / block_len * block_len, &leaves[..block_len.min(count)], &merkle::auth_path_in_levels(&levels, candidate as usize, 0));
+            if longest.to_str().len() > capacity { break; }
+            level = Some(candidate);
+            if block_len >= count { break; }
+        }
+        let level = match level {
+            Some(level) => level,
+            None => { warn!("Leaving out the Merkle tree, because not even the hash of one chunk fits in a tree symbol"); return; },
+        };
+        self.tree = Some((levels, level));
+        let needed = self.meta_symbol(self.total_len().saturating_sub(1)).to_str().len();
+        if needed > capacity {
+            warn!("Leaving out the Merkle tree, because the meta symbol does not fit in the QR code with its root");
+            self.tree = None;
+        }
     }
 
-    pub fn reset_iterator(&mut self) {
-         self.current_frame = None;
-         self.data_index = 0;
-         self.cur_data_chunk_count = 0;
-         self.hash_cached = None;
+    /// The number of bytes of data in each content symbol (except possibly the last one).
+    pub fn chunk_size(&self) -> usize { self.chunk_size }
+
+    /// The total number of symbols in this sequence: meta, content, tree, recipient, signature and manifest symbols.
+    pub fn total_len(&self) -> usize {
+        let metas = chunks_count(self.data_symbols_count(), self.config.data_symbols_between_meta_symbols).max(1);
+        self.data_symbols_count() + metas * (1 + self.header_symbols_count())
     }
-  
 
-    fn total_len(&self) -> usize { unimplemented!(); }
-    fn data_chunks_count(&self) -> usize { unimplemented!(); }
-    
-    fn get_config(&self) -> &SequenceEncoderConfig {
-        match &self.config {
-            Some(config) => config,
-            None => panic!("got to part of code which believes config is present, while it is actually not")
+    /// The number of symbols that follow every meta symbol.
+    fn header_symbols_count(&self) -> usize { self.recipient_symbols.len() + self.signature_symbols_count() + self.manifest_symbols.len() }
+
+    /// The number of symbols that are sent once, between the groups of meta and header symbols.
+    fn data_symbols_count(&self) -> usize { self.data_chunks_count() + self.tree_symbols_count() }
+
+    /// The number of tree symbols in this sequence, one before each block of chunks.
+    pub fn tree_symbols_count(&self) -> usize {
+        match self.tree {
+            Some((_, level)) => chunks_count(self.data_chunks_count(), 1 << level),
+            None => 0,
         }
     }
 
-    fn get_next_chunk(&mut self) -> &[u8] {
-        let chunk = &self.data[self.data_index ..= self.data_index + self.get_config().max_bytes_per_data_symbol];
-        self.data_index += self.get_config().max_bytes_per_data_symbol;
-        self.cur_data_chunk_count += 1;
-        chunk
+    /// The number of signature symbols in this sequence, which is known before the signature is made.
+    fn signature_symbols_count(&self) -> usize { if self.config.signing_key.is_some() { 1 } else { 0 } }
+
+    /// The number of content symbols in this sequence.
+    pub fn data_chunks_count(&self) -> usize { chunks_count(self.data.len(), self.chunk_size) }
+
+    /// The number of video frames this sequence takes up, counting repeated frames.
+    pub fn frames_count(&self) -> usize {
+        chunks_count(self.total_len(), self.config.symbols_per_frame * self.config.planes_per_frame()) * self.config.persist_each_symbol_for_frames
+    }
+
+    /// Group the remaining symbols into frames, as configured, repeating each frame if it should persist.
+    ///
+    /// With colour multiplexing, each frame has the symbols for the red plane first, then green, then blue.
+    pub fn into_frames(self) -> impl Iterator<Item = Vec<Symbol>> {
+        let symbols_per_frame = self.config.symbols_per_frame * self.config.planes_per_frame();
+        let persist = self.config.persist_each_symbol_for_frames;
+        let mut symbols = self;
+        iter::from_fn(move || {
+            let frame: Vec<Symbol> = symbols.by_ref().take(symbols_per_frame).collect();
+            if frame.is_empty() { None } else { Some(frame) }
+        }).flat_map(move |frame| iter::repeat_n(frame, persist))
     }
 
-    pub fn get_hash(&mut self) -> &str {
-        let return_value = match &self.hash_cached {
-            Some(hash) => &hash,
-            None => {
-                let mut hasher = Sha3_256::new();
-                hasher.update(&self.data);
-                let digest = hasher.finalize();
-                &hex::encode(digest)
-            }
+    fn meta_symbol(&self, cur_frame: usize) -> MetaSymbol {
+        let merkle_root = self.tree.as_ref().and_then(|(levels, _)| merkle::root_of_levels(levels)).map(hex::encode);
+        // SHA3-256 is left unnamed, so that versions that only know it can still read the sequence.
+        let hash_alg = match self.config.hash_algorithm {
+            HashAlgorithm::Sha3_256 => None,
+            algorithm => Some(algorithm.name().to_string()),
         };
-        self.hash_cached = Some(return_value.to_string());
-        return_value
+        let compression = match self.compression {
+            Compression::None => None,
+            compression => Some(compression.name().to_string()),
+        };
+        let meta = MetaSymbol { ver: 0, seq_id: self.sequence_id, frames: self.total_len(), cur_frame, content_len: vec![self.data.len(), self.data_chunks_count()], sha3: self.hash.clone(), name: self.file_name.clone(), hash_alg, compression, encryption: self.encryption.clone(), merkle_root, manifest: self.manifest.clone(), metadata: self.config.metadata.clone(), extra: Default::default() };
+        // The version is only raised when it has to be, so that older decoders still read what they can.
+        MetaSymbol { ver: meta.required_version(), ..meta }
+    }
+
+    fn content_symbol(&self, index: usize) -> ContentSymbol {
+        let start = index * self.chunk_size;
+        let end = (start + self.chunk_size).min(self.data.len());
+        let data = self.data[start..end].to_vec();
+        if self.config.chunk_checksums {
+            ContentSymbol::with_checksum(self.sequence_id, index, data)
+        } else {
+            ContentSymbol { sequence: self.sequence_id, index, data, checksum: None }
+        }
     }
 }
 
 impl Iterator for SequenceEncoder {
     type Item = Symbol;
     fn next(&mut self) -> Option<Symbol> {
-        if self.config.is_none() { return None; } // TODO: maybe panic instead?
-        let conf = self.get_config();
-        if self.current_frame.is_none() {
-            self.current_frame = Some(0);
-        }
-        let cur_frame = self.current_frame.unwrap();
-        if cur_frame % (conf.data_symbols_between_meta_symbols+1) == 0 {
-            let meta = MetaSymbol { ver: 0, seq_id: self.sequence_id, frames: self.total_len(), cur_frame: cur_frame, content_len: vec![self.data.len(), self.data_chunks_count()], sha3: self.get_hash().to_string(), name: "data.bin".to_string() };
-            meta.validate();
-            Some(Symbol::Meta(meta))
+        if self.current_frame >= self.total_len() { return None; }
+        let cur_frame = self.current_frame;
+        self.current_frame += 1;
+        let between = self.config.data_symbols_between_meta_symbols;
+        let header = self.header_symbols_count();
+        let (group, offset) = (cur_frame / (1 + header + between), cur_frame % (1 + header + between));
+        if offset == 0 {
+            Some(Symbol::Meta(self.meta_symbol(cur_frame)))
+        } else if offset <= self.recipient_symbols.len() {
+            // The recipient symbols follow every meta symbol, so that a decoder that starts partway through can still check whether it holds a matching identity.
+            Some(Symbol::Recipient(self.recipient_symbols[offset - 1].clone()))
+        } else if offset <= self.recipient_symbols.len() + self.signature_symbols_count() {
+            // Then the signature, so that such a decoder can also tell whether the file comes from someone it trusts.
+            self.signature_symbol.clone().map(Symbol::Signature)
+        } else if offset <= header {
+            // Then the manifest, which says what the content symbols that follow hold.
+            Some(Symbol::Manifest(self.manifest_symbols[offset - 1 - self.recipient_symbols.len() - self.signature_symbols_count()].clone()))
         } else {
-            let chunk = self.get_next_chunk();
-            let content = ContentSymbol { sequence: self.sequence_id, index: self.cur_data_chunk_count-1, data: chunk.to_vec() };
-            Some(Symbol::Content(content))
+            let item = group * between + offset - 1 - header;
+            match &self.tree {
+                None => Some(Symbol::Content(self.content_symbol(item))),
+                Some((levels, level)) => {
+                    // Each block of chunks comes right after the tree symbol covering it.
+                    let block_len = 1usize << level;
+                    let (block, offset) = (item / (block_len + 1), item % (block_len + 1));
+                    if offset == 0 {
+                        let leaves = &levels[0];
+                        let first = block * block_len;
+                        let end = (first + block_len).min(leaves.len());
+                        Some(Symbol::Tree(TreeSymbol::new(self.sequence_id, *level, first, &leaves[first..end], &merkle::auth_path_in_levels(levels, *level as usize, block))))
+                    } else {
+                        Some(Symbol::Content(self.content_symbol(block * block_len + offset - 1)))
+                    }
+                },
+            }
         }
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.total_len() - self.current_frame;
+        (remaining, Some(remaining))
+    }
 }
 
-impl ExactSizeIterator for SequenceEncoder {
-    fn len(&self) -> usize { unimplemented!(); }
+impl ExactSizeIterator for SequenceEncoder {}
+
+#[cfg(test)]
+mod tests {
+    use crate::sequence::*;
+    use crate::qr_writer::symbol_to_qrcode_with_version;
+
+    /// Get the meta symbol a sequence starts with.
+    fn first_meta(symbols: &[Symbol]) -> MetaSymbol {
+        match &symbols[0] { Symbol::Meta(meta) => meta.clone(), _ => panic!("first symbol is not meta") }
+    }
+
+    #[test]
+    fn test_default_config_fits_in_qr_code() {
+        let data = vec![0x55; 10000];
+        let enc = SequenceEncoder::new(&data, "test.bin".to_string(), SequenceEncoderConfig::new()).unwrap();
+        let (version, ec_level) = (enc.config.qr_version, enc.config.ecc_level);
+        for symb in enc {
+            assert!(symbol_to_qrcode_with_version(&symb, version, ec_level, 1).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_chunk_size_is_largest_that_fits() {
+        let mut config = SequenceEncoderConfig::new();
+        config.qr_version = Version::Normal(10);
+        config.ecc_level = EcLevel::L;
+        let auto_size = config.chunk_size(100000).unwrap();
+        config.max_bytes_per_data_symbol = Some(auto_size);
+        assert_eq!(config.chunk_size(100000).unwrap(), auto_size);
+        config.max_bytes_per_data_symbol = Some(auto_size + 1);
+        match config.chunk_size(100000) {
+            Err(SequenceEncoderError::ChunkTooLarge { requested, max }) => { assert_eq!(requested, auto_size+1); assert_eq!(max, auto_size); },
+            other => panic!("expected ChunkTooLarge, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_oversized_chunk_rejected_up_front() {
+        let mut config = SequenceEncoderConfig::new();
+        config.max_bytes_per_data_symbol = Some(4096);
+        assert!(matches!(SequenceEncoder::new(b"data", "test.bin".to_string(), config), Err(SequenceEncoderError::ChunkTooLarge { .. })));
+    }
+
+    #[test]
+    fn test_encode_decode_roundtrip() {
+        let data: Vec<u8> = (0..5000).map(|i| (i * 7 % 256) as u8).collect();
+        let mut config = SequenceEncoderConfig::new();
+        config.qr_version = Version::Normal(10);
+        config.data_symbols_between_meta_symbols = 3;
+        let enc = SequenceEncoder::new(&data, "test.bin".to_string(), config).unwrap();
+        let total = enc.total_len();
+        let symbols: Vec<Symbol> = enc.collect();
+        assert_eq!(symbols.len(), total);
+        let mut decoder = SequenceDecoder::new(first_meta(&symbols), DecoderLimits::default()).unwrap();
+        for symb in symbols { decoder.insert_new(symb).unwrap(); }
+        assert_eq!(decoder.collect_data().unwrap(), data);
+    }
+
+    #[test]
+    fn test_collector_accepts_content_before_meta() {
+        let data: Vec<u8> = (0..3000).map(|i| (i * 13 % 256) as u8).collect();
+        let mut config = SequenceEncoderConfig::new();
+        config.qr_version = Version::Normal(10);
+        config.data_symbols_between_meta_symbols = 5;
+        let mut symbols: Vec<Symbol> = SequenceEncoder::new(&data, "test.bin".to_string(), config).unwrap().collect();
+        symbols.rotate_left(3); // start reading in the middle of the sequence
+        let mut collector = SequenceCollector::new(DecoderLimits::default());
+        for symb in symbols {
+            assert!(!collector.is_complete());
+            collector.insert(symb);
+        }
+        assert!(collector.is_complete());
+        let decoders = collector.into_decoders();
+        assert_eq!(decoders.len(), 1);
+        assert_eq!(decoders[0].file_name(), "test.bin");
+        assert_eq!(decoders[0].collect_data().unwrap(), data);
+    }
+
+    #[test]
+    fn test_frames_group_and_repeat_symbols() {
+        let mut config = SequenceEncoderConfig::new();
+        config.max_bytes_per_data_symbol = Some(10);
+        config.symbols_per_frame = 4;
+        config.persist_each_symbol_for_frames = 2;
+        config.merkle_tree = false;
+        config.compression = Compression::None;
+        let enc = SequenceEncoder::new(vec![0; 95], "test.bin".to_string(), config).unwrap();
+        let total = enc.total_len(); // 10 content symbols and 1 meta symbol
+        let frames_count = enc.frames_count();
+        let frames: Vec<Vec<Symbol>> = enc.into_frames().collect();
+        assert_eq!(total, 11);
+        assert_eq!(frames.len(), frames_count);
+        assert_eq!(frames.iter().map(|f| f.len()).collect::<Vec<_>>(), vec![4, 4, 4, 4, 3, 3]);
+        assert_eq!(frames[0], frames[1]);
+        assert_ne!(frames[1], frames[2]);
+    }
+
+    #[test]
+    fn test_colour_multiplex_triples_symbols_per_frame() {
+        let mut config = SequenceEncoderConfig::new();
+        config.max_bytes_per_data_symbol = Some(10);
+        config.symbols_per_frame = 2;
+        config.colour_multiplex = true;
+        config.merkle_tree = false;
+        config.compression = Compression::None;
+        let enc = SequenceEncoder::new(vec![0; 95], "test.bin".to_string(), config).unwrap();
+        assert_eq!(enc.frames_count(), 2);
+        let frames: Vec<Vec<Symbol>> = enc.into_frames().collect();
+        assert_eq!(frames.iter().map(|f| f.len()).collect::<Vec<_>>(), vec![6, 5]);
+    }
+
+    #[test]
+    fn test_too_many_symbols_per_frame_rejected() {
+        let mut config = SequenceEncoderConfig::new();
+        config.symbols_per_frame = 40;
+        assert!(matches!(SequenceEncoder::new(b"data", "test.bin".to_string(), config), Err(SequenceEncoderError::InvalidLayout(LayoutError::SymbolTooLarge { .. }))));
+    }
+
+    #[test]
+    fn test_damaged_chunk_is_rejected_until_intact_copy_arrives() {
+        let data: Vec<u8> = (0..300).map(|i| (i * 11 % 256) as u8).collect();
+        let mut config = SequenceEncoderConfig::new();
+        config.max_bytes_per_data_symbol = Some(100);
+        let symbols: Vec<Symbol> = SequenceEncoder::new(&data, "test.bin".to_string(), config).unwrap().collect();
+        let mut decoder = SequenceDecoder::new(first_meta(&symbols), DecoderLimits::default()).unwrap();
+        let mut damaged = symbols.iter().find_map(|symb| match symb { Symbol::Content(content) => Some(content.clone()), _ => None }).unwrap();
+        damaged.data[10] ^= 0x40;
+        assert!(matches!(decoder.insert_new(Symbol::Content(damaged)), Err(SymbolInsertError::ChecksumMismatch)));
+        for symb in symbols { decoder.insert_new(symb).unwrap(); }
+        assert_eq!(decoder.collect_data().unwrap(), data);
+    }
+
+    #[test]
+    fn test_chunks_without_checksums_are_accepted() {
+        let data = vec![0x42; 1000];
+        let mut config = SequenceEncoderConfig::new();
+        config.qr_version = Version::Normal(10);
+        let with_checksums = config.chunk_size(data.len()).unwrap();
+        config.chunk_checksums = false;
+        assert!(config.chunk_size(data.len()).unwrap() > with_checksums);
+        let symbols: Vec<Symbol> = SequenceEncoder::new(&data, "test.bin".to_string(), config).unwrap().collect();
+        assert!(symbols.iter().all(|symb| !matches!(symb, Symbol::Content(ContentSymbol { checksum: Some(_), .. }))));
+        let mut decoder = SequenceDecoder::new(first_meta(&symbols), DecoderLimits::default()).unwrap();
+        for symb in symbols { decoder.insert_new(symb).unwrap(); }
+        assert_eq!(decoder.collect_data().unwrap(), data);
+    }
+
+    /// Encode this data in chunks of 10 bytes, and split the symbols into the meta symbol, the tree symbols and the content symbols.
+    fn tree_sequence(data: &[u8]) -> (MetaSymbol, Vec<TreeSymbol>, Vec<ContentSymbol>) {
+        let mut config = SequenceEncoderConfig::new();
+        config.max_bytes_per_data_symbol = Some(10);
+        config.qr_version = Version::Normal(12);
+        config.ecc_level = EcLevel::L;
+        config.compression = Compression::None;
+        let (mut meta, mut trees, mut contents) = (None, Vec::new(), Vec::new());
+        for symb in SequenceEncoder::new(data, "test.bin".to_string(), config).unwrap() {
+            match symb {
+                Symbol::Meta(symb) => meta = Some(symb),
+                Symbol::Tree(symb) => trees.push(symb),
+                Symbol::Content(symb) => contents.push(symb),
+                Symbol::Recipient(_) | Symbol::Signature(_) | Symbol::Manifest(_) => panic!("the sequence is neither encrypted, signed nor an archive"),
+            }
+        }
+        (meta.unwrap(), trees, contents)
+    }
+
+    #[test]
+    fn test_tree_symbols_cover_every_chunk() {
+        let data: Vec<u8> = (0..255).collect();
+        let (meta, trees, contents) = tree_sequence(&data);
+        assert!(meta.merkle_root.is_some());
+        assert_eq!(contents.len(), 26);
+        // A version 12 QR code has room for the hashes of four chunks, so there is a tree symbol before every four content symbols.
+        assert!(trees.iter().all(|tree| tree.level == 2));
+        assert_eq!(trees.iter().map(|tree| tree.first).collect::<Vec<_>>(), (0..26).step_by(4).collect::<Vec<_>>());
+        let mut decoder = SequenceDecoder::new(meta, DecoderLimits::default()).unwrap();
+        for tree in trees { decoder.insert_new(Symbol::Tree(tree)).unwrap(); }
+        assert_eq!(decoder.verified_leaves.len(), 26);
+        for content in contents { decoder.insert_new(Symbol::Content(content)).unwrap(); }
+        assert_eq!(decoder.collect_data().unwrap(), data);
+    }
+
+    #[test]
+    fn test_tree_pinpoints_damaged_chunks() {
+        let data: Vec<u8> = (0..255).collect();
+        let (meta, trees, contents) = tree_sequence(&data);
+        // Damage that the checksum cannot catch, because it was recomputed.
+        let forge = |content: &ContentSymbol| { let mut data = content.data.clone(); data[0] ^= 1; ContentSymbol::with_checksum(content.sequence, content.index, data) };
+
+        let mut decoder = SequenceDecoder::new(meta.clone(), DecoderLimits::default()).unwrap();
+        decoder.insert_new(Symbol::Content(forge(&contents[5]))).unwrap();
+        // The tree symbol for chunks 4 to 7 shows that chunk 5 was damaged, so it is dropped.
+        decoder.insert_new(Symbol::Tree(trees[1].clone())).unwrap();
+        assert!(!decoder.chunks.contains_key(&5));
+        assert!(matches!(decoder.insert_new(Symbol::Content(forge(&contents[4]))), Err(SymbolInsertError::NotInMerkleTree(4))));
+        for content in contents.iter() { decoder.insert_new(Symbol::Content(content.clone())).unwrap(); }
+        assert_eq!(decoder.collect_data().unwrap(), data);
+
+        // Without tree symbols, the damage can only be narrowed down to the chunks they would have covered.
+        let mut decoder = SequenceDecoder::new(meta, DecoderLimits::default()).unwrap();
+        for tree in trees.iter().filter(|tree| tree.first != 12) { decoder.insert_new(Symbol::Tree(tree.clone())).unwrap(); }
+        for content in contents.iter() {
+            let content = if content.index == 13 { forge(content) } else { content.clone() };
+            decoder.insert_new(Symbol::Content(content)).unwrap();
+        }
+        assert!(matches!(decoder.collect_data(), Err(CollectDataError::UnverifiedChunks(ref chunks)) if *chunks == vec![12, 13, 14, 15]));
+
+        let mut forged_tree = trees[0].clone();
+        forged_tree.leaves = TreeSymbol::new(0, 2, 0, &[[0; 32]; 4], &[]).leaves;
+        assert!(matches!(decoder.insert_new(Symbol::Tree(forged_tree)), Err(SymbolInsertError::TreeMismatch)));
+    }
+
+    #[test]
+    fn test_decoder_uses_declared_hash_algorithm() {
+        let data: Vec<u8> = (0..500).map(|i| (i * 3 % 256) as u8).collect();
+        for &algorithm in [HashAlgorithm::Sha3_256, HashAlgorithm::Sha256, HashAlgorithm::Blake3].iter() {
+            let mut config = SequenceEncoderConfig::new();
+            config.hash_algorithm = algorithm;
+            let symbols: Vec<Symbol> = SequenceEncoder::new(&data, "test.bin".to_string(), config).unwrap().collect();
+            let meta = first_meta(&symbols);
+            assert_eq!(meta.sha3, hex::encode(algorithm.digest(&data)));
+            assert_eq!(meta.hash_alg.is_none(), algorithm == HashAlgorithm::Sha3_256);
+            let mut decoder = SequenceDecoder::new(meta.clone(), DecoderLimits::default()).unwrap();
+            assert_eq!(decoder.hash_algorithm(), algorithm);
+            for symb in symbols { decoder.insert_new(symb).unwrap(); }
+            assert_eq!(decoder.collect_data().unwrap(), data);
+
+            // A meta symbol with the same hash under a different algorithm belongs to a different file.
+            let other = if algorithm == HashAlgorithm::Blake3 { HashAlgorithm::Sha256 } else { HashAlgorithm::Blake3 };
+            let relabelled = MetaSymbol { hash_alg: Some(other.name().to_string()), ..meta };
+            assert!(matches!(decoder.insert_new(Symbol::Meta(relabelled)), Err(SymbolInsertError::HashMismatch)));
+        }
+    }
+
+    #[test]
+    fn test_compressed_sequence_round_trips() {
+        let text = (0..300).map(|i| format!("{{\"frame\": {}, \"status\": \"ok\"}}\n", i)).collect::<String>().into_bytes();
+        for &compression in [Compression::Deflate, Compression::Zstd, Compression::Xz].iter() {
+            let mut config = SequenceEncoderConfig::new();
+            config.compression = compression;
+            config.max_bytes_per_data_symbol = Some(100);
+            let enc = SequenceEncoder::new(&text, "log.jsonl".to_string(), config).unwrap();
+            assert!(enc.data_chunks_count() * 100 < text.len() / 3, "{} was not used", compression);
+            let symbols: Vec<Symbol> = enc.collect();
+            let meta = first_meta(&symbols);
+            assert_eq!((meta.get_compression(), meta.ver), (Ok(compression), 1));
+            // The hash is of the file before compression.
+            assert_eq!(meta.get_hash(), Ok(HashAlgorithm::Sha3_256.digest(&text)));
+            let mut decoder = SequenceDecoder::new(meta.clone(), DecoderLimits::default()).unwrap();
+            for symb in symbols { decoder.insert_new(symb).unwrap(); }
+            assert_eq!(decoder.collect_data().unwrap(), text);
+            let uncompressed = MetaSymbol { compression: None, ..meta };
+            assert!(matches!(decoder.insert_new(Symbol::Meta(uncompressed)), Err(SymbolInsertError::CompressionMismatch)));
+        }
+
+        // Data that does not compress is sent as it is, without naming a compression.
+        let noise: Vec<u8> = (0..3000).map(|_| rand::random()).collect();
+        let symbols: Vec<Symbol> = SequenceEncoder::new(&noise, "noise.bin".to_string(), SequenceEncoderConfig::new()).unwrap().collect();
+        let meta = first_meta(&symbols);
+        assert_eq!((meta.compression.clone(), meta.content_len[0], meta.ver), (None, noise.len(), 0));
+        let mut decoder = SequenceDecoder::new(meta, DecoderLimits::default()).unwrap();
+        for symb in symbols { decoder.insert_new(symb).unwrap(); }
+        assert_eq!(decoder.collect_data().unwrap(), noise);
+    }
+
+    #[test]
+    fn test_encrypted_sequence_needs_right_passphrase() {
+        let text = "attack at dawn\n".repeat(50).into_bytes();
+        let mut config = SequenceEncoderConfig::new();
+        config.passphrase = Some("correct horse battery staple".to_string());
+        config.kdf_memory = 8;
+        config.chunk_checksums = false;
+        config.merkle_tree = false;
+        let symbols: Vec<Symbol> = SequenceEncoder::new(&text, "plans.txt".to_string(), config).unwrap().collect();
+        let meta = first_meta(&symbols);
+        assert_eq!((meta.encryption.is_some(), meta.ver), (true, 2));
+        assert_ne!(meta.get_hash(), Ok(HashAlgorithm::Sha3_256.digest(&text)));
+        let contents: Vec<ContentSymbol> = symbols.iter().filter_map(|symb| match symb { Symbol::Content(content) => Some(content.clone()), _ => None }).collect();
+        assert!(contents.iter().all(|content| !content.data.windows(6).any(|window| window == b"attack")));
+
+        let mut decoder = SequenceDecoder::new(meta.clone(), DecoderLimits::default()).unwrap();
+        assert!(decoder.encryption().is_some());
+        for symb in symbols { decoder.insert_new(symb).unwrap(); }
+        assert!(matches!(decoder.collect_data(), Err(CollectDataError::NoPassphrase)));
+        decoder.unlock("wrong passphrase").unwrap();
+        assert!(matches!(decoder.collect_data(), Err(CollectDataError::DecryptionFailed(EncryptionError::WrongPassphrase))));
+        decoder.unlock("correct horse battery staple").unwrap();
+        assert_eq!(decoder.collect_data().unwrap(), text);
+        let unencrypted = MetaSymbol { encryption: None, ..meta.clone() };
+        assert!(matches!(decoder.insert_new(Symbol::Meta(unencrypted)), Err(SymbolInsertError::EncryptionMismatch)));
+
+        // Damage is told apart from a wrong passphrase, because the hash is of the encrypted file.
+        let mut damaged = SequenceDecoder::new(meta, DecoderLimits::default()).unwrap();
+        damaged.unlock("correct horse battery staple").unwrap();
+        for (i, mut content) in contents.into_iter().enumerate() {
+            if i == 0 { content.data[0] ^= 1; }
+            damaged.insert_new(Symbol::Content(content)).unwrap();
+        }
+        assert!(matches!(damaged.collect_data(), Err(CollectDataError::HashMismatch)));
+    }
+
+    #[test]
+    fn test_sequence_encrypted_to_recipients() {
+        let (alice, bob, eve) = (Identity::generate(), Identity::generate(), Identity::generate());
+        let text = "meet me by the fountain\n".repeat(40).into_bytes();
+        let mut config = SequenceEncoderConfig::new();
+        config.recipients = vec![alice.to_public(), bob.to_public()];
+        config.data_symbols_between_meta_symbols = 3;
+        config.max_bytes_per_data_symbol = Some(10);
+        let symbols: Vec<Symbol> = SequenceEncoder::new(&text, "note.txt".to_string(), config).unwrap().collect();
+        assert!(matches!(&symbols[1..3], [Symbol::Recipient(first), Symbol::Recipient(second)] if first.recipient == 0 && second.recipient == 1));
+        let meta = first_meta(&symbols);
+        assert_eq!(meta.encryption.as_ref().and_then(|encryption| encryption.recipients), Some(2));
+        assert_eq!(symbols.len(), meta.frames);
+        // Every meta symbol is followed by the recipient symbols, so a decoder that missed the first ones can still unlock the file.
+        let metas: Vec<usize> = symbols.iter().enumerate().filter(|(_, symb)| matches!(symb, Symbol::Meta(_))).map(|(i, _)| i).collect();
+        assert!(metas.len() > 1);
+        assert!(metas.iter().all(|&i| matches!(&symbols[i+1..i+3], [Symbol::Recipient(first), Symbol::Recipient(second)] if first.recipient == 0 && second.recipient == 1)));
+        let mut late = SequenceDecoder::new(meta.clone(), DecoderLimits::default()).unwrap();
+        for symb in symbols.iter().filter(|symb| !matches!(symb, Symbol::Recipient(_))).chain(&symbols[metas[metas.len()-1]+1..metas[metas.len()-1]+3]) { late.insert_new(symb.clone()).unwrap(); }
+        late.unlock_with_identities(std::slice::from_ref(&bob)).unwrap();
+        assert_eq!(late.collect_data().unwrap(), text);
+
+        let decode = |identities: &[Identity], skip_recipient: Option<usize>| {
+            let mut decoder = SequenceDecoder::new(meta.clone(), DecoderLimits::default()).unwrap();
+            for symb in symbols.iter() {
+                if matches!(symb, Symbol::Recipient(recipient) if Some(recipient.recipient) == skip_recipient) { continue; }
+                decoder.insert_new(symb.clone()).unwrap();
+            }
+            decoder.unlock_with_identities(identities).map(|_| decoder.collect_data().unwrap())
+        };
+        assert_eq!(decode(std::slice::from_ref(&bob), None).unwrap(), text);
+        assert_eq!(decode(&[eve.clone(), alice.clone()], None).unwrap(), text);
+        assert!(matches!(decode(&[eve], None), Err(EncryptionError::NoMatchingIdentity { read: 2, recipients: 2 })));
+        assert!(matches!(decode(&[bob], Some(1)), Err(EncryptionError::NoMatchingIdentity { read: 1, recipients: 2 })));
+
+        let mut decoder = SequenceDecoder::new(meta.clone(), DecoderLimits::default()).unwrap();
+        assert_eq!(decoder.unlock("a passphrase").err(), Some(EncryptionError::NeedsIdentity));
+        let stray = RecipientSymbol { recipient: 2, ..match &symbols[1] { Symbol::Recipient(recipient) => recipient.clone(), _ => unreachable!() } };
+        assert!(matches!(decoder.insert_new(Symbol::Recipient(stray)), Err(SymbolInsertError::UnexpectedRecipient)));
+
+        let mut both = SequenceEncoderConfig::new();
+        both.passphrase = Some("a passphrase".to_string());
+        both.recipients = vec![alice.to_public()];
+        assert!(matches!(SequenceEncoder::new(&text, "note.txt".to_string(), both), Err(SequenceEncoderError::PassphraseAndRecipients)));
+    }
+
+    #[test]
+    fn test_signed_sequence_reports_its_signer() {
+        let (signer, stranger) = (SigningKey::generate(), SigningKey::generate());
+        let text = "signed, sealed, delivered\n".repeat(40).into_bytes();
+        let mut config = SequenceEncoderConfig::new();
+        config.signing_key = Some(signer);
+        config.data_symbols_between_meta_symbols = 3;
+        config.max_bytes_per_data_symbol = Some(10);
+        let encoder = SequenceEncoder::new(&text, "letter.txt".to_string(), config).unwrap();
+        let total = encoder.total_len();
+        let symbols: Vec<Symbol> = encoder.collect();
+        assert_eq!(symbols.len(), total);
+        let meta = first_meta(&symbols);
+        let signature = match &symbols[1] { Symbol::Signature(signature) => signature.clone(), _ => panic!("second symbol is not a signature") };
+        assert!(symbols.iter().filter_map(|symb| match symb { Symbol::Meta(meta) => Some(meta), _ => None }).all(|meta| meta.frames == total));
+        // Every meta symbol is followed by the signature, so a decoder that missed the first one still learns who signed the file.
+        let metas: Vec<usize> = symbols.iter().enumerate().filter(|(_, symb)| matches!(symb, Symbol::Meta(_))).map(|(i, _)| i).collect();
+        assert!(metas.len() > 1);
+        assert!(metas.iter().all(|&i| matches!(&symbols[i+1], Symbol::Signature(copy) if *copy == signature)));
+
+        let mut decoder = SequenceDecoder::new(meta.clone(), DecoderLimits::default()).unwrap();
+        assert_eq!(decoder.signature_status(&[]), SignatureStatus::Unsigned);
+        for symb in symbols.iter() { decoder.insert_new(symb.clone()).unwrap(); }
+        let key = match decoder.signature_status(&[]) { SignatureStatus::Untrusted(key) => key, status => panic!("signature was {:?}", status) };
+        assert_eq!(decoder.signature_status(&[stranger.to_public(), key]), SignatureStatus::Trusted(key));
+        assert_eq!(decoder.collect_data().unwrap(), text);
+
+        // A meta symbol for another file does not match the signature, and a forged signature does not match this file.
+        let mut renamed = SequenceDecoder::new(MetaSymbol { name: "forged.txt".to_string(), ..meta.clone() }, DecoderLimits::default()).unwrap();
+        assert!(matches!(renamed.insert_new(Symbol::Signature(signature.clone())), Err(SymbolInsertError::InvalidSignature(SignatureError::Mismatch))));
+        assert_eq!(renamed.signature_status(&[key]), SignatureStatus::Invalid);
+        let mut forged = SequenceDecoder::new(meta.clone(), DecoderLimits::default()).unwrap();
+        let forgery = SignatureSymbol { key: signature.key.clone(), ..stranger.sign(&meta) };
+        assert!(forged.insert_new(Symbol::Signature(forgery)).is_err());
+        assert_eq!(forged.signature_status(&[key]), SignatureStatus::Invalid);
+        forged.insert_new(Symbol::Signature(signature)).unwrap();
+        assert_eq!(forged.signature_status(&[key]), SignatureStatus::Trusted(key));
+        // A signature that does not match is outweighed by one that does, whoever it is by.
+        assert_eq!(forged.signature_status(&[]), SignatureStatus::Untrusted(key));
+    }
+
+    #[test]
+    fn test_limits_refuse_oversized_sequences() {
+        let data = "a line of a log file\n".repeat(500).into_bytes();
+        let mut config = SequenceEncoderConfig::new();
+        config.qr_version = Version::Normal(10);
+        let symbols: Vec<Symbol> = SequenceEncoder::new(&data, "log.txt".to_string(), config).unwrap().collect();
+        let meta = first_meta(&symbols);
+        let chunk = symbols.iter().find_map(|symb| match symb { Symbol::Content(content) => Some(content.clone()), _ => None }).unwrap();
+        let limits = DecoderLimits::default();
+
+        let huge = MetaSymbol { content_len: vec![1 << 50, 1], ..meta.clone() };
+        assert!(matches!(SequenceDecoder::new(huge, limits), Err(NewDecoderError::LimitExceeded(LimitError::FileTooLarge { len, max })) if len == 1 << 50 && max == limits.max_file_size));
+        let numerous = MetaSymbol { content_len: vec![1000, 1 << 40], ..meta.clone() };
+        assert!(matches!(SequenceDecoder::new(numerous, limits), Err(NewDecoderError::LimitExceeded(LimitError::TooManyChunks { .. }))));
+        // Argon2 would take terabytes of memory, or hours of passes, before the passphrase could be checked.
+        let costly = |m_cost, t_cost| MetaSymbol { ver: 2, encryption: Some(Encryption { m_cost: Some(m_cost), t_cost: Some(t_cost), ..Encryption::generate(8) }), ..meta.clone() };
+        assert!(SequenceDecoder::new(costly(limits.max_kdf_memory, limits.max_kdf_passes), limits).is_ok());
+        assert!(matches!(SequenceDecoder::new(costly(u32::MAX, 1), limits), Err(NewDecoderError::LimitExceeded(LimitError::KdfTooExpensive { m_cost: u32::MAX, .. }))));
+        assert!(matches!(SequenceDecoder::new(costly(8, u32::MAX), limits), Err(NewDecoderError::LimitExceeded(LimitError::KdfTooExpensive { t_cost: u32::MAX, .. }))));
+
+        let mut decoder = SequenceDecoder::new(meta.clone(), DecoderLimits { max_chunk_size: chunk.data.len() - 1, ..limits }).unwrap();
+        assert!(matches!(decoder.insert_new(Symbol::Content(chunk.clone())), Err(SymbolInsertError::LimitExceeded(LimitError::ChunkTooLarge { .. }))));
+        let stray = ContentSymbol::with_checksum(meta.seq_id, meta.content_len[1], vec![1, 2, 3]);
+        assert!(matches!(decoder.insert_new(Symbol::Content(stray)), Err(SymbolInsertError::ChunkOutOfRange(_))));
+
+        // The file is small once compressed, but not once decompressed.
+        let mut decoder = SequenceDecoder::new(meta.clone(), DecoderLimits { max_file_size: data.len() - 1, ..limits }).unwrap();
+        for symb in symbols.iter() { decoder.insert_new(symb.clone()).unwrap(); }
+        assert!(matches!(decoder.collect_data(), Err(CollectDataError::LimitExceeded(LimitError::FileTooLarge { .. }))));
+
+        let mut collector = SequenceCollector::new(DecoderLimits { max_sequences: 1, ..limits });
+        let other = MetaSymbol { seq_id: meta.seq_id.wrapping_add(1), ..meta.clone() };
+        collector.insert(Symbol::Meta(meta.clone()));
+        collector.insert(Symbol::Meta(other));
+        assert_eq!(collector.into_decoders().len(), 1);
+
+        // Symbols waiting for their meta symbol are held for no more sequences than can be decoded, and no more than `max_chunks` of them.
+        let mut collector = SequenceCollector::new(DecoderLimits { max_sequences: 2, max_chunks: 3, ..limits });
+        let orphan = |seq_id: u8, index| Symbol::Content(ContentSymbol::with_checksum(seq_id, index, vec![index as u8]));
+        for seq_id in 0..4 { collector.insert(orphan(meta.seq_id.wrapping_add(seq_id), 0)); }
+        assert_eq!(collector.orphans.len(), 2);
+        for index in 1..10 { collector.insert(orphan(meta.seq_id, index)); }
+        assert_eq!(collector.orphans_count, 3);
+        // Once the meta symbol of a sequence is refused, its symbols are dropped, and so are any that come later.
+        collector.insert(Symbol::Meta(MetaSymbol { content_len: vec![1 << 50, 1], ..meta.clone() }));
+        assert_eq!((collector.orphans.len(), collector.orphans_count), (1, 1));
+        collector.insert(orphan(meta.seq_id, 0));
+        assert_eq!(collector.orphans_count, 1);
+        assert!(collector.into_decoders().is_empty());
+    }
+
+    #[test]
+    fn test_file_metadata_is_carried_and_signed() {
+        let metadata = FileMetadata { mime: Some("text/csv".to_string()), mtime: Some(1_234_567_890), mode: Some(0o600), comment: Some("quarterly figures".to_string()) };
+        let mut config = SequenceEncoderConfig::new();
+        config.metadata = metadata.clone();
+        config.signing_key = Some(SigningKey::generate());
+        let symbols: Vec<Symbol> = SequenceEncoder::new(b"a,b\n1,2\n", "figures.csv".to_string(), config).unwrap().collect();
+        let meta = first_meta(&symbols);
+        let signature = symbols.iter().find_map(|symb| match symb { Symbol::Signature(signature) => Some(signature.clone()), _ => None }).unwrap();
+        let mut decoder = SequenceDecoder::new(meta.clone(), DecoderLimits::default()).unwrap();
+        for symb in symbols { decoder.insert_new(symb).unwrap(); }
+        assert_eq!(decoder.metadata(), &metadata);
+
+        let recommented = MetaSymbol { metadata: FileMetadata { comment: Some("nothing to see here".to_string()), ..metadata }, ..meta };
+        assert!(matches!(decoder.insert_new(Symbol::Meta(recommented.clone())), Err(SymbolInsertError::MetadataMismatch)));
+        let mut forged = SequenceDecoder::new(recommented, DecoderLimits::default()).unwrap();
+        assert!(matches!(forged.insert_new(Symbol::Signature(signature)), Err(SymbolInsertError::InvalidSignature(SignatureError::Mismatch))));
+    }
+
+    #[test]
+    fn test_archive_sequence_carries_its_manifest() {
+        let files: Vec<(String, Vec<u8>)> = (0..10).map(|i| (format!("notes/{}.txt", i), format!("note number {}\n", i).into_bytes())).collect();
+        let entries: Vec<ManifestEntry> = files.iter().map(|(path, data)| ManifestEntry { path: path.clone(), dir: false, size: data.len(), hash: Some(hex::encode(HashAlgorithm::Sha3_256.digest(data))), mode: Some(0o644), mtime: Some(1_600_000_000) }).collect();
+        let data: Vec<u8> = files.iter().flat_map(|(_, data)| data.clone()).collect();
+        let mut config = SequenceEncoderConfig::new();
+        config.qr_version = Version::Normal(12);
+        config.data_symbols_between_meta_symbols = 2;
+        config.max_bytes_per_data_symbol = Some(50);
+        config.compression = Compression::None;
+        let symbols: Vec<Symbol> = SequenceEncoder::new_archive(&data, &entries, "notes".to_string(), config).unwrap().collect();
+        let meta = first_meta(&symbols);
+        // The manifest does not fit in a single QR code of this size, so it is split.
+        assert!(meta.manifest.as_ref().unwrap().parts > 1);
+        assert_eq!(meta.ver, 3);
+        assert_eq!(symbols.len(), meta.frames);
+        // Every meta symbol is followed by the whole manifest, so a decoder that missed the first copy still learns what the tree holds.
+        let parts = meta.manifest.as_ref().unwrap().parts;
+        let metas: Vec<usize> = symbols.iter().enumerate().filter(|(_, symb)| matches!(symb, Symbol::Meta(_))).map(|(i, _)| i).collect();
+        assert!(metas.len() > 1);
+        assert!(metas.iter().all(|&i| (0..parts).all(|part| matches!(&symbols[i+1+part], Symbol::Manifest(manifest) if manifest.part == part))));
+        let mut late = SequenceDecoder::new(meta.clone(), DecoderLimits::default()).unwrap();
+        for symb in symbols[1+parts..].iter() { late.insert_new(symb.clone()).unwrap(); }
+        assert_eq!(late.collect_manifest().unwrap(), Some(entries.clone()));
+        let mut decoder = SequenceDecoder::new(meta.clone(), DecoderLimits::default()).unwrap();
+        for symb in symbols.iter().filter(|symb| !matches!(symb, Symbol::Manifest(manifest) if manifest.part == 0)) { decoder.insert_new(symb.clone()).unwrap(); }
+        assert!(!decoder.is_complete());
+        assert!(matches!(decoder.collect_manifest(), Err(CollectDataError::IncompleteManifest)));
+        for symb in symbols.iter() { decoder.insert_new(symb.clone()).unwrap(); }
+        assert!(decoder.is_complete());
+        assert_eq!(decoder.collect_manifest().unwrap(), Some(entries.clone()));
+        assert_eq!(decoder.collect_data().unwrap(), data);
+
+        let unlisted = MetaSymbol { manifest: None, ..meta.clone() };
+        assert!(matches!(decoder.insert_new(Symbol::Meta(unlisted)), Err(SymbolInsertError::ManifestMismatch)));
+        let forged = symbols.iter().find_map(|symb| match symb { Symbol::Manifest(manifest) => Some(ManifestSymbol::new(manifest.seq_id, manifest.part, b"{}")), _ => None }).unwrap();
+        assert!(matches!(decoder.insert_new(Symbol::Manifest(forged)), Err(SymbolInsertError::ManifestPartMismatch)));
+        // Parts that add up to more than the manifest's length are not kept, whatever their number.
+        let info = meta.manifest.clone().unwrap();
+        let mut padded = SequenceDecoder::new(meta.clone(), DecoderLimits::default()).unwrap();
+        let filler = vec![b' '; info.len / info.parts + 1];
+        let rejected = (0..info.parts).map(|part| padded.insert_new(Symbol::Manifest(ManifestSymbol::new(meta.seq_id, part, &filler)))).filter(|result| matches!(result, Err(SymbolInsertError::ManifestTooLong))).count();
+        assert_eq!(rejected, 1);
+        let mut empty = SequenceDecoder::new(meta.clone(), DecoderLimits::default()).unwrap();
+        assert!(matches!(empty.insert_new(Symbol::Manifest(ManifestSymbol::new(meta.seq_id, 0, b""))), Err(SymbolInsertError::ManifestTooLong)));
+        let huge = MetaSymbol { manifest: Some(ManifestInfo { parts: 1 << 40, len: 1 << 41, ..info }), ..meta.clone() };
+        assert!(matches!(SequenceDecoder::new(huge, DecoderLimits { max_file_size: usize::MAX, ..DecoderLimits::default() }), Err(NewDecoderError::LimitExceeded(LimitError::TooManyChunks { .. }))));
+        let mut single = SequenceDecoder::new(MetaSymbol { manifest: None, ..meta }, DecoderLimits::default()).unwrap();
+        assert!(matches!(single.insert_new(symbols.iter().find(|symb| matches!(symb, Symbol::Manifest(_))).unwrap().clone()), Err(SymbolInsertError::UnexpectedManifest)));
+    }
+
+    #[test]
+    fn test_encrypted_archive_hides_its_manifest() {
+        let entries = vec![ManifestEntry { path: "secret-plans.txt".to_string(), dir: false, size: 4, hash: Some(hex::encode(HashAlgorithm::Sha3_256.digest(b"plan"))), mode: None, mtime: None }];
+        let mut config = SequenceEncoderConfig::new();
+        config.passphrase = Some("hunter2".to_string());
+        config.kdf_memory = 8;
+        let symbols: Vec<Symbol> = SequenceEncoder::new_archive(b"plan", &entries, "plans".to_string(), config).unwrap().collect();
+        let meta = first_meta(&symbols);
+        assert!(symbols.iter().all(|symb| !symb.to_str().contains("secret")));
+        let mut decoder = SequenceDecoder::new(meta, DecoderLimits::default()).unwrap();
+        for symb in symbols { decoder.insert_new(symb).unwrap(); }
+        assert!(matches!(decoder.collect_manifest(), Err(CollectDataError::NoPassphrase)));
+        decoder.unlock("hunter2").unwrap();
+        assert_eq!(decoder.collect_manifest().unwrap(), Some(entries));
+        assert_eq!(decoder.collect_data().unwrap(), b"plan");
+    }
 }
-*/
@@ -0,0 +1,174 @@
+use ed25519_dalek::Signer;
+use thiserror::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::encryption::{decode_bech32_key, encode_bech32_key};
+use crate::symbol::{MetaSymbol, SignatureSymbol};
+
+/// Errors that may occur while reading keys or checking a signature.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum SignatureError {
+    /// A signing or verifying key could not be parsed.
+    #[error("{0:?} is not a valid {1}")]
+    InvalidKey(String, &'static str),
+
+    /// A signing key file must hold exactly one key.
+    #[error("the file holds {0} signing keys, rather than exactly one")]
+    KeyCount(usize),
+
+    /// The signature does not match the meta symbol, so either of them was forged or belongs to another file.
+    #[error("the signature does not match the meta symbol")]
+    Mismatch,
+}
+
+/// An Ed25519 public key that signatures are checked against, written in Bech32: `datablast-sig1...`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VerifyingKey(ed25519_dalek::VerifyingKey);
+
+/// An Ed25519 private key that signs sequences, written in Bech32 in uppercase: `DATABLAST-SIG-SECRET-KEY-1...`.
+pub struct SigningKey(ed25519_dalek::SigningKey);
+
+const VERIFYING_KEY_PREFIX: &str = "datablast-sig";
+const SIGNING_KEY_PREFIX: &str = "datablast-sig-secret-key-";
+
+/// Whether a sequence was signed, and by whom.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureStatus {
+    /// There was no signature symbol.
+    Unsigned,
+    /// A signature symbol did not match the meta symbol, and no signature symbol did.
+    ///
+    /// Anyone can add a signature symbol with the right sequence ID, so this says no more about the file than no signature at all:
+    /// the file is still written, with a warning, once it matches its hash, unless a trusted signature is required.
+    Invalid,
+    /// The sequence was signed, but by a key that is not trusted.
+    Untrusted(VerifyingKey),
+    /// The sequence was signed by this trusted key.
+    Trusted(VerifyingKey),
+}
+
+impl SigningKey {
+    pub fn generate() -> Self { SigningKey(ed25519_dalek::SigningKey::from_bytes(&rand::random())) }
+
+    /// The public key that checks the signatures made with this key.
+    pub fn to_public(&self) -> VerifyingKey { VerifyingKey(self.0.verifying_key()) }
+
+    /// Sign the fields of this meta symbol that describe the file.
+    pub fn sign(&self, meta: &MetaSymbol) -> SignatureSymbol {
+        let signature = self.0.sign(&meta.signed_message());
+        SignatureSymbol { seq_id: meta.seq_id, key: base64::encode(self.0.verifying_key().as_bytes()), sig: base64::encode(signature.to_bytes()) }
+    }
+
+    /// Read the one signing key in a key file, skipping blank lines and comments starting with `#`.
+    pub fn parse_file(contents: &str) -> Result<SigningKey, SignatureError> {
+        let mut keys: Vec<SigningKey> = key_lines(contents).map(str::parse).collect::<Result<_, _>>()?;
+        if keys.len() != 1 { return Err(SignatureError::KeyCount(keys.len())); }
+        Ok(keys.remove(0))
+    }
+}
+
+impl FromStr for SigningKey {
+    type Err = SignatureError;
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        // The key itself must not end up in logs, so only its prefix is reported.
+        let key = decode_bech32_key(text, SIGNING_KEY_PREFIX).ok_or_else(|| SignatureError::InvalidKey(text.chars().take(26).collect::<String>() + "...", "signing key"))?;
+        Ok(SigningKey(ed25519_dalek::SigningKey::from_bytes(&key)))
+    }
+}
+
+impl fmt::Display for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str(&encode_bech32_key(&self.0.to_bytes(), SIGNING_KEY_PREFIX).to_uppercase()) }
+}
+
+impl VerifyingKey {
+    /// Read the keys in a file of trusted keys, one on each line, skipping blank lines and comments starting with `#`.
+    pub fn parse_file(contents: &str) -> Result<Vec<VerifyingKey>, SignatureError> {
+        key_lines(contents).map(str::parse).collect()
+    }
+}
+
+impl FromStr for VerifyingKey {
+    type Err = SignatureError;
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let invalid = || SignatureError::InvalidKey(text.to_string(), "verifying key");
+        let key = decode_bech32_key(text, VERIFYING_KEY_PREFIX).ok_or_else(invalid)?;
+        Ok(VerifyingKey(ed25519_dalek::VerifyingKey::from_bytes(&key).map_err(|_| invalid())?))
+    }
+}
+
+impl fmt::Display for VerifyingKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str(&encode_bech32_key(self.0.as_bytes(), VERIFYING_KEY_PREFIX)) }
+}
+
+/// Check this signature symbol against the signed message of the meta symbol, and get the key that made it.
+pub fn verify(symb: &SignatureSymbol, message: &[u8]) -> Result<VerifyingKey, SignatureError> {
+    let key = symb.get_key().map_err(|_| SignatureError::Mismatch)?;
+    let key = ed25519_dalek::VerifyingKey::from_bytes(&key).map_err(|_| SignatureError::Mismatch)?;
+    let signature = ed25519_dalek::Signature::from_bytes(&symb.get_signature().map_err(|_| SignatureError::Mismatch)?);
+    key.verify_strict(message, &signature).map_err(|_| SignatureError::Mismatch)?;
+    Ok(VerifyingKey(key))
+}
+
+/// The lines of a key file that hold keys.
+fn key_lines(contents: &str) -> impl Iterator<Item = &str> {
+    contents.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::signature::*;
+    use crate::symbol::FileMetadata;
+
+    fn meta() -> MetaSymbol {
+        MetaSymbol { ver: 0, seq_id: 4, frames: 10, cur_frame: 0, content_len: vec![100, 9], sha3: "ab".repeat(32), name: "signed.txt".to_string(), hash_alg: None, compression: None, encryption: None, merkle_root: None, manifest: None, metadata: FileMetadata::default(), extra: Default::default() }
+    }
+
+    #[test]
+    fn test_signature_covers_meta_fields() {
+        let key = SigningKey::generate();
+        let symb = key.sign(&meta());
+        assert_eq!(symb.seq_id, 4);
+        assert_eq!(verify(&symb, &meta().signed_message()), Ok(key.to_public()));
+        // Another meta symbol of the same sequence, at another frame, is signed by the same signature.
+        let later = MetaSymbol { cur_frame: 7, ..meta() };
+        assert_eq!(verify(&symb, &later.signed_message()), Ok(key.to_public()));
+        let renamed = MetaSymbol { name: "evil.txt".to_string(), ..meta() };
+        assert_eq!(verify(&symb, &renamed.signed_message()), Err(SignatureError::Mismatch));
+        let rehashed = MetaSymbol { sha3: "cd".repeat(32), ..meta() };
+        assert_eq!(verify(&symb, &rehashed.signed_message()), Err(SignatureError::Mismatch));
+        // Claiming someone else's key for the signature does not work either.
+        let impostor = SignatureSymbol { key: base64::encode(SigningKey::generate().to_public().0.as_bytes()), ..symb };
+        assert_eq!(verify(&impostor, &meta().signed_message()), Err(SignatureError::Mismatch));
+    }
+
+    #[test]
+    fn test_signature_covers_fields_from_later_versions() {
+        let key = SigningKey::generate();
+        // A later encoder signs a meta symbol with a field this version does not know.
+        let mut newer = meta();
+        newer.extra.insert("owner".to_string(), serde_json::json!({ "name": "alice", "id": 7 }));
+        let symb = key.sign(&newer);
+        let parsed = match crate::symbol::symbol_from_string(newer.to_str()) { Ok(crate::symbol::Symbol::Meta(parsed)) => parsed, other => panic!("expected a meta symbol, got {:?}", other) };
+        assert_eq!(verify(&symb, &parsed.signed_message()), Ok(key.to_public()));
+        // Changing or dropping the unknown field still breaks the signature.
+        let mut altered = parsed.clone();
+        altered.extra.insert("owner".to_string(), serde_json::json!("mallory"));
+        assert_eq!(verify(&symb, &altered.signed_message()), Err(SignatureError::Mismatch));
+        assert_eq!(verify(&symb, &meta().signed_message()), Err(SignatureError::Mismatch));
+    }
+
+    #[test]
+    fn test_keys_round_trip_through_files() {
+        let key = SigningKey::generate();
+        let file = format!("# public key: {}\n{}\n", key.to_public(), key);
+        assert!(key.to_public().to_string().starts_with("datablast-sig1"));
+        assert!(key.to_string().starts_with("DATABLAST-SIG-SECRET-KEY-1"));
+        assert_eq!(SigningKey::parse_file(&file).unwrap().to_public(), key.to_public());
+        assert_eq!(SigningKey::parse_file("# nothing here\n").err(), Some(SignatureError::KeyCount(0)));
+        let trusted = format!("{}\n\n# a comment\n{}\n", key.to_public(), SigningKey::generate().to_public());
+        assert_eq!(VerifyingKey::parse_file(&trusted).unwrap()[0], key.to_public());
+        assert!(key.to_string().parse::<VerifyingKey>().is_err());
+        assert!(key.to_public().to_string().parse::<SigningKey>().is_err());
+    }
+}
@@ -5,15 +5,28 @@ use base64::{encode, decode, DecodeError};
 use core::num::ParseIntError;
 use hex;
 use thiserror::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::convert::TryInto;
+use sha3::Digest;
 
 pub type Version = u32;
 
 #[derive(Debug, Eq, PartialEq, Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum SymbolDecodeError {
     #[error("There was an error while decoding this content symbol: {0}")]
     InvalidContent(ContentDecodeError),
     #[error("There was an error while decoding this meta symbol: {0}")]
     InvalidMeta(MetaDecodeError),
+    #[error("There was an error while decoding this tree symbol: {0}")]
+    InvalidTree(TreeDecodeError),
+    #[error("There was an error while decoding this recipient symbol: {0}")]
+    InvalidRecipient(RecipientDecodeError),
+    #[error("There was an error while decoding this signature symbol: {0}")]
+    InvalidSignature(SignatureDecodeError),
+    #[error("The data of this manifest symbol is not valid Base64")]
+    InvalidManifest,
 }
 
 #[derive(Debug, Eq, PartialEq, Error)]
@@ -28,7 +41,16 @@ pub enum ContentDecodeError {
     InvalidPieceIdPart(ParseIntError),
 
     #[error("The data part was empty")]
-    NoDataPart
+    NoDataPart,
+
+    #[error("The header before the data part was {0} characters long, but at least 3 are needed for the sequence ID and piece ID")]
+    HeaderTooShort(usize),
+
+    #[error("The header before the data part should only contain hex digits, but it was {0:?}")]
+    HeaderNotHex(String),
+
+    #[error("The checksum after the data part should be 8 lowercase hex digits, but it was {0:?}")]
+    InvalidChecksumPart(String),
 }
 
 #[derive(Debug, Eq, PartialEq, Error)]
@@ -36,6 +58,9 @@ pub enum MetaDecodeError {
     #[error("This program version does not understand this meta version: {0}")]
     UnknownVersion(Version),
 
+    #[error("The meta symbol claims version {ver}, but it uses features of version {required}, which older decoders would misread")]
+    VersionTooLow { ver: Version, required: Version },
+
     #[error("There were {0} elements in the content_len array while 2 were expected")]
     InvalidLengthOfContentLen(usize),
 
@@ -44,6 +69,247 @@ pub enum MetaDecodeError {
 
     #[error("The hash field is not a valid hex number")]
     HashFieldNotHex,
+
+    #[error("The Merkle root field is not a string of 64 hex digits")]
+    InvalidMerkleRoot,
+
+    #[error("This program version does not know the hash algorithm {0:?}")]
+    UnknownHashAlgorithm(String),
+
+    #[error("This program version does not know the compression {0:?}")]
+    UnknownCompression(String),
+
+    #[error("This program version does not know the cipher {0:?}")]
+    UnknownCipher(String),
+
+    #[error("This program version does not know the key derivation function {0:?}")]
+    UnknownKeyDerivation(String),
+
+    #[error("The salt was supposed to be {} bytes of Base64", Encryption::SALT_LEN)]
+    InvalidSalt,
+
+    #[error("The nonce was supposed to be {} bytes of Base64", Encryption::NONCE_LEN)]
+    InvalidNonce,
+
+    #[error("The encryption settings lack the {0} field")]
+    MissingEncryptionField(&'static str),
+
+    #[error("The file was encrypted to no recipients")]
+    NoRecipients,
+
+    #[error("The manifest field must have at least one part, no more parts than bytes, and a hash of 64 hex digits")]
+    InvalidManifest,
+
+    #[error("The MIME type {0:?} is not of the form type/subtype")]
+    InvalidMime(String),
+
+    #[error("The mode {0:o} has bits beyond the Unix permission bits")]
+    InvalidMode(u32),
+
+    #[error("The modification time {0} is after the year 9999")]
+    InvalidMtime(u64),
+}
+
+/// An algorithm that can be used for the hash of the whole file in a meta symbol. Each of them gives a 32-byte hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HashAlgorithm {
+    /// SHA3-256, which is used when a meta symbol does not name an algorithm.
+    #[default]
+    Sha3_256,
+    Sha256,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    /// The name of the algorithm, as written in the `hash_alg` field.
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha3_256 => "sha3-256",
+            HashAlgorithm::Sha256 => "sha-256",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    /// Hash this data.
+    pub fn digest(self, data: &[u8]) -> [u8;32] {
+        match self {
+            HashAlgorithm::Sha3_256 => sha3::Sha3_256::digest(data).into(),
+            HashAlgorithm::Sha256 => sha2::Sha256::digest(data).into(),
+            HashAlgorithm::Blake3 => blake3::hash(data).into(),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str(self.name()) }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = MetaDecodeError;
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        [HashAlgorithm::Sha3_256, HashAlgorithm::Sha256, HashAlgorithm::Blake3].iter().copied()
+            .find(|algorithm| algorithm.name() == name)
+            .ok_or_else(|| MetaDecodeError::UnknownHashAlgorithm(name.to_string()))
+    }
+}
+
+/// How the file was compressed before it was split into chunks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// The chunks carry the file as it is, which is assumed when a meta symbol does not name a compression.
+    #[default]
+    None,
+    /// Raw DEFLATE, as in RFC 1951.
+    Deflate,
+    Zstd,
+    Xz,
+}
+
+impl Compression {
+    /// The name of the compression, as written in the `compression` field.
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Deflate => "deflate",
+            Compression::Zstd => "zstd",
+            Compression::Xz => "xz",
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str(self.name()) }
+}
+
+impl FromStr for Compression {
+    type Err = MetaDecodeError;
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        [Compression::None, Compression::Deflate, Compression::Zstd, Compression::Xz].iter().copied()
+            .find(|compression| compression.name() == name)
+            .ok_or_else(|| MetaDecodeError::UnknownCompression(name.to_string()))
+    }
+}
+
+/// How the file was encrypted, after it was compressed and before it was split into chunks.
+///
+/// The key is either derived from a passphrase, and then the Argon2 fields are set, or wrapped for each recipient in a recipient symbol, and then `recipients` is set.
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Encryption {
+    /// The AEAD the file was encrypted with, which must be `Encryption::CIPHER`.
+    pub cipher: String,
+    /// Where the key comes from: `Encryption::KDF_PASSPHRASE` or `Encryption::KDF_RECIPIENTS`.
+    pub kdf: String,
+    /// The salt of the key derivation, as Base64.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub salt: Option<String>,
+    /// The memory Argon2 uses, in KiB.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub m_cost: Option<u32>,
+    /// The number of passes Argon2 makes over its memory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub t_cost: Option<u32>,
+    /// The number of lanes Argon2 splits its memory into.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p_cost: Option<u32>,
+    /// The number of recipient symbols, each of which wraps the key for one recipient.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipients: Option<usize>,
+    /// The nonce of the AEAD, as Base64.
+    pub nonce: String,
+}
+
+impl Encryption {
+    pub const CIPHER: &'static str = "chacha20-poly1305";
+    /// The key is derived from a passphrase with Argon2id.
+    pub const KDF_PASSPHRASE: &'static str = "argon2id";
+    /// The key is wrapped for X25519 public keys in recipient symbols.
+    pub const KDF_RECIPIENTS: &'static str = "x25519";
+    pub const SALT_LEN: usize = 16;
+    pub const NONCE_LEN: usize = 12;
+
+    pub fn validate(&self) -> Result<(), MetaDecodeError> {
+        if self.cipher != Encryption::CIPHER { return Err(MetaDecodeError::UnknownCipher(self.cipher.clone())); }
+        match self.kdf.as_str() {
+            Encryption::KDF_PASSPHRASE => { self.get_salt()?; self.get_costs()?; },
+            Encryption::KDF_RECIPIENTS => { self.get_recipients()?; },
+            _ => return Err(MetaDecodeError::UnknownKeyDerivation(self.kdf.clone())),
+        }
+        self.get_nonce()?;
+        Ok(())
+    }
+
+    /// Whether the key is derived from a passphrase, rather than wrapped for recipients.
+    pub fn uses_passphrase(&self) -> bool { self.kdf == Encryption::KDF_PASSPHRASE }
+
+    /// Decode the salt, which must be `SALT_LEN` bytes.
+    pub fn get_salt(&self) -> Result<[u8; Encryption::SALT_LEN], MetaDecodeError> {
+        let salt = self.salt.as_ref().ok_or(MetaDecodeError::MissingEncryptionField("salt"))?;
+        let salt = decode(salt).map_err(|_| MetaDecodeError::InvalidSalt)?;
+        salt.as_slice().try_into().map_err(|_| MetaDecodeError::InvalidSalt)
+    }
+
+    /// Get the memory, passes and lanes of Argon2.
+    pub fn get_costs(&self) -> Result<(u32, u32, u32), MetaDecodeError> {
+        let m_cost = self.m_cost.ok_or(MetaDecodeError::MissingEncryptionField("m_cost"))?;
+        let t_cost = self.t_cost.ok_or(MetaDecodeError::MissingEncryptionField("t_cost"))?;
+        let p_cost = self.p_cost.ok_or(MetaDecodeError::MissingEncryptionField("p_cost"))?;
+        Ok((m_cost, t_cost, p_cost))
+    }
+
+    /// Get the number of recipient symbols, which must be at least one.
+    pub fn get_recipients(&self) -> Result<usize, MetaDecodeError> {
+        match self.recipients {
+            Some(0) => Err(MetaDecodeError::NoRecipients),
+            Some(count) => Ok(count),
+            None => Err(MetaDecodeError::MissingEncryptionField("recipients")),
+        }
+    }
+
+    /// Decode the nonce, which must be `NONCE_LEN` bytes.
+    pub fn get_nonce(&self) -> Result<[u8; Encryption::NONCE_LEN], MetaDecodeError> {
+        let nonce = decode(&self.nonce).map_err(|_| MetaDecodeError::InvalidNonce)?;
+        nonce.as_slice().try_into().map_err(|_| MetaDecodeError::InvalidNonce)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Error)]
+pub enum TreeDecodeError {
+    #[error("The leaves part was not valid Base64: {0}")]
+    InvalidLeavesPart(DecodeError),
+
+    #[error("The path part was not valid Base64: {0}")]
+    InvalidPathPart(DecodeError),
+
+    #[error("The hashes took {0} bytes, which is not a whole number of 32-byte hashes")]
+    PartialHash(usize),
+
+    #[error("There were no leaves")]
+    NoLeaves,
+
+    #[error("There were {leaves} leaves, but a block at level {level} holds at most {max}")]
+    TooManyLeaves { leaves: usize, level: u32, max: usize },
+
+    #[error("The first leaf {first} does not start a block at level {level}")]
+    MisalignedBlock { first: usize, level: u32 },
+}
+
+#[derive(Debug, Eq, PartialEq, Error)]
+pub enum RecipientDecodeError {
+    #[error("The share was supposed to be {} bytes of Base64", RecipientSymbol::SHARE_LEN)]
+    InvalidShare,
+
+    #[error("The body was supposed to be {} bytes of Base64", RecipientSymbol::BODY_LEN)]
+    InvalidBody,
+}
+
+#[derive(Debug, Eq, PartialEq, Error)]
+pub enum SignatureDecodeError {
+    #[error("The key was supposed to be {} bytes of Base64", SignatureSymbol::KEY_LEN)]
+    InvalidKey,
+
+    #[error("The signature was supposed to be {} bytes of Base64", SignatureSymbol::SIGNATURE_LEN)]
+    InvalidSignature,
 }
 
 pub type MetaValidateResult = Result<(), MetaDecodeError>;
@@ -61,6 +327,30 @@ pub fn symbol_from_string(data: String) -> SymbolDecodeResult {
                                 }
                         },
         Err(error) => {
+            if let Ok(treesymb) = serde_json::from_str::<TreeSymbol>(&data) {
+                return match treesymb.validate() {
+                    Ok(_) => Ok(Symbol::Tree(treesymb)),
+                    Err(error) => Err(SymbolDecodeError::InvalidTree(error)),
+                };
+            }
+            if let Ok(recipientsymb) = serde_json::from_str::<RecipientSymbol>(&data) {
+                return match recipientsymb.validate() {
+                    Ok(_) => Ok(Symbol::Recipient(recipientsymb)),
+                    Err(error) => Err(SymbolDecodeError::InvalidRecipient(error)),
+                };
+            }
+            if let Ok(signaturesymb) = serde_json::from_str::<SignatureSymbol>(&data) {
+                return match signaturesymb.validate() {
+                    Ok(_) => Ok(Symbol::Signature(signaturesymb)),
+                    Err(error) => Err(SymbolDecodeError::InvalidSignature(error)),
+                };
+            }
+            if let Ok(manifestsymb) = serde_json::from_str::<ManifestSymbol>(&data) {
+                return match manifestsymb.get_data() {
+                    Some(_) => Ok(Symbol::Manifest(manifestsymb)),
+                    None => Err(SymbolDecodeError::InvalidManifest),
+                };
+            }
             trace!("Couldn't decode symbol as JSON: {:?} (data is {:?})", error, data);
             match ContentSymbol::from_str(data) {
                 Ok(contentsymb) => Ok(Symbol::Content(contentsymb)),
@@ -70,11 +360,17 @@ pub fn symbol_from_string(data: String) -> SymbolDecodeResult {
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
-/// Some sort of symbol. Currently supported are meta symbols and content symbols.
+#[derive(Debug, Eq, PartialEq, Clone)]
+/// Some sort of symbol. Currently supported are meta symbols, content symbols, tree symbols, recipient symbols, signature symbols and manifest symbols.
+// Meta symbols are rare, so boxing them would not save much.
+#[allow(clippy::large_enum_variant)]
 pub enum Symbol {
     Meta(MetaSymbol),
     Content(ContentSymbol),
+    Tree(TreeSymbol),
+    Recipient(RecipientSymbol),
+    Signature(SignatureSymbol),
+    Manifest(ManifestSymbol),
 }
 
 impl Symbol {
@@ -82,12 +378,16 @@ impl Symbol {
         match self {
             Symbol::Meta(symb) => symb.to_str(),
             Symbol::Content(symb) => symb.to_str(),
+            Symbol::Tree(symb) => symb.to_str(),
+            Symbol::Recipient(symb) => symb.to_str(),
+            Symbol::Signature(symb) => symb.to_str(),
+            Symbol::Manifest(symb) => symb.to_str(),
         }
     }
 }
 
 #[derive(Serialize, Deserialize)]
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone)]
 /// A meta symbol. Contains information about the sequence.
 pub struct MetaSymbol {
     pub ver: Version,
@@ -95,39 +395,202 @@ pub struct MetaSymbol {
     pub frames: usize,
     pub cur_frame: usize,
     pub content_len: Vec<usize>, // should only have two elements, as per spec v.0
-    pub sha3: String, // should have len==64
+    /// The hash of the file, as 64 hex digits. It was always SHA3-256 at first, which is where the name comes from, but `hash_alg` may name another algorithm.
+    pub sha3: String,
     pub name: String,
+    /// The algorithm of the hash in `sha3`, if it is not SHA3-256.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash_alg: Option<String>,
+    /// How the file was compressed before it was split into chunks, if it was. The hash is always of the file before compression.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<String>,
+    /// How the file was encrypted, if it was. The hash is then of the encrypted file, so that it says nothing about the contents.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<Encryption>,
+    /// The root of the Merkle tree over the chunks, as 64 hex digits, if the encoder built one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merkle_root: Option<String>,
+    /// If the sequence carries a directory tree rather than a single file, where to find the list of its files.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<ManifestInfo>,
+    /// What else is known about the file, which the decoder reports or restores but does not need.
+    #[serde(flatten)]
+    pub metadata: FileMetadata,
+    /// Fields this version does not know, from a later encoder. They are kept so that a signature over them can still be checked.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Eq, PartialEq, Clone, Default)]
+/// Optional facts about a file that do not affect how it is decoded. In a meta symbol, they are fields of their own.
+pub struct FileMetadata {
+    /// The MIME type of the file, like `text/plain`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mime: Option<String>,
+    /// The time the file was last modified, in seconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<u64>,
+    /// The Unix permission bits of the file, including the setuid, setgid and sticky bits.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// A free-form comment on the file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+}
+
+impl FileMetadata {
+    /// The last second of the year 9999, which every platform can hold as a modification time.
+    pub const MAX_MTIME: u64 = 253_402_300_799;
+
+    /// Check that the MIME type is a type and a subtype, that the mode is only permission bits, and that the modification time is not absurdly late.
+    pub fn validate(&self) -> MetaValidateResult {
+        if let Some(mime) = &self.mime {
+            // Both are tokens, as in RFC 2045: printable ASCII without spaces or separators.
+            let is_token = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?=".contains(c));
+            match mime.split_once('/') {
+                Some((kind, subtype)) if is_token(kind) && is_token(subtype) => {},
+                _ => return Err(MetaDecodeError::InvalidMime(mime.clone())),
+            }
+        }
+        if let Some(mode) = self.mode {
+            if mode > 0o7777 { return Err(MetaDecodeError::InvalidMode(mode)); }
+        }
+        if let Some(mtime) = self.mtime {
+            if mtime > FileMetadata::MAX_MTIME { return Err(MetaDecodeError::InvalidMtime(mtime)); }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Eq, PartialEq, Clone)]
+/// The manifest of a directory tree, which is carried in manifest symbols.
+pub struct ManifestInfo {
+    /// The number of manifest symbols the manifest is split into.
+    pub parts: usize,
+    /// The length of the manifest, in bytes, as carried by the manifest symbols.
+    pub len: usize,
+    /// The hash of the manifest as carried by the manifest symbols, as 64 hex digits, with the algorithm of the file's hash.
+    pub hash: String,
+}
+
+impl ManifestInfo {
+    /// Decode the hash, which must be 64 hex digits.
+    pub fn get_hash(&self) -> Result<[u8;32], MetaDecodeError> {
+        let mut array = [0; 32];
+        hex::decode_to_slice(&self.hash, &mut array).map_err(|_| MetaDecodeError::InvalidManifest)?;
+        Ok(array)
+    }
+
+    pub fn validate(&self) -> MetaValidateResult {
+        if self.parts == 0 || self.parts > self.len { return Err(MetaDecodeError::InvalidManifest); }
+        self.get_hash()?;
+        Ok(())
+    }
 }
 
 impl MetaSymbol {
+    /// The latest version of the specification that this program reads and writes.
+    pub const LATEST_VERSION: Version = 3;
+
+    /// The lowest version of the specification that can read this sequence.
+    ///
+    /// Each version adds a way of storing the file that decoders of earlier versions would misread, rather than refuse: version 1 compresses it, version 2 encrypts it, and version 3 makes it a directory tree.
+    pub fn required_version(&self) -> Version {
+        if self.manifest.is_some() { 3 }
+        else if self.encryption.is_some() { 2 }
+        else if self.get_compression() != Ok(Compression::None) { 1 }
+        else { 0 }
+    }
+
     pub fn validate(&self) -> MetaValidateResult {
-        if self.ver != 0 {return Err(MetaDecodeError::UnknownVersion(self.ver));}
+        if self.ver > MetaSymbol::LATEST_VERSION {return Err(MetaDecodeError::UnknownVersion(self.ver));}
         if self.content_len.len() != 2 {return Err(MetaDecodeError::InvalidLengthOfContentLen(self.content_len.len()));}
-        if self.sha3.len() != 64 {return Err(MetaDecodeError::InvalidLengthOfHashField(self.sha3.len()));}
-        let decode_res = hex::decode(&self.sha3);
-        if decode_res.is_err() { return Err(MetaDecodeError::HashFieldNotHex); }
+        self.get_hash()?;
+        self.get_hash_algorithm()?;
+        self.get_compression()?;
+        if let Some(encryption) = &self.encryption { encryption.validate()?; }
+        self.get_merkle_root()?;
+        if let Some(manifest) = &self.manifest { manifest.validate()?; }
+        self.metadata.validate()?;
+        if self.ver < self.required_version() { return Err(MetaDecodeError::VersionTooLow { ver: self.ver, required: self.required_version() }); }
         Ok(())
     }
 
     pub fn to_str(&self) -> String { serde_json::to_string(self).expect("JSON serialization failed?!") }
-    pub fn get_hash(&self) -> [u8;32] {
-        let vec = hex::decode(&self.sha3).unwrap(); // this should not panic if this has been validated
-        let slice = vec.as_slice();
+
+    /// Decode the hash field, which must be 64 hex digits.
+    pub fn get_hash(&self) -> Result<[u8;32], MetaDecodeError> {
+        if self.sha3.len() != 64 {return Err(MetaDecodeError::InvalidLengthOfHashField(self.sha3.len()));}
+        let mut array = [0; 32];
+        hex::decode_to_slice(&self.sha3, &mut array).map_err(|_| MetaDecodeError::HashFieldNotHex)?;
+        Ok(array)
+    }
+
+    /// Get the algorithm of the hash field.
+    pub fn get_hash_algorithm(&self) -> Result<HashAlgorithm, MetaDecodeError> {
+        match &self.hash_alg {
+            Some(name) => name.parse(),
+            None => Ok(HashAlgorithm::Sha3_256),
+        }
+    }
+
+    /// Get how the file was compressed.
+    pub fn get_compression(&self) -> Result<Compression, MetaDecodeError> {
+        match &self.compression {
+            Some(name) => name.parse(),
+            None => Ok(Compression::None),
+        }
+    }
+
+    /// The bytes that a signature symbol signs: a fixed prefix, then this meta symbol as canonical JSON, leaving out the fields that differ from one meta symbol or encoding of the file to the next.
+    ///
+    /// Fields this version does not know are signed too, so a signature by a later encoder still checks.
+    pub fn signed_message(&self) -> Vec<u8> {
+        let mut fields = match serde_json::to_value(self) {
+            Ok(serde_json::Value::Object(fields)) => fields,
+            _ => panic!("meta symbol is not a JSON object?!"),
+        };
+        for field in ["seq_id", "frames", "cur_frame"] { fields.remove(field); }
+        // The map sorts its keys, and serde_json writes no whitespace, so the same fields always give the same bytes.
+        [SignatureSymbol::CONTEXT, serde_json::to_string(&fields).expect("JSON serialization failed?!").as_bytes()].concat()
+    }
+
+    /// Decode the Merkle root field, if there is one.
+    pub fn get_merkle_root(&self) -> Result<Option<[u8;32]>, MetaDecodeError> {
+        let root = match &self.merkle_root { Some(root) => root, None => return Ok(None) };
         let mut array = [0; 32];
-        array.copy_from_slice(slice);
-        array
+        hex::decode_to_slice(root, &mut array).map_err(|_| MetaDecodeError::InvalidMerkleRoot)?;
+        Ok(Some(array))
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone)]
 /// A content symbol. Contains a piece of data from the encoded file.
 pub struct ContentSymbol {
     pub sequence: u8,
     pub index: usize,
     pub data: Vec<u8>,
+    /// The CRC32C of the data, if the encoder added one. Older encoders did not, and older decoders ignore it.
+    pub checksum: Option<u32>,
 }
 
 impl ContentSymbol {
+    /// The number of characters the checksum adds after the data part.
+    pub const CHECKSUM_LEN: usize = 9;
+
+    /// Make a content symbol carrying the checksum of its data.
+    pub fn with_checksum(sequence: u8, index: usize, data: Vec<u8>) -> Self {
+        let checksum = Some(crc32c::crc32c(&data));
+        ContentSymbol { sequence, index, data, checksum }
+    }
+
+    /// Whether the data matches the checksum, which is always the case for a symbol without one.
+    pub fn checksum_matches(&self) -> bool {
+        self.checksum.is_none_or(|checksum| checksum == crc32c::crc32c(&self.data))
+    }
+
     pub fn from_str(data: String) -> Result<Self, ContentDecodeError> {
         let mut iter = data.split("@");
         let num_part = iter.next().expect("Wasn't able to get first element in a string split by character?!!");
@@ -136,12 +599,411 @@ impl ContentSymbol {
             Some(data) => {data_part = data;},
             None => {return Err(ContentDecodeError::NoDataPart);},
         }
+        // Checking this first makes sure the header is ASCII, so it can be split at any byte.
+        if !num_part.chars().all(|c| c.is_ascii_hexdigit()) { return Err(ContentDecodeError::HeaderNotHex(num_part.to_string())); }
+        if num_part.len() < 3 { return Err(ContentDecodeError::HeaderTooShort(num_part.len())); }
         let seq = match u8::from_str_radix(&num_part[..2], 16) { Ok(val)=>val, Err(error)=>{return Err(ContentDecodeError::InvalidSequenceIdPart(error));} };
         let ind = match usize::from_str_radix(&num_part[2..], 16) { Ok(val)=>val, Err(error)=>{return Err(ContentDecodeError::InvalidPieceIdPart(error));} };
         let data = match decode(data_part) {Ok(data)=>data, Err(error)=>{return Err(ContentDecodeError::InvalidDataPart(error)); } };
-        Ok(ContentSymbol{ sequence: seq, index: ind, data: data })
+        let checksum = match iter.next() {
+            None => None,
+            Some(part) if part.len() == 8 && part.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f')) => u32::from_str_radix(part, 16).ok(),
+            Some(part) => { return Err(ContentDecodeError::InvalidChecksumPart(part.to_string())); },
+        };
+        Ok(ContentSymbol{ sequence: seq, index: ind, data: data, checksum })
     }
     pub fn to_str(&self) -> String {
-        format!("{:02x}{:x}@{}", self.sequence, self.index, encode(&self.data))
+        match self.checksum {
+            Some(checksum) => format!("{:02x}{:x}@{}@{:08x}", self.sequence, self.index, encode(&self.data), checksum),
+            None => format!("{:02x}{:x}@{}", self.sequence, self.index, encode(&self.data)),
+        }
+    }
+
+    /// Get the number of characters before the data part in a content symbol with this index.
+    pub fn header_len(index: usize) -> usize {
+        format!("{:02x}{:x}@", 0, index).len()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Eq, PartialEq, Clone)]
+/// A tree symbol. Contains the hashes of a block of chunks, which can be checked against the Merkle root in the meta symbol without the rest of the tree.
+pub struct TreeSymbol {
+    pub seq_id: u8,
+    /// The block holds `2^level` chunks, or fewer if it is the last one.
+    pub level: u32,
+    /// The index of the first chunk in the block, which is a multiple of the block size.
+    pub first: usize,
+    /// The leaf hashes of the chunks in the block, 32 bytes each, in Base64.
+    pub leaves: String,
+    /// The partners of the block's ancestors in the tree, from the bottom up, 32 bytes each, in Base64.
+    pub path: String,
+}
+
+impl TreeSymbol {
+    pub fn new(seq_id: u8, level: u32, first: usize, leaves: &[[u8;32]], path: &[[u8;32]]) -> Self {
+        TreeSymbol { seq_id, level, first, leaves: encode(leaves.concat()), path: encode(path.concat()) }
+    }
+
+    pub fn validate(&self) -> Result<(), TreeDecodeError> {
+        let leaves = self.get_leaves()?;
+        self.get_path()?;
+        if leaves.is_empty() { return Err(TreeDecodeError::NoLeaves); }
+        let block_len = 1usize.checked_shl(self.level).ok_or(TreeDecodeError::MisalignedBlock { first: self.first, level: self.level })?;
+        if leaves.len() > block_len { return Err(TreeDecodeError::TooManyLeaves { leaves: leaves.len(), level: self.level, max: block_len }); }
+        if !self.first.is_multiple_of(block_len) { return Err(TreeDecodeError::MisalignedBlock { first: self.first, level: self.level }); }
+        Ok(())
+    }
+
+    pub fn to_str(&self) -> String { serde_json::to_string(self).expect("JSON serialization failed?!") }
+
+    /// The index of the block among the blocks of the same size.
+    pub fn block_index(&self) -> usize { self.first >> self.level }
+
+    /// Decode the leaf hashes.
+    pub fn get_leaves(&self) -> Result<Vec<[u8;32]>, TreeDecodeError> {
+        split_hashes(decode(&self.leaves).map_err(TreeDecodeError::InvalidLeavesPart)?)
+    }
+
+    /// Decode the authentication path.
+    pub fn get_path(&self) -> Result<Vec<[u8;32]>, TreeDecodeError> {
+        split_hashes(decode(&self.path).map_err(TreeDecodeError::InvalidPathPart)?)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Eq, PartialEq, Clone)]
+/// A recipient symbol. Contains the key of an encrypted file, wrapped for one X25519 public key.
+pub struct RecipientSymbol {
+    pub seq_id: u8,
+    /// The number of this recipient symbol, from 0 to one less than `recipients` in the encryption settings.
+    pub recipient: usize,
+    /// The ephemeral X25519 public key the wrapping key was agreed with, in Base64.
+    pub share: String,
+    /// The wrapped key, in Base64.
+    pub body: String,
+}
+
+impl RecipientSymbol {
+    pub const SHARE_LEN: usize = 32;
+    /// The key, along with the tag of the AEAD that wrapped it.
+    pub const BODY_LEN: usize = 32;
+
+    pub fn validate(&self) -> Result<(), RecipientDecodeError> {
+        self.get_share()?;
+        self.get_body()?;
+        Ok(())
+    }
+
+    pub fn to_str(&self) -> String { serde_json::to_string(self).expect("JSON serialization failed?!") }
+
+    /// Decode the share, which must be `SHARE_LEN` bytes.
+    pub fn get_share(&self) -> Result<[u8; RecipientSymbol::SHARE_LEN], RecipientDecodeError> {
+        let share = decode(&self.share).map_err(|_| RecipientDecodeError::InvalidShare)?;
+        share.as_slice().try_into().map_err(|_| RecipientDecodeError::InvalidShare)
+    }
+
+    /// Decode the body, which must be `BODY_LEN` bytes.
+    pub fn get_body(&self) -> Result<[u8; RecipientSymbol::BODY_LEN], RecipientDecodeError> {
+        let body = decode(&self.body).map_err(|_| RecipientDecodeError::InvalidBody)?;
+        body.as_slice().try_into().map_err(|_| RecipientDecodeError::InvalidBody)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Eq, PartialEq, Clone)]
+/// A signature symbol. Contains an Ed25519 signature over the fields of the meta symbol that describe the file, including its hash.
+pub struct SignatureSymbol {
+    pub seq_id: u8,
+    /// The public key of the signer, in Base64.
+    pub key: String,
+    /// The signature of `MetaSymbol::signed_message`, in Base64.
+    pub sig: String,
+}
+
+impl SignatureSymbol {
+    /// The prefix of every signed message, so that the signature cannot be passed off as one over anything else.
+    pub const CONTEXT: &'static [u8] = b"datablast-signature-v1\n";
+    pub const KEY_LEN: usize = 32;
+    pub const SIGNATURE_LEN: usize = 64;
+
+    pub fn validate(&self) -> Result<(), SignatureDecodeError> {
+        self.get_key()?;
+        self.get_signature()?;
+        Ok(())
+    }
+
+    pub fn to_str(&self) -> String { serde_json::to_string(self).expect("JSON serialization failed?!") }
+
+    /// Decode the public key, which must be `KEY_LEN` bytes.
+    pub fn get_key(&self) -> Result<[u8; SignatureSymbol::KEY_LEN], SignatureDecodeError> {
+        let key = decode(&self.key).map_err(|_| SignatureDecodeError::InvalidKey)?;
+        key.as_slice().try_into().map_err(|_| SignatureDecodeError::InvalidKey)
+    }
+
+    /// Decode the signature, which must be `SIGNATURE_LEN` bytes.
+    pub fn get_signature(&self) -> Result<[u8; SignatureSymbol::SIGNATURE_LEN], SignatureDecodeError> {
+        let sig = decode(&self.sig).map_err(|_| SignatureDecodeError::InvalidSignature)?;
+        sig.as_slice().try_into().map_err(|_| SignatureDecodeError::InvalidSignature)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Eq, PartialEq, Clone)]
+/// A manifest symbol. Contains a part of the manifest of a directory tree, which lists its files.
+pub struct ManifestSymbol {
+    pub seq_id: u8,
+    /// The number of this part, from 0 to one less than `parts` in the meta symbol's manifest field.
+    pub part: usize,
+    /// This part of the manifest, in Base64.
+    pub data: String,
+}
+
+impl ManifestSymbol {
+    pub fn new(seq_id: u8, part: usize, data: &[u8]) -> Self { ManifestSymbol { seq_id, part, data: encode(data) } }
+
+    pub fn to_str(&self) -> String { serde_json::to_string(self).expect("JSON serialization failed?!") }
+
+    /// Decode this part of the manifest, if it is valid Base64.
+    pub fn get_data(&self) -> Option<Vec<u8>> { decode(&self.data).ok() }
+}
+
+/// Split bytes into 32-byte hashes.
+fn split_hashes(bytes: Vec<u8>) -> Result<Vec<[u8;32]>, TreeDecodeError> {
+    if !bytes.len().is_multiple_of(32) { return Err(TreeDecodeError::PartialHash(bytes.len())); }
+    Ok(bytes.chunks(32).map(|hash| { let mut array = [0; 32]; array.copy_from_slice(hash); array }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use crate::symbol::*;
+
+    /// A valid meta symbol of a plain file, for tests to derive others from.
+    fn meta() -> MetaSymbol {
+        MetaSymbol { ver: 0, seq_id: 1, frames: 2, cur_frame: 0, content_len: vec![10, 1], sha3: "ab".repeat(32), name: "a".to_string(), hash_alg: None, compression: None, encryption: None, merkle_root: None, manifest: None, metadata: FileMetadata::default(), extra: Default::default() }
+    }
+
+    #[test]
+    fn test_malformed_headers_are_rejected() {
+        let content = |text: &str| ContentSymbol::from_str(text.to_string());
+        assert_eq!(content("a@AAAA"), Err(ContentDecodeError::HeaderTooShort(1)));
+        assert_eq!(content("@AAAA"), Err(ContentDecodeError::HeaderTooShort(0)));
+        assert_eq!(content("0é1@AAAA"), Err(ContentDecodeError::HeaderNotHex("0é1".to_string())));
+        assert_eq!(content("+01@AAAA"), Err(ContentDecodeError::HeaderNotHex("+01".to_string())));
+        assert_eq!(content("0a1f@AAAA"), Ok(ContentSymbol { sequence: 10, index: 31, data: vec![0, 0, 0], checksum: None }));
+        assert_eq!(content("0a1f@AAAA@0000"), Err(ContentDecodeError::InvalidChecksumPart("0000".to_string())));
+        assert_eq!(content("0a1f@AAAA@+1234567"), Err(ContentDecodeError::InvalidChecksumPart("+1234567".to_string())));
+        assert_eq!(content("0a1f@AAAA@ABCDEF01"), Err(ContentDecodeError::InvalidChecksumPart("ABCDEF01".to_string())));
+        assert_eq!(content("0A1F@AAAA"), Ok(ContentSymbol { sequence: 10, index: 31, data: vec![0, 0, 0], checksum: None }));
+        assert!(matches!(symbol_from_string("é".to_string()), Err(SymbolDecodeError::InvalidContent(ContentDecodeError::NoDataPart))));
+    }
+
+    #[test]
+    fn test_invalid_hash_is_an_error() {
+        let mut meta = meta();
+        assert_eq!(meta.get_hash(), Ok([0xab; 32]));
+        meta.sha3 = "ab".to_string();
+        assert_eq!(meta.get_hash(), Err(MetaDecodeError::InvalidLengthOfHashField(2)));
+        // 64 bytes, but not 64 hex digits.
+        meta.sha3 = "é".repeat(32);
+        assert_eq!(meta.get_hash(), Err(MetaDecodeError::HashFieldNotHex));
+        assert_eq!(symbol_from_string(meta.to_str()), Err(SymbolDecodeError::InvalidMeta(MetaDecodeError::HashFieldNotHex)));
+    }
+
+    proptest! {
+        #[test]
+        fn prop_any_text_is_parsed_without_panicking(text in any::<String>()) {
+            let _ = symbol_from_string(text);
+        }
+
+        #[test]
+        fn prop_content_like_text_is_parsed_without_panicking(text in "[0-9a-fA-Fé+@-]{0,24}@[A-Za-z0-9+/=@é]{0,16}") {
+            let _ = symbol_from_string(text);
+        }
+
+        #[test]
+        fn prop_meta_like_text_is_parsed_without_panicking(ver in 0u32..3, content_len in prop::collection::vec(any::<usize>(), 0..4), sha3 in "[0-9a-fé]{0,70}", name in any::<String>(), hash_alg in prop::option::of("sha3-256|sha-256|blake3|[a-z0-9é-]{0,10}"), compression in prop::option::of("none|deflate|zstd|xz|[a-z0-9é]{0,8}"), merkle_root in prop::option::of("[0-9a-fé]{62,66}")) {
+            let meta = MetaSymbol { ver, seq_id: 0, frames: 1, content_len, sha3, name, hash_alg, compression, merkle_root, ..meta() };
+            let valid = meta.validate().is_ok();
+            prop_assert_eq!(symbol_from_string(meta.to_str()).is_ok(), valid);
+        }
+
+        #[test]
+        fn prop_content_symbols_round_trip(sequence in any::<u8>(), index in any::<usize>(), data in prop::collection::vec(any::<u8>(), 0..64), checksum in any::<Option<u32>>()) {
+            let symb = ContentSymbol { sequence, index, data, checksum };
+            prop_assert_eq!(ContentSymbol::from_str(symb.to_str()), Ok(symb));
+        }
+    }
+
+    #[test]
+    fn test_checksum_detects_corrupted_data() {
+        let symb = ContentSymbol::with_checksum(3, 5, b"chunk of data".to_vec());
+        assert!(symb.to_str().ends_with(&format!("@{:08x}", crc32c::crc32c(b"chunk of data"))));
+        assert_eq!(symb.to_str().len(), ContentSymbol { checksum: None, ..symb.clone() }.to_str().len() + ContentSymbol::CHECKSUM_LEN);
+        let parsed = ContentSymbol::from_str(symb.to_str()).unwrap();
+        assert!(parsed.checksum_matches());
+        let corrupted = ContentSymbol { data: b"chunk of dat4".to_vec(), ..parsed };
+        assert!(!corrupted.checksum_matches());
+        assert!(ContentSymbol { checksum: None, ..corrupted }.checksum_matches());
+    }
+
+    #[test]
+    fn test_tree_symbols_are_parsed_and_checked() {
+        let tree = TreeSymbol::new(4, 1, 6, &[[1; 32], [2; 32]], &[[3; 32]]);
+        assert_eq!(symbol_from_string(tree.to_str()), Ok(Symbol::Tree(tree.clone())));
+        assert_eq!((tree.block_index(), tree.get_leaves(), tree.get_path()), (3, Ok(vec![[1; 32], [2; 32]]), Ok(vec![[3; 32]])));
+        let invalid = |tree: TreeSymbol| match symbol_from_string(tree.to_str()) { Err(SymbolDecodeError::InvalidTree(error)) => error, other => panic!("expected an invalid tree, got {:?}", other) };
+        assert_eq!(invalid(TreeSymbol { first: 5, ..tree.clone() }), TreeDecodeError::MisalignedBlock { first: 5, level: 1 });
+        assert_eq!(invalid(TreeSymbol { level: 0, ..tree.clone() }), TreeDecodeError::TooManyLeaves { leaves: 2, level: 0, max: 1 });
+        assert_eq!(invalid(TreeSymbol { level: 200, ..tree.clone() }), TreeDecodeError::MisalignedBlock { first: 6, level: 200 });
+        assert_eq!(invalid(TreeSymbol { leaves: String::new(), ..tree.clone() }), TreeDecodeError::NoLeaves);
+        assert_eq!(invalid(TreeSymbol { path: "AAAA".to_string(), ..tree.clone() }), TreeDecodeError::PartialHash(3));
+
+        // Meta symbols without a root, as older versions wrote them, are still read.
+        let meta = meta();
+        assert!(!meta.to_str().contains("merkle_root"));
+        let with_root = MetaSymbol { merkle_root: Some("cd".repeat(32)), ..meta };
+        assert_eq!(symbol_from_string(with_root.to_str()), Ok(Symbol::Meta(with_root.clone())));
+        assert_eq!(with_root.get_merkle_root(), Ok(Some([0xcd; 32])));
+        let bad_root = MetaSymbol { merkle_root: Some("cd".to_string()), ..with_root };
+        assert_eq!(symbol_from_string(bad_root.to_str()), Err(SymbolDecodeError::InvalidMeta(MetaDecodeError::InvalidMerkleRoot)));
+    }
+
+    #[test]
+    fn test_hash_algorithm_defaults_to_sha3() {
+        let meta = meta();
+        assert_eq!(meta.get_hash_algorithm(), Ok(HashAlgorithm::Sha3_256));
+        let blake3 = MetaSymbol { hash_alg: Some("blake3".to_string()), ..meta.clone() };
+        assert_eq!(symbol_from_string(blake3.to_str()), Ok(Symbol::Meta(blake3.clone())));
+        assert_eq!(blake3.get_hash_algorithm(), Ok(HashAlgorithm::Blake3));
+        let unknown = MetaSymbol { hash_alg: Some("md5".to_string()), ..meta };
+        assert_eq!(symbol_from_string(unknown.to_str()), Err(SymbolDecodeError::InvalidMeta(MetaDecodeError::UnknownHashAlgorithm("md5".to_string()))));
+    }
+
+    #[test]
+    fn test_compression_defaults_to_none() {
+        let meta = meta();
+        assert_eq!(meta.get_compression(), Ok(Compression::None));
+        assert!(!meta.to_str().contains("compression"));
+        let zstd = MetaSymbol { ver: 1, compression: Some("zstd".to_string()), ..meta.clone() };
+        assert_eq!(symbol_from_string(zstd.to_str()), Ok(Symbol::Meta(zstd.clone())));
+        assert_eq!(zstd.get_compression(), Ok(Compression::Zstd));
+        // A decoder of version 0 would take the compressed file for the file itself, so it must be told it cannot read this.
+        let unversioned = MetaSymbol { ver: 0, ..zstd.clone() };
+        assert_eq!(symbol_from_string(unversioned.to_str()), Err(SymbolDecodeError::InvalidMeta(MetaDecodeError::VersionTooLow { ver: 0, required: 1 })));
+        assert_eq!(MetaSymbol { compression: Some("none".to_string()), ..meta.clone() }.validate(), Ok(()));
+        let unknown = MetaSymbol { compression: Some("lz4".to_string()), ..meta };
+        assert_eq!(symbol_from_string(unknown.to_str()), Err(SymbolDecodeError::InvalidMeta(MetaDecodeError::UnknownCompression("lz4".to_string()))));
+    }
+
+    #[test]
+    fn test_recipient_symbols_round_trip() {
+        let recipient = RecipientSymbol { seq_id: 3, recipient: 1, share: encode([5; 32]), body: encode([6; 32]) };
+        assert_eq!(symbol_from_string(recipient.to_str()), Ok(Symbol::Recipient(recipient.clone())));
+        assert_eq!((recipient.get_share(), recipient.get_body()), (Ok([5; 32]), Ok([6; 32])));
+        let short_share = RecipientSymbol { share: encode([5; 31]), ..recipient.clone() };
+        assert_eq!(symbol_from_string(short_share.to_str()), Err(SymbolDecodeError::InvalidRecipient(RecipientDecodeError::InvalidShare)));
+        let bad_body = RecipientSymbol { body: "?".to_string(), ..recipient };
+        assert_eq!(symbol_from_string(bad_body.to_str()), Err(SymbolDecodeError::InvalidRecipient(RecipientDecodeError::InvalidBody)));
+    }
+
+    #[test]
+    fn test_signature_symbols_round_trip() {
+        let signature = SignatureSymbol { seq_id: 3, key: encode([5; 32]), sig: encode([6; 64]) };
+        assert_eq!(symbol_from_string(signature.to_str()), Ok(Symbol::Signature(signature.clone())));
+        let short_key = SignatureSymbol { key: encode([5; 31]), ..signature.clone() };
+        assert_eq!(symbol_from_string(short_key.to_str()), Err(SymbolDecodeError::InvalidSignature(SignatureDecodeError::InvalidKey)));
+        let short_sig = SignatureSymbol { sig: encode([6; 32]), ..signature };
+        assert_eq!(symbol_from_string(short_sig.to_str()), Err(SymbolDecodeError::InvalidSignature(SignatureDecodeError::InvalidSignature)));
+    }
+
+    #[test]
+    fn test_signed_message_leaves_out_frame_numbers() {
+        let meta = MetaSymbol { frames: 20, ..meta() };
+        assert_eq!(meta.signed_message(), MetaSymbol { seq_id: 9, frames: 40, cur_frame: 21, ..meta.clone() }.signed_message());
+        assert_ne!(meta.signed_message(), MetaSymbol { name: "b".to_string(), ..meta.clone() }.signed_message());
+        assert_ne!(meta.signed_message(), MetaSymbol { sha3: "cd".repeat(32), ..meta.clone() }.signed_message());
+        assert_eq!(meta.signed_message(), [SignatureSymbol::CONTEXT, format!(r#"{{"content_len":[10,1],"name":"a","sha3":"{}","ver":0}}"#, "ab".repeat(32)).as_bytes()].concat());
+        let mut extended = meta.clone();
+        extended.extra.insert("owner".to_string(), serde_json::json!("alice"));
+        assert_ne!(meta.signed_message(), extended.signed_message());
+    }
+
+    #[test]
+    fn test_manifest_symbols_round_trip() {
+        let manifest = ManifestSymbol::new(3, 1, b"[{\"path\":\"a.txt\"}]");
+        assert_eq!(symbol_from_string(manifest.to_str()), Ok(Symbol::Manifest(manifest.clone())));
+        let broken = ManifestSymbol { data: "not base64!".to_string(), ..manifest };
+        assert_eq!(symbol_from_string(broken.to_str()), Err(SymbolDecodeError::InvalidManifest));
+
+        let meta = MetaSymbol { ver: 3, seq_id: 3, name: "dir".to_string(), manifest: Some(ManifestInfo { parts: 2, len: 40, hash: "cd".repeat(32) }), ..meta() };
+        assert_eq!(meta.validate(), Ok(()));
+        // A decoder of an earlier version would write the concatenated files out as one file named after the directory.
+        assert_eq!(MetaSymbol { ver: 2, ..meta.clone() }.validate(), Err(MetaDecodeError::VersionTooLow { ver: 2, required: 3 }));
+        let no_parts = MetaSymbol { manifest: Some(ManifestInfo { parts: 0, len: 40, hash: "cd".repeat(32) }), ..meta.clone() };
+        assert_eq!(no_parts.validate(), Err(MetaDecodeError::InvalidManifest));
+        let bad_hash = MetaSymbol { manifest: Some(ManifestInfo { parts: 2, len: 40, hash: "cd".to_string() }), ..meta };
+        assert_eq!(bad_hash.validate(), Err(MetaDecodeError::InvalidManifest));
+    }
+
+    #[test]
+    fn test_file_metadata_round_trips_and_unknown_fields_are_ignored() {
+        let metadata = FileMetadata { mime: Some("text/plain".to_string()), mtime: Some(1_600_000_000), mode: Some(0o644), comment: Some("the \"final\" draft".to_string()) };
+        let meta = MetaSymbol { name: "a.txt".to_string(), metadata, ..meta() };
+        assert!(meta.to_str().ends_with(r#""name":"a.txt","mime":"text/plain","mtime":1600000000,"mode":420,"comment":"the \"final\" draft"}"#));
+        assert_eq!(symbol_from_string(meta.to_str()), Ok(Symbol::Meta(meta.clone())));
+        // A later version may add fields; they are kept aside rather than making the symbol unreadable.
+        let newer = meta.to_str().replacen('{', r#"{"owner":"alice","tags":["x",{"y":1}],"#, 1);
+        let parsed = match symbol_from_string(newer) { Ok(Symbol::Meta(parsed)) => parsed, other => panic!("expected a meta symbol, got {:?}", other) };
+        assert_eq!(MetaSymbol { extra: Default::default(), ..parsed.clone() }, meta);
+        assert_eq!(parsed.extra.keys().collect::<Vec<_>>(), ["owner", "tags"]);
+        assert_eq!(symbol_from_string(parsed.to_str()), Ok(Symbol::Meta(parsed.clone())));
+
+        let metadata = |metadata: FileMetadata| MetaSymbol { metadata, ..meta.clone() }.validate();
+        assert_eq!(metadata(FileMetadata { mime: Some("application/vnd.oasis.opendocument.text".to_string()), ..FileMetadata::default() }), Ok(()));
+        assert_eq!(metadata(FileMetadata { mime: Some("text".to_string()), ..FileMetadata::default() }), Err(MetaDecodeError::InvalidMime("text".to_string())));
+        assert_eq!(metadata(FileMetadata { mime: Some("text/plain\n".to_string()), ..FileMetadata::default() }), Err(MetaDecodeError::InvalidMime("text/plain\n".to_string())));
+        assert_eq!(metadata(FileMetadata { mime: Some("text/html/x".to_string()), ..FileMetadata::default() }), Err(MetaDecodeError::InvalidMime("text/html/x".to_string())));
+        assert_eq!(metadata(FileMetadata { mode: Some(0o4755), ..FileMetadata::default() }), Ok(()));
+        assert_eq!(metadata(FileMetadata { mode: Some(0o10644), ..FileMetadata::default() }), Err(MetaDecodeError::InvalidMode(0o10644)));
+        assert_eq!(metadata(FileMetadata { mtime: Some(FileMetadata::MAX_MTIME), ..FileMetadata::default() }), Ok(()));
+        assert_eq!(metadata(FileMetadata { mtime: Some(u64::MAX), ..FileMetadata::default() }), Err(MetaDecodeError::InvalidMtime(u64::MAX)));
+    }
+
+    #[test]
+    fn test_encryption_fields_are_checked() {
+        let encryption = Encryption { cipher: Encryption::CIPHER.to_string(), kdf: Encryption::KDF_PASSPHRASE.to_string(), salt: Some(encode([7; 16])), m_cost: Some(64), t_cost: Some(1), p_cost: Some(1), recipients: None, nonce: encode([9; 12]) };
+        let meta = MetaSymbol { ver: 2, encryption: Some(encryption.clone()), ..meta() };
+        assert_eq!(symbol_from_string(meta.to_str()), Ok(Symbol::Meta(meta.clone())));
+        // A decoder of version 0 or 1 would write out the encrypted file as if it were the file.
+        for ver in 0..2 {
+            assert_eq!(MetaSymbol { ver, ..meta.clone() }.validate(), Err(MetaDecodeError::VersionTooLow { ver, required: 2 }));
+        }
+        assert_eq!((encryption.get_salt(), encryption.get_nonce()), (Ok([7; 16]), Ok([9; 12])));
+        let broken = vec![
+            (Encryption { cipher: "aes-256-gcm".to_string(), ..encryption.clone() }, MetaDecodeError::UnknownCipher("aes-256-gcm".to_string())),
+            (Encryption { kdf: "scrypt".to_string(), ..encryption.clone() }, MetaDecodeError::UnknownKeyDerivation("scrypt".to_string())),
+            (Encryption { salt: Some(encode([7; 15])), ..encryption.clone() }, MetaDecodeError::InvalidSalt),
+            (Encryption { t_cost: None, ..encryption.clone() }, MetaDecodeError::MissingEncryptionField("t_cost")),
+            (Encryption { nonce: "not base64!".to_string(), ..encryption.clone() }, MetaDecodeError::InvalidNonce),
+            (Encryption { kdf: Encryption::KDF_RECIPIENTS.to_string(), ..encryption.clone() }, MetaDecodeError::MissingEncryptionField("recipients")),
+            (Encryption { kdf: Encryption::KDF_RECIPIENTS.to_string(), recipients: Some(0), ..encryption }, MetaDecodeError::NoRecipients),
+        ];
+        for (encryption, error) in broken {
+            let meta = MetaSymbol { encryption: Some(encryption), ..meta.clone() };
+            assert_eq!(symbol_from_string(meta.to_str()), Err(SymbolDecodeError::InvalidMeta(error)));
+        }
+    }
+
+    #[test]
+    fn test_hash_algorithms_match_known_digests() {
+        let digest = |algorithm: HashAlgorithm| hex::encode(algorithm.digest(b"abc"));
+        assert_eq!(digest(HashAlgorithm::Sha3_256), "3a985da74fe225b2045c172d6bd390bd855f086e3e9d525b46bfe24511431532");
+        assert_eq!(digest(HashAlgorithm::Sha256), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(digest(HashAlgorithm::Blake3), "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85");
+        for algorithm in [HashAlgorithm::Sha3_256, HashAlgorithm::Sha256, HashAlgorithm::Blake3].iter() {
+            assert_eq!(algorithm.name().parse(), Ok(*algorithm));
+        }
     }
 }
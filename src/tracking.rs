@@ -0,0 +1,196 @@
+use image::{GenericImageView, Pixel, SubImage};
+use log::debug;
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::qr_reader::{Detection, ReaderConfig, detect_with_config};
+
+/// A part of a frame where a grid was found, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Region {
+    left: u32,
+    top: u32,
+    right: u32,
+    bottom: u32,
+    /// How far around the grid to search for it again.
+    padding: u32,
+}
+
+/// The quiet zone a QR code needs around it is this many modules wide, and one more module leaves room for the grid moving a little.
+const PADDING_MODULES: u32 = 5;
+
+impl Region {
+    /// Get the smallest region that holds all of the grid's corners.
+    fn around(detection: &Detection) -> Self {
+        let xs = detection.corners.iter().map(|corner| corner.0.max(0) as u32);
+        let ys = detection.corners.iter().map(|corner| corner.1.max(0) as u32);
+        let (left, top, right, bottom) = (xs.clone().min().unwrap(), ys.clone().min().unwrap(), xs.max().unwrap(), ys.max().unwrap());
+        let size = (right - left).max(bottom - top);
+        let padding = match detection.version() {
+            Some(version) => size / (17 + 4 * version as u32) * PADDING_MODULES + 2,
+            // Without knowing how large the modules are, leave as much room as for a small QR code.
+            None => size / 4 + 2,
+        };
+        Region { left, top, right, bottom, padding }
+    }
+
+    fn contains(&self, (x, y): (i32, i32)) -> bool {
+        x >= self.left as i32 && x <= self.right as i32 && y >= self.top as i32 && y <= self.bottom as i32
+    }
+
+    /// Get this region with room around it for the quiet zone, and for the grid moving a little, but still inside an image of this size.
+    fn padded(&self, width: u32, height: u32) -> Region {
+        Region {
+            left: self.left.saturating_sub(self.padding),
+            top: self.top.saturating_sub(self.padding),
+            right: (self.right + self.padding).min(width),
+            bottom: (self.bottom + self.padding).min(height),
+            padding: 0,
+        }
+    }
+}
+
+/// Remembers where grids were found in earlier frames, so that later frames can be read by looking only there.
+///
+/// Symbols stay in the same place for a whole video, so most frames only need a small part of their area searched.
+/// The frames are split into runs of `full_scan_every`: the first frame of each run is searched in full, and the places where grids were found in it are searched in the rest of the run.
+/// A frame where a grid is missing from one of those places is searched in full too.
+///
+/// What is found in a frame depends only on that frame and the first frame of its run, so several threads may read frames at once, in any order, and still find the same grids.
+pub struct RegionTracker {
+    /// Search the whole frame at least once every this many frames, to find grids that moved or appeared. Zero searches every frame in full.
+    pub full_scan_every: usize,
+    /// The places where grids were found in the first frame of each run that may still be needed.
+    runs: Mutex<HashMap<usize, Vec<Region>>>,
+    /// Signalled whenever the places for another run are known.
+    run_scanned: Condvar,
+    full_scans: AtomicUsize,
+}
+
+impl RegionTracker {
+    pub fn new(full_scan_every: usize) -> Self {
+        RegionTracker { full_scan_every, runs: Mutex::new(HashMap::new()), run_scanned: Condvar::new(), full_scans: AtomicUsize::new(0) }
+    }
+
+    /// How many frames were searched in full so far.
+    pub fn full_scans(&self) -> usize { self.full_scans.load(Ordering::Relaxed) }
+
+    /// Find the grids in the frame with this index in the video and try to read a symbol from each, as `detect_with_config` does.
+    ///
+    /// This waits until the first frame of the run this frame belongs to has been searched, so that frame must be passed in as well, by this thread or another one.
+    pub fn detect<I>(&self, index: usize, img: &I, config: &ReaderConfig) -> Vec<Detection> where I: GenericImageView, I::Pixel: Pixel<Subpixel=u8> {
+        if self.full_scan_every == 0 { return self.full_scan(img, config); }
+        let run = index / self.full_scan_every;
+        if index.is_multiple_of(self.full_scan_every) {
+            let detections = self.full_scan(img, config);
+            let mut regions: Vec<Region> = Vec::new();
+            for detection in detections.iter() {
+                // The same grid may be found in several channels.
+                if regions.iter().any(|region| region.contains(detection.center())) { continue; }
+                regions.push(Region::around(detection));
+            }
+            debug!("Found {} grids in {} places in frame {}", detections.len(), regions.len(), index);
+            self.runs.lock().unwrap().insert(run, regions);
+            self.run_scanned.notify_all();
+            return detections;
+        }
+
+        let regions = {
+            let runs = self.run_scanned.wait_while(self.runs.lock().unwrap(), |runs| !runs.contains_key(&run)).unwrap();
+            runs[&run].clone()
+        };
+        if regions.is_empty() { return self.full_scan(img, config); }
+        match detect_in_regions(&regions, img, config) {
+            Some(detections) => detections,
+            None => {
+                debug!("A grid is missing from frame {} where it was found before, so searching the whole frame", index);
+                self.full_scan(img, config)
+            },
+        }
+    }
+
+    /// Forget the places found for runs that end before the frame with this index, once no frame before it will be read any more.
+    pub fn forget_before(&self, index: usize) {
+        if self.full_scan_every == 0 { return; }
+        let first_needed = index / self.full_scan_every;
+        self.runs.lock().unwrap().retain(|&run, _| run >= first_needed);
+    }
+
+    /// Search the whole frame.
+    fn full_scan<I>(&self, img: &I, config: &ReaderConfig) -> Vec<Detection> where I: GenericImageView, I::Pixel: Pixel<Subpixel=u8> {
+        self.full_scans.fetch_add(1, Ordering::Relaxed);
+        detect_with_config(img, config)
+    }
+}
+
+/// Search only these places, or give up if any of them no longer holds a grid.
+fn detect_in_regions<I>(regions: &[Region], img: &I, config: &ReaderConfig) -> Option<Vec<Detection>> where I: GenericImageView, I::Pixel: Pixel<Subpixel=u8> {
+    let (width, height) = img.dimensions();
+    let mut output = Vec::new();
+    for region in regions.iter() {
+        let area = region.padded(width, height);
+        if area.right <= area.left || area.bottom <= area.top { return None; }
+        let view = SubImage::new(img, area.left, area.top, area.right - area.left, area.bottom - area.top);
+        let before = output.len();
+        for mut detection in detect_with_config(&view, config) {
+            for corner in detection.corners.iter_mut() {
+                *corner = (corner.0 + area.left as i32, corner.1 + area.top as i32);
+            }
+            // Only accept the grid that is tracked here, not a neighbouring one that happens to be in the area.
+            if region.contains(detection.center()) { output.push(detection); }
+        }
+        if output.len() == before { return None; }
+    }
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbImage;
+    use crate::tracking::*;
+    use crate::compositor::FrameLayout;
+    use crate::qr_writer::{string_to_qrcode_with_version, Version, EcLevel};
+
+    fn frame(layout: &FrameLayout, texts: &[String]) -> RgbImage {
+        let codes: Vec<_> = texts.iter().map(|text| string_to_qrcode_with_version(text.clone(), Version::Normal(2), EcLevel::M, 1).unwrap()).collect();
+        layout.compose(&codes).unwrap()
+    }
+
+    fn contents(detections: Vec<Detection>) -> Vec<String> {
+        let mut strings: Vec<String> = detections.into_iter().filter_map(|detection| detection.content).collect();
+        strings.sort();
+        strings
+    }
+
+    #[test]
+    fn test_tracked_regions_find_the_same_grids() {
+        let layout = FrameLayout::fit(960, 540, 4, 16).unwrap();
+        let config = ReaderConfig::new();
+        let tracker = RegionTracker::new(10);
+        for i in 0..12 {
+            let texts: Vec<String> = (0..4).map(|j| format!("frame {} symbol {}", i, j)).collect();
+            let img = frame(&layout, &texts);
+            assert_eq!(contents(tracker.detect(i, &img, &config)), texts);
+            tracker.forget_before(i + 1);
+        }
+        assert_eq!(tracker.full_scans(), 2);
+        assert_eq!(tracker.runs.lock().unwrap().keys().collect::<Vec<_>>(), vec![&1]);
+    }
+
+    #[test]
+    fn test_missing_grid_triggers_full_scan() {
+        let config = ReaderConfig::new();
+        let tracker = RegionTracker::new(10);
+        let texts: Vec<String> = (0..2).map(|j| format!("symbol {}", j)).collect();
+        tracker.detect(0, &frame(&FrameLayout::with_grid(960, 540, 2, 1, 16), &texts), &config);
+        // The symbols move to a different grid, so they are no longer where they were.
+        let moved = frame(&FrameLayout::with_grid(960, 540, 1, 2, 16), &texts);
+        assert_eq!(contents(tracker.detect(1, &moved, &config)), texts);
+        assert_eq!(tracker.full_scans(), 2);
+
+        let every_frame = RegionTracker::new(0);
+        for i in 0..3 { every_frame.detect(i, &moved, &config); }
+        assert_eq!(every_frame.full_scans(), 3);
+    }
+}
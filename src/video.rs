@@ -1,8 +1,10 @@
 use image::{RgbImage, load_from_memory};
 use image::ImageError;
-use std::process::{Command, Stdio, Child};
+use std::process::{Command, Stdio, Child, ChildStdout};
 use std::collections::HashMap;
-use std::io::Read;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use log::{warn, info};
 
 /// Start a process and return a handle to it.
 fn launch(cmd: &str, args: &[&str]) -> Child {
@@ -62,6 +64,104 @@ impl Video {
 }
 
 
+/// Read consecutive binary PPM images from a stream, such as the frames FFmpeg writes with `-f image2pipe -vcodec ppm`.
+pub struct PpmFrames<R: Read> {
+    reader: BufReader<R>,
+}
+
+impl<R: Read> PpmFrames<R> {
+    pub fn new(stream: R) -> Self {
+        PpmFrames { reader: BufReader::new(stream) }
+    }
+
+    /// Read one whitespace-separated token of a PPM header, along with the single whitespace character after it.
+    fn read_token(&mut self) -> Option<String> {
+        let mut token = Vec::new();
+        loop {
+            let byte = match self.reader.fill_buf() {
+                Ok(buf) if !buf.is_empty() => buf[0],
+                _ => { break; },
+            };
+            self.reader.consume(1);
+            if byte.is_ascii_whitespace() {
+                if token.is_empty() { continue; } else { break; }
+            }
+            token.push(byte);
+        }
+        if token.is_empty() { None } else { String::from_utf8(token).ok() }
+    }
+}
+
+impl<R: Read> Iterator for PpmFrames<R> {
+    type Item = RgbImage;
+    fn next(&mut self) -> Option<RgbImage> {
+        let magic = self.read_token()?;
+        if magic != "P6" { warn!("Expected a binary PPM image in the stream, but found {:?}", magic); return None; }
+        let width: u32 = self.read_token()?.parse().ok()?;
+        let height: u32 = self.read_token()?.parse().ok()?;
+        let max_value: u32 = self.read_token()?.parse().ok()?;
+        if max_value != 255 { warn!("Only 8-bit PPM images are supported, but this one has a maximum value of {}", max_value); return None; }
+        let mut data = vec![0; width as usize * height as usize * 3];
+        if self.reader.read_exact(&mut data).is_err() { warn!("The stream ended in the middle of a frame"); return None; }
+        RgbImage::from_raw(width, height, data)
+    }
+}
+
+/// The frames of a video, decoded by FFmpeg one at a time as they are needed.
+pub struct VideoFrames {
+    process: Child,
+    frames: PpmFrames<ChildStdout>,
+}
+
+impl VideoFrames {
+    /// Start FFmpeg decoding this video. The source may be anything FFmpeg understands, not only a file path.
+    pub fn open(source: &str) -> Self {
+        let mut process = launch("ffmpeg", &["-v", "error", "-i", source, "-f", "image2pipe", "-vcodec", "ppm", "-"]);
+        let stdout = process.stdout.take().expect("FFmpeg's stdout was not captured?!");
+        VideoFrames { process, frames: PpmFrames::new(stdout) }
+    }
+}
+
+impl Iterator for VideoFrames {
+    type Item = RgbImage;
+    fn next(&mut self) -> Option<RgbImage> { self.frames.next() }
+}
+
+impl Drop for VideoFrames {
+    fn drop(&mut self) {
+        // We may stop reading before the end of the video, so FFmpeg may still be running.
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+/// Get the images in this directory whose names are frame numbers (such as `12.png`), in order of frame number.
+pub fn frame_paths_in_directory(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut numbered = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let number = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse::<u64>().ok());
+        if let Some(number) = number { numbered.push((number, path)); }
+    }
+    numbered.sort();
+    Ok(numbered.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Get the frames from this input, which is either a directory of numbered images, or a video that FFmpeg can read.
+pub fn frames_from(input: &str) -> Box<dyn Iterator<Item = RgbImage> + Send> {
+    let path = Path::new(input);
+    if path.is_dir() {
+        let paths = frame_paths_in_directory(path).expect("could not list the frames directory");
+        info!("Reading {} frame images from {}", paths.len(), path.display());
+        Box::new(paths.into_iter().filter_map(|path| match image::open(&path) {
+            Ok(img) => Some(img.to_rgb()),
+            Err(error) => { warn!("Skipping frame {}, which could not be loaded: {}", path.display(), error); None },
+        }))
+    } else {
+        Box::new(VideoFrames::open(input))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::video::*;
@@ -75,4 +175,30 @@ mod tests {
         bytes_to_img(exec_command(&"cat", &["test_data/image_load_test.png"]))?;
         Ok(())
     }
+
+    #[test]
+    fn test_ppm_stream() {
+        let mut stream = b"P6\n2 1\n255\n".to_vec();
+        stream.extend(&[255, 0, 0, 0, 0, 255]);
+        stream.extend(b"P6 1  1\n255 ");
+        stream.extend(&[10, 20, 30]);
+        stream.extend(b"P6\n4 4\n255\n\x00"); // truncated
+        let frames: Vec<RgbImage> = PpmFrames::new(&stream[..]).collect();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].dimensions(), (2, 1));
+        assert_eq!(frames[0].get_pixel(1, 0).data, [0, 0, 255]);
+        assert_eq!(frames[1].get_pixel(0, 0).data, [10, 20, 30]);
+    }
+
+    #[test]
+    fn test_frame_paths_sorted_numerically() {
+        let dir = std::env::temp_dir().join(format!("datablast-test-frames-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["10.png", "2.png", "1.png", "notes.txt"].iter() {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        let names: Vec<String> = frame_paths_in_directory(&dir).unwrap().iter().map(|p| p.file_name().unwrap().to_string_lossy().to_string()).collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(names, vec!["1.png", "2.png", "10.png"]);
+    }
 }